@@ -1,7 +1,13 @@
+use std::{env, path::PathBuf};
+
 fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
     tonic_build::configure()
-        .build_client(false)
+        .build_client(true)
         .build_server(true)
+        .type_attribute(".orderbook", "#[derive(serde::Serialize)]")
+        .file_descriptor_set_path(out_dir.join("orderbook_descriptor.bin"))
         .compile(&["proto/orderbook.proto"], &[] as &[&str])
         .expect("Error: failed to compile proto file");
 }
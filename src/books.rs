@@ -0,0 +1,844 @@
+//! Exchange feeds of the served pairs, connected again feed by feed as the config changes.
+//!
+//! Every pair traded on the exchanges has a feed per exchange trading it,
+//! merged into its book with fees that can change in place. Served books are
+//! composed from the books of one or several traded pairs.
+
+use std::{collections::HashMap, mem, sync::Arc, time::Duration};
+
+use futures::{future, stream, stream::BoxStream, Stream, StreamExt};
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    task::JoinHandle,
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::{
+    cli::{BookSettings, Composition, ServedPair},
+    currencies::CurrencyPair,
+    exchanges::{BinanceExchange, BitstampExchange, ConnectToOrderBook, ExchangeEndpoints},
+    feeds::FeedMonitor,
+    fees::FeeSchedule,
+    instruments::PairCatalog,
+    merged_book::MergedBook,
+    order_book::{Summary, Trade},
+    quotes::{self, Conversion, MemberBooks},
+    recorder::{self, Recorder},
+    server::{BookSubscriber, ServedPairs, TradeSubscriber, BROADCAST_QUEUE_CAPACITY},
+    synthetic, Error, Result,
+};
+
+/// Exchanges whose books can be merged, in the order they are connected.
+const EXCHANGES: [&str; 2] = [
+    BinanceExchange::EXCHANGE_NAME,
+    BitstampExchange::EXCHANGE_NAME,
+];
+
+/// The books of every served pair, published to the clients of a server.
+pub struct Books {
+    served_pairs: ServedPairs,
+    books: Vec<(CurrencyPair, ServedBook)>,
+    feed_monitor: FeedMonitor,
+    recorder: Recorder,
+}
+
+impl Books {
+    /// Connects the books of `pairs` and serves them in `served_pairs`.
+    ///
+    /// Every valid summary parsed from an exchange is recorded in
+    /// `feed_monitor`, and every raw message received by `recorder`.
+    pub async fn connect(
+        pairs: &[ServedPair],
+        settings: &BookSettings,
+        served_pairs: ServedPairs,
+        feed_monitor: FeedMonitor,
+        recorder: Recorder,
+    ) -> Result<Self> {
+        let mut books = Self {
+            served_pairs,
+            books: vec![],
+            feed_monitor,
+            recorder,
+        };
+
+        for served_pair in pairs {
+            let connector = Connector::new(settings, &books.feed_monitor, &books.recorder);
+            let book = ServedBook::connect(served_pair, settings.level3, connector).await?;
+            books.serve(&served_pair.currency_pair, book);
+        }
+
+        Ok(books)
+    }
+
+    /// Serves `pairs` from now on, connecting again only the feeds that changed.
+    ///
+    /// Pairs no longer served are disconnected, which ends the streams of
+    /// their clients. Pairs failing to connect are logged and left as they are.
+    pub async fn reload(&mut self, pairs: &[ServedPair], settings: &BookSettings) {
+        let (kept, removed) =
+            mem::take(&mut self.books)
+                .into_iter()
+                .partition(|(currency_pair, _)| {
+                    pairs
+                        .iter()
+                        .any(|served_pair| served_pair.currency_pair == *currency_pair)
+                });
+        self.books = kept;
+
+        for (currency_pair, _) in removed {
+            self.served_pairs.remove(&currency_pair);
+            log::info!("Stopped serving {}.", currency_pair.as_str());
+        }
+
+        for served_pair in pairs {
+            let connector = Connector::new(settings, &self.feed_monitor, &self.recorder);
+            let currency_pair = &served_pair.currency_pair;
+
+            match self
+                .books
+                .iter_mut()
+                .find(|(served, _)| served == currency_pair)
+            {
+                Some((_, book)) => {
+                    match book
+                        .reconfigure(served_pair, settings.level3, connector)
+                        .await
+                    {
+                        Ok(()) => {
+                            self.served_pairs
+                                .set_traded_pairs(currency_pair, book.traded_pairs());
+                        }
+                        Err(err) => {
+                            log::error!(
+                                "Failed to connect the reloaded feeds of {}, keeping the \
+                                 current ones: {err}.",
+                                currency_pair.as_str()
+                            );
+                        }
+                    }
+                }
+                None => {
+                    match ServedBook::connect(served_pair, settings.level3, connector).await {
+                        Ok(book) => {
+                            self.serve(currency_pair, book);
+                            log::info!("Started serving {}.", currency_pair.as_str());
+                        }
+                        Err(err) => {
+                            log::error!(
+                                "Failed to connect the feeds of {}: {err}.",
+                                currency_pair.as_str()
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        let currency_pairs: Vec<_> = pairs
+            .iter()
+            .map(|served_pair| served_pair.currency_pair.clone())
+            .collect();
+        self.served_pairs.sort_like(&currency_pairs);
+    }
+
+    fn serve(&mut self, currency_pair: &CurrencyPair, book: ServedBook) {
+        self.served_pairs.insert(
+            currency_pair,
+            book.books.clone(),
+            book.trades.clone(),
+            book.traded_pairs(),
+        );
+        self.books.push((currency_pair.clone(), book));
+    }
+}
+
+/// The books and trades of a served pair, published until it's dropped.
+struct ServedBook {
+    composition: Composition,
+    traded_books: Vec<TradedBook>,
+    books: BookSubscriber,
+    publisher: JoinHandle<()>,
+    trade_source: Option<TradeSource>,
+    trades: TradeSubscriber,
+    trade_publisher: JoinHandle<()>,
+}
+
+impl ServedBook {
+    async fn connect(
+        served_pair: &ServedPair,
+        level3: bool,
+        connector: Connector<'_>,
+    ) -> Result<Self> {
+        let ServedPair {
+            currency_pair,
+            composition,
+        } = served_pair;
+
+        let (traded_books, stream) =
+            connect_composition(currency_pair, composition, level3, connector).await?;
+
+        let trade_source = TradeSource::new(currency_pair, composition, connector);
+        let trade_stream = TradeSource::connect(trade_source.as_ref()).await?;
+
+        let (books, _) = broadcast::channel(BROADCAST_QUEUE_CAPACITY);
+        let (trades, _) = broadcast::channel(BROADCAST_QUEUE_CAPACITY);
+
+        Ok(Self {
+            composition: composition.clone(),
+            traded_books,
+            publisher: spawn_publisher(stream, books.clone()),
+            books,
+            trade_source,
+            trade_publisher: spawn_publisher(trade_stream, trades.clone()),
+            trades,
+        })
+    }
+
+    /// Applies the composition of `served_pair`, the streams of its clients go on.
+    ///
+    /// Books made of the same traded pairs are reconfigured in place, see
+    /// `TradedBook::reconfigure`. Otherwise the new books are connected before
+    /// the current ones are dropped, so books keep coming.
+    async fn reconfigure(
+        &mut self,
+        served_pair: &ServedPair,
+        level3: bool,
+        connector: Connector<'_>,
+    ) -> Result<()> {
+        let ServedPair {
+            currency_pair,
+            composition,
+        } = served_pair;
+
+        let trade_source = TradeSource::new(currency_pair, composition, connector);
+        let trade_stream = if trade_source != self.trade_source {
+            Some(TradeSource::connect(trade_source.as_ref()).await?)
+        } else {
+            None
+        };
+
+        if same_traded_books(&self.composition, composition) {
+            let specs = traded_specs(currency_pair, composition, level3);
+            for (traded_book, spec) in self.traded_books.iter_mut().zip(&specs) {
+                traded_book.reconfigure(spec, connector).await;
+            }
+        } else {
+            let (traded_books, stream) =
+                connect_composition(currency_pair, composition, level3, connector).await?;
+
+            self.publisher.abort();
+            self.publisher = spawn_publisher(stream, self.books.clone());
+            self.traded_books = traded_books;
+        }
+        self.composition = composition.clone();
+
+        if let Some(trade_stream) = trade_stream {
+            self.trade_publisher.abort();
+            self.trade_publisher = spawn_publisher(trade_stream, self.trades.clone());
+            self.trade_source = trade_source;
+        }
+
+        Ok(())
+    }
+
+    fn traded_pairs(&self) -> Vec<CurrencyPair> {
+        self.traded_books
+            .iter()
+            .map(|traded_book| traded_book.currency_pair.clone())
+            .collect()
+    }
+}
+
+impl Drop for ServedBook {
+    /// Dropping the published streams disconnects their feeds.
+    fn drop(&mut self) {
+        self.publisher.abort();
+        self.trade_publisher.abort();
+    }
+}
+
+/// A pair traded on the exchanges that a served book is made of.
+#[derive(Debug, Clone, PartialEq)]
+struct TradedSpec {
+    currency_pair: CurrencyPair,
+    fees: FeeSchedule,
+    /// Whether Bitstamp's book is maintained order by order.
+    level3: bool,
+}
+
+/// The pairs the book of `composition` is made of, in the order `compose` takes their books.
+fn traded_specs(
+    currency_pair: &CurrencyPair,
+    composition: &Composition,
+    level3: bool,
+) -> Vec<TradedSpec> {
+    let spec = |currency_pair: &CurrencyPair, fees: &FeeSchedule| {
+        TradedSpec {
+            currency_pair: currency_pair.clone(),
+            fees: fees.clone(),
+            level3,
+        }
+    };
+
+    match composition {
+        Composition::Direct(fees) => vec![spec(currency_pair, fees)],
+        Composition::Inverted(fees) => vec![spec(&currency_pair.inverse(), fees)],
+        Composition::Synthetic(legs) => {
+            legs.iter()
+                .map(|leg| spec(&leg.currency_pair, &leg.fees))
+                .collect()
+        }
+        Composition::EquivalentQuotes(members) => {
+            let mut specs = vec![];
+
+            for member in members {
+                specs.push(spec(&member.currency_pair, &member.fees));
+
+                // Rates are mid prices of the raw book
+                if let Conversion::Live { currency_pair, .. } = &member.conversion {
+                    specs.push(TradedSpec {
+                        currency_pair: currency_pair.clone(),
+                        fees: FeeSchedule::default(),
+                        level3: false,
+                    });
+                }
+            }
+
+            specs
+        }
+    }
+}
+
+/// Whether both compositions are made of the same traded books, whatever their fees.
+fn same_traded_books(current: &Composition, reloaded: &Composition) -> bool {
+    let without_fees = |composition: &Composition| {
+        let mut composition = composition.clone();
+
+        match &mut composition {
+            Composition::Direct(fees) | Composition::Inverted(fees) => {
+                *fees = FeeSchedule::default();
+            }
+            Composition::Synthetic(legs) => {
+                for leg in legs.iter_mut() {
+                    leg.fees = FeeSchedule::default();
+                }
+            }
+            Composition::EquivalentQuotes(members) => {
+                for member in members {
+                    member.fees = FeeSchedule::default();
+                }
+            }
+        }
+
+        composition
+    };
+
+    without_fees(current) == without_fees(reloaded)
+}
+
+/// Connects the traded books of `composition` and composes the served book.
+async fn connect_composition(
+    currency_pair: &CurrencyPair,
+    composition: &Composition,
+    level3: bool,
+    connector: Connector<'_>,
+) -> Result<(Vec<TradedBook>, BoxStream<'static, Result<MergedBook>>)> {
+    let mut traded_books = vec![];
+    let mut books = vec![];
+
+    for spec in traded_specs(currency_pair, composition, level3) {
+        let (traded_book, stream) = TradedBook::connect(&spec, connector).await?;
+        traded_books.push(traded_book);
+        books.push(stream);
+    }
+
+    Ok((traded_books, compose(composition, books)))
+}
+
+/// Composes the served book from the `books` of the pairs of `traded_specs`.
+///
+/// See `synthetic::synthetic_books` and `quotes::equivalent_quote_books`.
+fn compose(
+    composition: &Composition,
+    books: Vec<BoxStream<'static, Result<MergedBook>>>,
+) -> BoxStream<'static, Result<MergedBook>> {
+    let mut books = books.into_iter();
+    let mut next_books = move || books.next().expect("a book for every traded pair");
+
+    match composition {
+        Composition::Direct(_) => next_books(),
+        Composition::Inverted(_) => {
+            next_books()
+                .map(|book| book.map(|book| book.inverted()))
+                .boxed()
+        }
+        Composition::Synthetic(legs) => {
+            let [first, second] = &**legs;
+
+            synthetic::synthetic_books(
+                (first.currency_pair.clone(), next_books()),
+                (second.currency_pair.clone(), next_books()),
+            )
+            .boxed()
+        }
+        Composition::EquivalentQuotes(members) => {
+            let mut member_books = vec![];
+
+            for member in members {
+                let books = next_books();
+
+                let rates = match member.conversion {
+                    Conversion::Fixed(rate) => stream::once(future::ready(Ok(rate))).boxed(),
+                    Conversion::Live { inverted, .. } => {
+                        next_books()
+                            // Rates aren't updated while a side of their book is empty
+                            .filter_map(move |book| {
+                                future::ready(
+                                    book.map(|book| quotes::mid_price(&book, inverted))
+                                        .transpose(),
+                                )
+                            })
+                            .boxed()
+                    }
+                };
+
+                member_books.push(MemberBooks {
+                    currency_pair: member.currency_pair.clone(),
+                    books,
+                    rates,
+                });
+            }
+
+            quotes::equivalent_quote_books(member_books).boxed()
+        }
+    }
+}
+
+/// Where the exchange feeds are connected to, and what they're recorded by.
+#[derive(Clone, Copy)]
+struct Connector<'a> {
+    endpoints: &'a ExchangeEndpoints,
+    catalog: &'a PairCatalog,
+    feed_monitor: &'a FeedMonitor,
+    recorder: &'a Recorder,
+}
+
+impl<'a> Connector<'a> {
+    fn new(
+        settings: &'a BookSettings,
+        feed_monitor: &'a FeedMonitor,
+        recorder: &'a Recorder,
+    ) -> Self {
+        Self {
+            endpoints: &settings.endpoints,
+            catalog: &settings.catalog,
+            feed_monitor,
+            recorder,
+        }
+    }
+
+    /// Exchanges trading `currency_pair`, in the order they are connected.
+    fn exchanges_trading(&self, currency_pair: &CurrencyPair) -> Vec<&'static str> {
+        let trading = self.catalog.exchanges_trading(currency_pair);
+
+        EXCHANGES
+            .into_iter()
+            .filter(|exchange| trading.contains(exchange))
+            .collect()
+    }
+
+    /// Connects to the book of `currency_pair` on `exchange`, answering pings and parsing summaries.
+    ///
+    /// Level-3 books are maintained from order events, which aren't recorded.
+    async fn connect_feed(
+        &self,
+        exchange: &'static str,
+        currency_pair: &CurrencyPair,
+        settings: &FeedSettings,
+    ) -> Result<BoxStream<'static, Result<Summary>>> {
+        let FeedSettings {
+            url,
+            reconnect_delay,
+            level3,
+        } = settings;
+
+        let summaries = if exchange == BinanceExchange::EXCHANGE_NAME {
+            let binance =
+                BinanceExchange::connect_with_reconnects(url, currency_pair, *reconnect_delay)
+                    .await?;
+            self.recorder
+                .tap(exchange, binance)
+                .map(|message| BinanceExchange::try_parse_summary(message?))
+                .boxed()
+        } else if *level3 {
+            BitstampExchange::level3_summaries(url, currency_pair, *reconnect_delay)
+                .await?
+                .boxed()
+        } else {
+            let bitstamp =
+                BitstampExchange::connect_with_reconnects(url, currency_pair, *reconnect_delay)
+                    .await?;
+            self.recorder
+                .tap(exchange, bitstamp)
+                .map(|message| BitstampExchange::try_parse_summary(message?))
+                .boxed()
+        };
+
+        Ok(self
+            .feed_monitor
+            .track(exchange, currency_pair, summaries)
+            .boxed())
+    }
+}
+
+/// How the feed of an exchange is connected, it's connected again when this changes.
+#[derive(Debug, Clone, PartialEq)]
+struct FeedSettings {
+    url: String,
+    reconnect_delay: Duration,
+    /// Whether the book is maintained order by order, only Bitstamp's can be.
+    level3: bool,
+}
+
+impl FeedSettings {
+    fn new(exchange: &str, endpoints: &ExchangeEndpoints, level3: bool) -> Self {
+        let is_bitstamp = exchange == BitstampExchange::EXCHANGE_NAME;
+
+        Self {
+            url: if is_bitstamp {
+                endpoints.bitstamp.clone()
+            } else {
+                endpoints.binance.clone()
+            },
+            reconnect_delay: endpoints.reconnect_delay,
+            level3: level3 && is_bitstamp,
+        }
+    }
+}
+
+/// What the feeds of a traded pair send to its merged book.
+enum FeedUpdate {
+    Summary(Result<Summary>),
+    /// The feed of the exchange was removed, its last summary isn't merged anymore.
+    Removed(&'static str),
+}
+
+/// The merged book of a pair traded on the exchanges, fed by a task per exchange.
+struct TradedBook {
+    currency_pair: CurrencyPair,
+    feeds: HashMap<&'static str, Feed>,
+    updates: mpsc::UnboundedSender<FeedUpdate>,
+    fees: watch::Sender<FeeSchedule>,
+}
+
+struct Feed {
+    settings: FeedSettings,
+    task: JoinHandle<()>,
+}
+
+impl Feed {
+    /// Disconnects the feed, nothing is sent anymore once this returns.
+    async fn stop(self) {
+        self.task.abort();
+        let _ = self.task.await;
+    }
+}
+
+impl TradedBook {
+    /// Connects to the exchanges trading the pair of `spec` and returns its merged books.
+    ///
+    /// The feeds are disconnected once the returned stream is dropped.
+    async fn connect(
+        spec: &TradedSpec,
+        connector: Connector<'_>,
+    ) -> Result<(Self, BoxStream<'static, Result<MergedBook>>)> {
+        let exchanges = connector.exchanges_trading(&spec.currency_pair);
+        if exchanges.is_empty() {
+            return Err(Error::UnsupportedCurrencyPair(
+                spec.currency_pair.as_str().to_owned(),
+            ));
+        }
+
+        let (updates, receiver) = mpsc::unbounded_channel();
+        let (fees, fees_receiver) = watch::channel(spec.fees.clone());
+
+        let mut traded_book = Self {
+            currency_pair: spec.currency_pair.clone(),
+            feeds: HashMap::new(),
+            updates,
+            fees,
+        };
+
+        for exchange in exchanges {
+            let settings = FeedSettings::new(exchange, connector.endpoints, spec.level3);
+            let summaries = connector
+                .connect_feed(exchange, &spec.currency_pair, &settings)
+                .await?;
+
+            let task = traded_book.spawn_feed(summaries);
+            traded_book.feeds.insert(exchange, Feed { settings, task });
+        }
+
+        let books = merge_feed_updates(UnboundedReceiverStream::new(receiver), fees_receiver);
+
+        Ok((traded_book, books.boxed()))
+    }
+
+    /// Applies `spec` in place, the fees apply from the next merged book on.
+    ///
+    /// Only the feeds of exchanges added, removed or connected differently
+    /// are connected again, the others stay connected. Feeds failing to
+    /// connect are logged and kept as they are.
+    async fn reconfigure(&mut self, spec: &TradedSpec, connector: Connector<'_>) {
+        self.fees.send_replace(spec.fees.clone());
+
+        let exchanges = connector.exchanges_trading(&self.currency_pair);
+
+        let removed: Vec<_> = self
+            .feeds
+            .keys()
+            .copied()
+            .filter(|exchange| !exchanges.contains(exchange))
+            .collect();
+
+        for exchange in removed {
+            if let Some(feed) = self.feeds.remove(exchange) {
+                feed.stop().await;
+                let _ = self.updates.send(FeedUpdate::Removed(exchange));
+            }
+        }
+
+        for exchange in exchanges {
+            let settings = FeedSettings::new(exchange, connector.endpoints, spec.level3);
+            if self.feeds.get(exchange).map(|feed| &feed.settings) == Some(&settings) {
+                continue;
+            }
+
+            // Connect the new feed before stopping the current one, so books keep coming
+            let summaries = match connector
+                .connect_feed(exchange, &self.currency_pair, &settings)
+                .await
+            {
+                Ok(summaries) => summaries,
+                Err(err) => {
+                    log::error!(
+                        "Failed to connect the reloaded {exchange} feed of {}: {err}.",
+                        self.currency_pair.as_str()
+                    );
+                    continue;
+                }
+            };
+
+            if let Some(feed) = self.feeds.remove(exchange) {
+                feed.stop().await;
+            }
+
+            let task = self.spawn_feed(summaries);
+            self.feeds.insert(exchange, Feed { settings, task });
+        }
+    }
+
+    /// Forwards `summaries` to the merged book until nobody merges them anymore.
+    fn spawn_feed(&self, mut summaries: BoxStream<'static, Result<Summary>>) -> JoinHandle<()> {
+        let updates = self.updates.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    summary = summaries.next() => match summary {
+                        Some(summary) => {
+                            let _ = updates.send(FeedUpdate::Summary(summary));
+                        }
+                        None => break,
+                    },
+                    _ = updates.closed() => break,
+                }
+            }
+        })
+    }
+}
+
+/// Where the trades of a served pair come from, they're connected again when it changes.
+#[derive(Debug, Clone, PartialEq)]
+struct TradeSource {
+    traded_pair: CurrencyPair,
+    inverted: bool,
+    /// Exchanges trading the pair, and their URLs.
+    exchanges: Vec<(&'static str, String)>,
+    reconnect_delay: Duration,
+}
+
+impl TradeSource {
+    /// Trades of an inverted book are the inverse pair's, inverted. Synthetic and
+    /// equivalent quote books aren't traded as such, they have no trades.
+    fn new(
+        currency_pair: &CurrencyPair,
+        composition: &Composition,
+        connector: Connector<'_>,
+    ) -> Option<Self> {
+        let (traded_pair, inverted) = match composition {
+            Composition::Direct(_) => (currency_pair.clone(), false),
+            Composition::Inverted(_) => (currency_pair.inverse(), true),
+            Composition::Synthetic(_) | Composition::EquivalentQuotes(_) => return None,
+        };
+
+        let exchanges = connector
+            .exchanges_trading(&traded_pair)
+            .into_iter()
+            .map(|exchange| {
+                let FeedSettings { url, .. } =
+                    FeedSettings::new(exchange, connector.endpoints, false);
+                (exchange, url)
+            })
+            .collect();
+
+        Some(Self {
+            traded_pair,
+            inverted,
+            exchanges,
+            reconnect_delay: connector.endpoints.reconnect_delay,
+        })
+    }
+
+    /// Connects to the public trades of `source`, none without a source.
+    async fn connect(source: Option<&Self>) -> Result<BoxStream<'static, Result<Trade>>> {
+        let source = match source {
+            Some(source) => source,
+            None => return Ok(stream::empty().boxed()),
+        };
+
+        let mut trades = vec![];
+
+        for (exchange, url) in &source.exchanges {
+            let exchange_trades = if *exchange == BinanceExchange::EXCHANGE_NAME {
+                BinanceExchange::trades(url, &source.traded_pair, source.reconnect_delay)
+                    .await?
+                    .boxed()
+            } else {
+                BitstampExchange::trades(url, &source.traded_pair, source.reconnect_delay)
+                    .await?
+                    .boxed()
+            };
+            trades.push(exchange_trades);
+        }
+
+        let trades = stream::select_all(trades);
+        if source.inverted {
+            Ok(trades
+                .map(|trade| trade.map(|trade| trade.inverted()))
+                .boxed())
+        } else {
+            Ok(trades.boxed())
+        }
+    }
+}
+
+/// Connects to the exchanges trading `currency_pair` in `catalog` and
+/// returns the merged book stream.
+///
+/// Every valid summary parsed from an exchange is recorded in `feed_monitor`,
+/// and every raw message received is recorded by `recorder`. With `level3`,
+/// Bitstamp's levels come from a book maintained order by order, whose
+/// messages aren't recorded. Books are merged with `fees`.
+pub async fn build_aggregated_book_order(
+    currency_pair: &CurrencyPair,
+    catalog: &PairCatalog,
+    endpoints: &ExchangeEndpoints,
+    feed_monitor: &FeedMonitor,
+    recorder: &Recorder,
+    level3: bool,
+    fees: FeeSchedule,
+) -> Result<impl Stream<Item = Result<MergedBook>>> {
+    let connector = Connector {
+        endpoints,
+        catalog,
+        feed_monitor,
+        recorder,
+    };
+    let spec = TradedSpec {
+        currency_pair: currency_pair.clone(),
+        fees,
+        level3,
+    };
+
+    let (_, books) = TradedBook::connect(&spec, connector).await?;
+
+    Ok(books)
+}
+
+/// Consumes `stream` in the background, broadcasting every item with `publisher`.
+///
+/// Aborting the returned task drops `stream`, and the connections it's made of.
+pub fn spawn_publisher<T: Send + Sync + 'static>(
+    stream: impl Stream<Item = Result<T>> + Send + 'static,
+    publisher: broadcast::Sender<Result<Arc<T>, String>>,
+) -> JoinHandle<()> {
+    let mut stream = Box::pin(stream);
+
+    // Consume the stream and transmit all summaries to the publisher
+    tokio::spawn(async move {
+        let stringify_error = |err| format!("{err}");
+
+        while let Some(updated_summary) = stream.next().await {
+            let summary = updated_summary.map(Arc::new).map_err(stringify_error);
+
+            // Ignore send errors, nobody might be listening to this publisher now,
+            // however, new listeners are spawned on-demand when requests are received.
+            let _ = publisher.send(summary);
+        }
+    })
+}
+
+/// Merges the summaries of `stream` with `fees`, see `merge_feed_updates`.
+pub fn merge_summaries(
+    stream: impl Stream<Item = Result<Summary>>,
+    fees: FeeSchedule,
+) -> impl Stream<Item = Result<MergedBook>> {
+    let (_, fees) = watch::channel(fees);
+    merge_feed_updates(stream.map(FeedUpdate::Summary), fees)
+}
+
+// Merge summaries from different exchanges, summaries are cached by
+// the (hopefully) unique exchange names, and overwritten every
+// time the same exchange updates it's latest summary. Summaries of
+// removed feeds are dropped, invalid ones are logged and skipped, and
+// books are merged with the current fees.
+//
+// Crossed and locked books are detected as they are merged, so every
+// client sees the same start time for each crossing.
+fn merge_feed_updates(
+    updates: impl Stream<Item = FeedUpdate>,
+    fees: watch::Receiver<FeeSchedule>,
+) -> impl Stream<Item = Result<MergedBook>> {
+    updates
+        .scan(
+            (HashMap::<String, Summary>::new(), None),
+            move |(cached_summaries, last_crossing), update| {
+                match update {
+                    FeedUpdate::Summary(Ok(next_summary)) => {
+                        let cache_key = next_summary.asks[0].exchange.clone();
+                        cached_summaries.insert(cache_key, next_summary);
+                    }
+                    // The last valid summary of the exchange stays merged
+                    FeedUpdate::Summary(Err(err)) => {
+                        log::warn!("Skipped a summary that couldn't be merged: {err}.");
+                        return future::ready(Some(None));
+                    }
+                    FeedUpdate::Removed(exchange) => {
+                        cached_summaries.remove(exchange);
+                        if cached_summaries.is_empty() {
+                            return future::ready(Some(None));
+                        }
+                    }
+                }
+
+                let mut merged_book = MergedBook::merge(cached_summaries.values(), &fees.borrow());
+                merged_book
+                    .detect_crossing(last_crossing.as_ref(), recorder::unix_timestamp_millis());
+                *last_crossing = merged_book.crossing.clone();
+
+                future::ready(Some(Some(Ok(merged_book))))
+            },
+        )
+        .filter_map(future::ready)
+}
@@ -1,32 +1,670 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
 use clap::Parser;
+use log::LevelFilter;
 
 use crate::{
-    currencies::{CurrencyPair, SUPPORTED_CURRENCY_PAIRS},
-    Result,
+    client::BookView,
+    config::{self, Config},
+    currencies::CurrencyPair,
+    exchanges::{BinanceExchange, BitstampExchange, ConnectToOrderBook, ExchangeEndpoints},
+    fees::{FeeSchedule, TakerFee},
+    instruments::{self, InstrumentSources, PairCatalog},
+    quotes::{self, EquivalentQuote, QuoteMember},
+    replay::ReplaySpeed,
+    server::ServerOptions,
+    synthetic::{self, SyntheticLeg},
+    terminal_ui::BookSource,
+    Error, Result,
 };
 
-pub fn parse_arguments() -> Result<(CurrencyPair, u16)> {
-    let CliArgs {
-        currency_pair,
-        port,
-    } = CliArgs::parse();
+/// Currency pair served when none is given nor configured.
+const DEFAULT_CURRENCY_PAIR: &str = "ETHBTC";
+/// File caching the instruments when neither given nor configured.
+const DEFAULT_INSTRUMENTS_CACHE: &str = "instruments.json";
+
+/// What the program was asked to do.
+pub enum Command {
+    /// Serve the aggregated order book, reloading the config file if given.
+    Serve(Box<ServeCommand>, Option<ServeReloader>),
+    /// Print the books streamed by a running server.
+    Watch {
+        addr: String,
+        currency_pair: Option<CurrencyPair>,
+        format: OutputFormat,
+        view: BookView,
+    },
+    /// Render the merged book in the terminal.
+    Tui {
+        source: BookSource,
+        currency_pair: CurrencyPair,
+    },
+    /// Serve books replayed from recorded exchange messages.
+    Replay {
+        files: Vec<PathBuf>,
+        speed: ReplaySpeed,
+        currency_pair: CurrencyPair,
+        port: u16,
+    },
+    /// Print which exchanges trade each pair.
+    ListPairs { catalog: PairCatalog },
+}
+
+/// Everything needed to serve the aggregated order book, from the arguments and the config.
+pub struct ServeCommand {
+    /// Pairs to serve, the first one is served to requests without a pair.
+    pub pairs: Vec<ServedPair>,
+    pub server: ServerOptions,
+    pub record_dir: Option<PathBuf>,
+    pub book: BookSettings,
+    pub staleness_timeout: Duration,
+    pub log_level: LevelFilter,
+}
+
+/// A served pair and the books it's made of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServedPair {
+    pub currency_pair: CurrencyPair,
+    pub composition: Composition,
+}
+
+/// How the exchange feeds of every served pair are connected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookSettings {
+    pub endpoints: ExchangeEndpoints,
+    pub level3: bool,
+    pub catalog: PairCatalog,
+}
 
-    let currency_pair = currency_pair.parse()?;
+/// Resolves the serve command again, from the same arguments and the config file as it is now.
+pub struct ServeReloader {
+    args: ServeArgs,
+    config_path: PathBuf,
+}
+
+impl ServeReloader {
+    pub fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    pub async fn reload(&self) -> Result<ServeCommand> {
+        let config = Config::load(&self.config_path)?;
+        self.args.clone().resolve(config).await
+    }
+}
 
-    Ok((currency_pair, port))
+/// Which books the exchanges trade the served book is made of.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Composition {
+    /// The book of the served pair, with its fees.
+    Direct(FeeSchedule),
+    /// The book of the inverse pair, with its fees, inverted.
+    Inverted(FeeSchedule),
+    /// The books of two pairs through a shared currency.
+    Synthetic(Box<[SyntheticLeg; 2]>),
+    /// The books of the served pair and of its base against equivalent quotes.
+    EquivalentQuotes(Vec<QuoteMember>),
+}
+
+impl Composition {
+    /// Composes the book of `currency_pair` through `via`, with `equivalent_quotes`,
+    /// or else from the book of the pair or of its inverse, whichever `catalog` supports.
+    fn resolve(
+        currency_pair: &CurrencyPair,
+        via: Option<&str>,
+        equivalent_quotes: &[EquivalentQuote],
+        catalog: &PairCatalog,
+        taker_fees: &[TakerFee],
+        net_of_fees: bool,
+    ) -> Result<Self> {
+        let composition = if let Some(via) = via {
+            let legs = synthetic::legs_via(currency_pair, via)?;
+
+            if let Some(unsupported) = legs.iter().find(|leg| !catalog.supports(leg)) {
+                return Err(Error::UnsupportedCurrencyPair(
+                    unsupported.as_str().to_owned(),
+                ));
+            }
+
+            Self::Synthetic(Box::new(legs.map(|leg| {
+                SyntheticLeg {
+                    fees: FeeSchedule::new(taker_fees, &leg, net_of_fees),
+                    currency_pair: leg,
+                }
+            })))
+        } else if !equivalent_quotes.is_empty() {
+            Self::EquivalentQuotes(quotes::resolve_members(
+                currency_pair,
+                equivalent_quotes,
+                catalog,
+                taker_fees,
+                net_of_fees,
+            )?)
+        } else if catalog.supports(currency_pair) {
+            Self::Direct(FeeSchedule::new(taker_fees, currency_pair, net_of_fees))
+        } else if catalog.supports(&currency_pair.inverse()) {
+            let traded_pair = currency_pair.inverse();
+            Self::Inverted(FeeSchedule::new(taker_fees, &traded_pair, net_of_fees))
+        } else {
+            return Err(Error::UnsupportedCurrencyPair(
+                currency_pair.as_str().to_owned(),
+            ));
+        };
+
+        Ok(composition)
+    }
+}
+
+pub async fn parse_arguments() -> Result<Command> {
+    let CliArgs { command, serve } = CliArgs::parse();
+
+    if command.is_some() {
+        init_logging();
+    }
+
+    let command = match command {
+        None => {
+            let reloader = serve.config.clone().map(|config_path| {
+                ServeReloader {
+                    args: serve.clone(),
+                    config_path,
+                }
+            });
+
+            let config = serve.load_config()?;
+            init_logging();
+            log::set_max_level(serve.log_level.unwrap_or_else(|| config.log_level()));
+
+            Command::Serve(Box::new(serve.resolve(config).await?), reloader)
+        }
+        Some(Subcommand::Watch(WatchArgs {
+            addr,
+            currency_pair,
+            format,
+            aggregated,
+            bucket,
+            metrics,
+            index,
+            trades,
+        })) => {
+            let view = if let Some(bucket) = bucket {
+                BookView::Bucketed(bucket.parse()?)
+            } else if aggregated {
+                BookView::Aggregated
+            } else if metrics {
+                BookView::Metrics
+            } else if index {
+                BookView::Index
+            } else if trades {
+                BookView::Trades
+            } else {
+                BookView::Levels
+            };
+
+            Command::Watch {
+                addr,
+                currency_pair: currency_pair.as_deref().map(str::parse).transpose()?,
+                format,
+                view,
+            }
+        }
+        Some(Subcommand::Tui(TuiArgs {
+            addr,
+            currency_pair,
+            endpoints,
+            instruments,
+        })) => {
+            let currency_pair: CurrencyPair = currency_pair.parse()?;
+
+            let source = match addr {
+                Some(addr) => BookSource::Remote(addr),
+                None => {
+                    let catalog = instruments.load_catalog(&Config::default()).await?;
+
+                    if !catalog.supports(&currency_pair) {
+                        return Err(Error::UnsupportedCurrencyPair(
+                            currency_pair.as_str().to_owned(),
+                        ));
+                    }
+
+                    BookSource::Local(endpoints.resolve(&Config::default()), catalog)
+                }
+            };
+
+            Command::Tui {
+                source,
+                currency_pair,
+            }
+        }
+        Some(Subcommand::Replay(ReplayArgs {
+            files,
+            speed,
+            currency_pair,
+            port,
+        })) => {
+            Command::Replay {
+                files,
+                speed,
+                currency_pair: currency_pair.parse()?,
+                port,
+            }
+        }
+        Some(Subcommand::ListPairs(instruments)) => {
+            Command::ListPairs {
+                catalog: instruments.load_catalog(&Config::default()).await?,
+            }
+        }
+    };
+
+    Ok(command)
+}
+
+/// Logs to stderr our messages of `log::max_level`, info by default, and
+/// only the warnings and errors of dependencies.
+fn init_logging() {
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Warn)
+        .filter_module(env!("CARGO_CRATE_NAME"), LevelFilter::Trace)
+        .init();
+
+    log::set_max_level(LevelFilter::Info);
 }
 
 /// gRPC server that streams an order book for a currency pair.
 #[derive(Parser, Debug)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct CliArgs {
-    /// Currency pair for the order book.
+    #[clap(subcommand)]
+    pub command: Option<Subcommand>,
+
+    #[clap(flatten)]
+    pub serve: ServeArgs,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ServeArgs {
+    /// TOML file configuring the exchanges, the book, the server and logging.
+    ///
+    /// Arguments override its settings, see `keyrocky.example.toml`.
+    #[clap(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Currency pair for the order book, like "ETHBTC" or "ETH/BTC", see the supported pairs in the README.
+    ///
+    /// Served instead of the configured pairs, ETHBTC if none are configured.
+    pub currency_pair: Option<String>,
+
+    /// Port where the server will be served, on the configured address.
+    ///
+    /// 50051 unless configured.
+    pub port: Option<u16>,
+
+    /// Record every raw exchange message to compressed files in this directory.
+    #[clap(long = "record", value_name = "DIR")]
+    pub record_dir: Option<PathBuf>,
+
+    /// Maintain Bitstamp's book order by order, sending order counts and queues.
+    ///
+    /// Order events can't be replayed, so this can't be combined with `--record`.
+    #[clap(long, conflicts_with = "record-dir", overrides_with = "no-level3")]
+    pub level3: bool,
+
+    /// Keep Bitstamp's book by levels, even if the config maintains it order by order.
+    #[clap(long, overrides_with = "level3")]
+    pub no_level3: bool,
+
+    /// Taker fee of an exchange in basis points, for all its pairs or a single one.
+    ///
+    /// Fees of a pair override the ones of the exchange. Levels carry the
+    /// effective price of taking them, net of this fee.
+    #[clap(long = "taker-fee", value_name = "EXCHANGE[:PAIR]=BPS")]
+    pub taker_fees: Vec<String>,
+
+    /// Order the merged book by the effective prices instead of the raw ones.
+    #[clap(long, overrides_with = "no-net-of-fees")]
+    pub net_of_fees: bool,
+
+    /// Order the merged book by the raw prices, even if the config orders it net of fees.
+    #[clap(long, overrides_with = "net-of-fees")]
+    pub no_net_of_fees: bool,
+
+    /// Compose the book from two traded pairs through this currency.
+    ///
+    /// ETHGBP through BTC is composed from the books of ETHBTC and BTCGBP.
+    /// Books of both legs aren't recorded, so this can't be combined with `--record`.
+    #[clap(long, value_name = "CURRENCY", conflicts_with = "record-dir")]
+    pub via: Option<String>,
+
+    /// Merge the books of the pair's base against this quote, equivalent to the pair's quote.
+    ///
+    /// Prices are converted at RATE, 1 by default, or with "live" at the mid price
+    /// of the book between both quotes, like `USDC=live` for a pair quoted in USDT.
+    /// The pair itself is merged too if supported, so `ETHUSD` can merge the
+    /// books of USDT, USDC and PAX. Books aren't recorded, so this can't be
+    /// combined with `--record`.
     #[clap(
-        default_value = "ETHBTC",
-        possible_values = SUPPORTED_CURRENCY_PAIRS
+        long = "equivalent-quote",
+        value_name = "QUOTE[=RATE]",
+        conflicts_with_all = &["record-dir", "via"]
     )]
+    pub equivalent_quotes: Vec<String>,
+
+    #[clap(flatten)]
+    pub endpoints: EndpointArgs,
+
+    #[clap(flatten)]
+    pub instruments: InstrumentArgs,
+
+    /// Lowest level of the logged messages: off, error, warn, info, debug or trace.
+    #[clap(long, value_name = "LEVEL", value_parser = config::parse_log_level)]
+    pub log_level: Option<LevelFilter>,
+}
+
+impl ServeArgs {
+    fn load_config(&self) -> Result<Config> {
+        match &self.config {
+            Some(path) => Config::load(path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// Resolves what to serve, arguments overriding `config`, which overrides the defaults.
+    async fn resolve(self, config: Config) -> Result<ServeCommand> {
+        let ServeArgs {
+            config: _,
+            currency_pair,
+            port,
+            record_dir,
+            endpoints,
+            level3,
+            no_level3,
+            taker_fees,
+            net_of_fees,
+            no_net_of_fees,
+            via,
+            equivalent_quotes,
+            instruments,
+            log_level,
+        } = self;
+
+        // A pair given as argument is served alone
+        let currency_pairs = match currency_pair {
+            Some(currency_pair) => vec![currency_pair.parse()?],
+            None if config.pairs.is_empty() => vec![DEFAULT_CURRENCY_PAIR.parse()?],
+            None => {
+                config
+                    .pairs
+                    .iter()
+                    .map(|pair| pair.parse())
+                    .collect::<Result<Vec<CurrencyPair>>>()?
+            }
+        };
+        let level3 = flag(level3, no_level3, config.book.level3);
+        let net_of_fees = flag(net_of_fees, no_net_of_fees, config.book.net_of_fees);
+        // A composition given as arguments replaces the configured one as a whole
+        let (via, equivalent_quotes) = if via.is_some() || !equivalent_quotes.is_empty() {
+            (via, equivalent_quotes)
+        } else {
+            (
+                config.book.via.clone(),
+                config.book.equivalent_quotes.clone(),
+            )
+        };
+
+        if record_dir.is_some() && (level3 || via.is_some() || !equivalent_quotes.is_empty()) {
+            return Err(Error::InvalidConfig(
+                "--record can't be combined with level3, via or equivalent quotes".into(),
+            ));
+        }
+
+        if record_dir.is_some() && currency_pairs.len() > 1 {
+            return Err(Error::InvalidConfig(
+                "--record records a single pair, pass the pair to record as argument".into(),
+            ));
+        }
+
+        // Fees given as arguments come last, so they override the configured ones
+        let taker_fees = config
+            .book
+            .taker_fees
+            .iter()
+            .chain(&taker_fees)
+            .map(|fee| fee.parse())
+            .collect::<Result<Vec<TakerFee>>>()?;
+
+        let equivalent_quotes = equivalent_quotes
+            .iter()
+            .map(|quote| quote.parse())
+            .collect::<Result<Vec<EquivalentQuote>>>()?;
+
+        let catalog = instruments.load_catalog(&config).await?;
+
+        let pairs = currency_pairs
+            .into_iter()
+            .map(|currency_pair| {
+                let composition = Composition::resolve(
+                    &currency_pair,
+                    via.as_deref(),
+                    &equivalent_quotes,
+                    &catalog,
+                    &taker_fees,
+                    net_of_fees,
+                )?;
+
+                Ok(ServedPair {
+                    currency_pair,
+                    composition,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(ServeCommand {
+            pairs,
+            server: ServerOptions {
+                addr: config.bind_addr(port),
+                depth: config.depth(),
+                tls: config.tls_identity()?,
+            },
+            record_dir,
+            book: BookSettings {
+                endpoints: endpoints.resolve(&config),
+                level3,
+                catalog,
+            },
+            staleness_timeout: config.staleness_timeout(),
+            log_level: log_level.unwrap_or_else(|| config.log_level()),
+        })
+    }
+}
+
+/// Whether a setting is on, given its `--flag` and `--no-flag` arguments and the configured value.
+fn flag(enabled: bool, disabled: bool, configured: bool) -> bool {
+    match (enabled, disabled) {
+        (true, _) => true,
+        (_, true) => false,
+        _ => configured,
+    }
+}
+
+/// Overrides of the exchange websocket URLs.
+#[derive(clap::Args, Debug, Clone)]
+struct EndpointArgs {
+    /// Websocket base URL of Binance.
+    #[clap(long, value_name = "URL")]
+    pub binance_url: Option<String>,
+
+    /// Websocket base URL of Bitstamp.
+    #[clap(long, value_name = "URL")]
+    pub bitstamp_url: Option<String>,
+}
+
+impl EndpointArgs {
+    /// The given URLs, or else the configured ones.
+    fn resolve(self, config: &Config) -> ExchangeEndpoints {
+        let defaults = ExchangeEndpoints::default();
+        let exchanges = &config.exchanges;
+
+        ExchangeEndpoints {
+            binance: self
+                .binance_url
+                .or_else(|| exchanges.binance.url.clone())
+                .unwrap_or(defaults.binance),
+            bitstamp: self
+                .bitstamp_url
+                .or_else(|| exchanges.bitstamp.url.clone())
+                .unwrap_or(defaults.bitstamp),
+            reconnect_delay: config.reconnect_delay(),
+        }
+    }
+}
+
+/// Where the pairs the exchanges trade are discovered from.
+#[derive(clap::Args, Debug, Clone)]
+struct InstrumentArgs {
+    /// Binance's exchange info, a URL or the path of a local file.
+    #[clap(long, value_name = "URL|PATH")]
+    pub binance_instruments: Option<String>,
+
+    /// Bitstamp's trading pairs info, a URL or the path of a local file.
+    #[clap(long, value_name = "URL|PATH")]
+    pub bitstamp_instruments: Option<String>,
+
+    /// File caching the pairs the exchanges trade, fetched again once a day.
+    ///
+    /// "instruments.json" unless configured.
+    #[clap(long, value_name = "PATH")]
+    pub instruments_cache: Option<PathBuf>,
+}
+
+impl InstrumentArgs {
+    /// Loads the pairs the exchanges enabled in `config` trade, from the given
+    /// sources or else the configured ones.
+    async fn load_catalog(self, config: &Config) -> Result<PairCatalog> {
+        let defaults = InstrumentSources::default();
+        let exchanges = &config.exchanges;
+
+        let sources = InstrumentSources {
+            binance: self
+                .binance_instruments
+                .or_else(|| exchanges.binance.instruments.clone())
+                .unwrap_or(defaults.binance),
+            bitstamp: self
+                .bitstamp_instruments
+                .or_else(|| exchanges.bitstamp.instruments.clone())
+                .unwrap_or(defaults.bitstamp),
+        };
+        let cache = self
+            .instruments_cache
+            .or_else(|| config.feeds.instruments_cache.clone())
+            .unwrap_or_else(|| DEFAULT_INSTRUMENTS_CACHE.into());
+
+        let mut catalog = instruments::load_catalog(&sources, &cache).await?;
+
+        let enabled = [
+            (BinanceExchange::EXCHANGE_NAME, exchanges.binance.enabled),
+            (BitstampExchange::EXCHANGE_NAME, exchanges.bitstamp.enabled),
+        ];
+        for (exchange, enabled) in enabled {
+            if !enabled {
+                catalog.exchanges.remove(exchange);
+            }
+        }
+
+        Ok(catalog)
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// Connect to a running server and print the books it streams.
+    Watch(WatchArgs),
+    /// Render the merged book live in the terminal.
+    Tui(TuiArgs),
+    /// Serve books replayed from files written with `--record`.
+    Replay(ReplayArgs),
+    /// Print which exchanges trade each pair.
+    ListPairs(InstrumentArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct WatchArgs {
+    /// Address of the server.
+    #[clap(long, default_value = "http://[::1]:50051")]
+    pub addr: String,
+
+    /// Currency pair to stream, the server's first one if omitted.
+    #[clap(long = "pair")]
+    pub currency_pair: Option<String>,
+
+    /// How each book is printed.
+    #[clap(long, value_enum, default_value = "table")]
+    pub format: OutputFormat,
+
+    /// Merge the levels at equal prices, showing the amount of each exchange.
+    #[clap(long)]
+    pub aggregated: bool,
+
+    /// Group prices into buckets of this size, like "0.5", or "1bp" of the mid price.
+    #[clap(long, value_name = "SIZE", conflicts_with = "aggregated")]
+    pub bucket: Option<String>,
+
+    /// Print the metrics of each book instead of its levels.
+    #[clap(long, conflicts_with_all = &["aggregated", "bucket"])]
+    pub metrics: bool,
+
+    /// Print the index price of the exchanges and its constituents instead of the levels.
+    #[clap(long, conflicts_with_all = &["aggregated", "bucket", "metrics"])]
+    pub index: bool,
+
+    /// Print the trades of the exchanges instead of the levels.
+    #[clap(long, conflicts_with_all = &["aggregated", "bucket", "metrics", "index"])]
+    pub trades: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct TuiArgs {
+    /// Address of a running server, the book is merged locally when omitted.
+    #[clap(long)]
+    pub addr: Option<String>,
+
+    /// Currency pair for the order book.
+    #[clap(long = "pair", default_value = "ETHBTC")]
+    pub currency_pair: String,
+
+    #[clap(flatten)]
+    pub endpoints: EndpointArgs,
+
+    #[clap(flatten)]
+    pub instruments: InstrumentArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ReplayArgs {
+    /// Recorded files to replay, messages from all files are replayed in timestamp order.
+    #[clap(required = true)]
+    pub files: Vec<PathBuf>,
+
+    /// Pacing multiplier over the original pacing, or "max" to replay as fast as possible.
+    #[clap(long, default_value = "1")]
+    pub speed: ReplaySpeed,
+
+    /// Currency pair of the recorded books.
+    #[clap(long = "pair", default_value = "ETHBTC")]
     pub currency_pair: String,
 
     /// Port where the server will be served.
-    #[clap(default_value = "50051")]
+    #[clap(long, default_value = "50051")]
     pub port: u16,
 }
+
+/// Output formats of the `watch` subcommand.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    /// A human readable price ladder.
+    Table,
+    /// One JSON object per line.
+    Json,
+}
@@ -0,0 +1,350 @@
+//! Client for a running aggregator server.
+
+use tonic::{Request, Streaming};
+
+use crate::{
+    cli::OutputFormat,
+    currencies::CurrencyPair,
+    merged_book::BucketSize,
+    order_book::{
+        AggregatedLevel, AggregatedSummary, BucketedBookRequest, Empty, Index, IndexRequest, Level,
+        Metrics, MetricsRequest, OrderbookAggregatorClient, RequestedBucketSize, Summary, Trade,
+        CURRENCY_PAIR_METADATA_KEY,
+    },
+    Result,
+};
+
+/// Which of the server's views of the book to request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BookView {
+    /// Every level of every exchange.
+    Levels,
+    /// Levels at equal prices merged into one.
+    Aggregated,
+    /// Prices grouped into buckets.
+    Bucketed(BucketSize),
+    /// Analytics of each book.
+    Metrics,
+    /// Index price of the exchanges.
+    Index,
+    /// Trades of the exchanges.
+    Trades,
+}
+
+/// Connects to the server at `addr` and prints every book it streams.
+///
+/// If `currency_pair` is given, the server streams that pair or refuses the
+/// request, otherwise it streams its first pair.
+pub async fn watch(
+    addr: String,
+    currency_pair: Option<CurrencyPair>,
+    format: OutputFormat,
+    view: BookView,
+) -> Result<()> {
+    let mut stream = match view {
+        BookView::Levels => {
+            let mut stream = subscribe(addr, currency_pair.as_ref()).await?;
+
+            while let Some(summary) = stream.message().await? {
+                match format {
+                    OutputFormat::Table => print_table(&summary),
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&summary)?),
+                }
+            }
+
+            return Ok(());
+        }
+        BookView::Metrics => {
+            let mut stream = subscribe_metrics(addr, currency_pair.as_ref()).await?;
+
+            while let Some(metrics) = stream.message().await? {
+                match format {
+                    OutputFormat::Table => print_metrics(&metrics),
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&metrics)?),
+                }
+            }
+
+            return Ok(());
+        }
+        BookView::Index => {
+            let mut stream = subscribe_index(addr, currency_pair.as_ref()).await?;
+
+            while let Some(index) = stream.message().await? {
+                match format {
+                    OutputFormat::Table => print_index(&index),
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&index)?),
+                }
+            }
+
+            return Ok(());
+        }
+        BookView::Trades => {
+            let mut stream = subscribe_trades(addr, currency_pair.as_ref()).await?;
+
+            while let Some(trade) = stream.message().await? {
+                match format {
+                    OutputFormat::Table => print_trade(&trade),
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&trade)?),
+                }
+            }
+
+            return Ok(());
+        }
+        BookView::Aggregated => subscribe_aggregated(addr, currency_pair.as_ref()).await?,
+        BookView::Bucketed(bucket_size) => {
+            subscribe_bucketed(addr, currency_pair.as_ref(), bucket_size).await?
+        }
+    };
+
+    while let Some(summary) = stream.message().await? {
+        match format {
+            OutputFormat::Table => print_aggregated_table(&summary),
+            OutputFormat::Json => println!("{}", serde_json::to_string(&summary)?),
+        }
+    }
+
+    Ok(())
+}
+
+/// Connects to the server at `addr` and requests its stream of books.
+pub async fn subscribe(
+    addr: String,
+    currency_pair: Option<&CurrencyPair>,
+) -> Result<Streaming<Summary>> {
+    let mut client = OrderbookAggregatorClient::connect(addr).await?;
+
+    let stream = client
+        .book_summary(pair_request(currency_pair, Empty {}))
+        .await?
+        .into_inner();
+
+    Ok(stream)
+}
+
+/// Connects to the server at `addr` and requests its stream of aggregated books.
+pub async fn subscribe_aggregated(
+    addr: String,
+    currency_pair: Option<&CurrencyPair>,
+) -> Result<Streaming<AggregatedSummary>> {
+    let mut client = OrderbookAggregatorClient::connect(addr).await?;
+
+    let stream = client
+        .aggregated_book_summary(pair_request(currency_pair, Empty {}))
+        .await?
+        .into_inner();
+
+    Ok(stream)
+}
+
+/// Connects to the server at `addr` and requests its stream of books grouped in buckets.
+pub async fn subscribe_bucketed(
+    addr: String,
+    currency_pair: Option<&CurrencyPair>,
+    bucket_size: BucketSize,
+) -> Result<Streaming<AggregatedSummary>> {
+    let mut client = OrderbookAggregatorClient::connect(addr).await?;
+
+    let bucket_size = match bucket_size {
+        BucketSize::Tick(size) => RequestedBucketSize::TickSize(size),
+        BucketSize::BasisPoints(size) => RequestedBucketSize::BasisPoints(size),
+    };
+
+    let message = BucketedBookRequest {
+        bucket_size: Some(bucket_size),
+        depth: 0,
+    };
+
+    let stream = client
+        .bucketed_book_summary(pair_request(currency_pair, message))
+        .await?
+        .into_inner();
+
+    Ok(stream)
+}
+
+/// Connects to the server at `addr` and requests its stream of metrics, with default parameters.
+pub async fn subscribe_metrics(
+    addr: String,
+    currency_pair: Option<&CurrencyPair>,
+) -> Result<Streaming<Metrics>> {
+    let mut client = OrderbookAggregatorClient::connect(addr).await?;
+
+    let stream = client
+        .market_metrics(pair_request(currency_pair, MetricsRequest::default()))
+        .await?
+        .into_inner();
+
+    Ok(stream)
+}
+
+/// Connects to the server at `addr` and requests its stream of index prices, with default
+/// parameters.
+pub async fn subscribe_index(
+    addr: String,
+    currency_pair: Option<&CurrencyPair>,
+) -> Result<Streaming<Index>> {
+    let mut client = OrderbookAggregatorClient::connect(addr).await?;
+
+    let stream = client
+        .index_price(pair_request(currency_pair, IndexRequest::default()))
+        .await?
+        .into_inner();
+
+    Ok(stream)
+}
+
+/// Connects to the server at `addr` and requests its stream of trades.
+pub async fn subscribe_trades(
+    addr: String,
+    currency_pair: Option<&CurrencyPair>,
+) -> Result<Streaming<Trade>> {
+    let mut client = OrderbookAggregatorClient::connect(addr).await?;
+
+    let stream = client
+        .trades(pair_request(currency_pair, Empty {}))
+        .await?
+        .into_inner();
+
+    Ok(stream)
+}
+
+/// A request carrying the expected currency pair, if any.
+fn pair_request<T>(currency_pair: Option<&CurrencyPair>, message: T) -> Request<T> {
+    let mut request = Request::new(message);
+
+    if let Some(currency_pair) = currency_pair {
+        let value = currency_pair.as_str().parse().unwrap();
+        request
+            .metadata_mut()
+            .insert(CURRENCY_PAIR_METADATA_KEY, value);
+    }
+
+    request
+}
+
+/// Prints the book as a ladder, asks on top and bids below.
+fn print_table(summary: &Summary) {
+    println!(
+        "{:<4} {:<10} {:>16} {:>16} {:>7}",
+        "SIDE", "EXCHANGE", "PRICE", "AMOUNT", "ORDERS"
+    );
+
+    for level in summary.asks.iter().rev() {
+        print_level("ask", level);
+    }
+
+    let market = if summary.crossed {
+        "CROSSED"
+    } else if summary.locked {
+        "LOCKED"
+    } else {
+        ""
+    };
+
+    println!(
+        "{:<4} {:<10} {:>16} {:>16}",
+        "", "spread", summary.spread, market
+    );
+
+    for level in &summary.bids {
+        print_level("bid", level);
+    }
+
+    println!();
+}
+
+fn print_level(side: &str, level: &Level) {
+    // Zero means the exchange doesn't tell
+    let order_count = match level.order_count {
+        0 => "-".to_string(),
+        count => count.to_string(),
+    };
+
+    println!(
+        "{:<4} {:<10} {:>16} {:>16} {:>7}",
+        side, level.exchange, level.price, level.amount, order_count
+    );
+}
+
+/// Prints the aggregated book as a ladder, with the amount of each venue.
+fn print_aggregated_table(summary: &AggregatedSummary) {
+    println!("{:<4} {:>16} {:>16}  VENUES", "SIDE", "PRICE", "AMOUNT");
+
+    for level in summary.asks.iter().rev() {
+        print_aggregated_level("ask", level);
+    }
+
+    println!("{:<4} {:>16}", "", summary.spread);
+
+    for level in &summary.bids {
+        print_aggregated_level("bid", level);
+    }
+
+    println!();
+}
+
+fn print_aggregated_level(side: &str, level: &AggregatedLevel) {
+    let venues = level
+        .venues
+        .iter()
+        .map(|venue| format!("{} {}", venue.exchange, venue.amount))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    println!(
+        "{:<4} {:>16} {:>16}  {}",
+        side, level.price, level.amount, venues
+    );
+}
+
+fn print_metrics(metrics: &Metrics) {
+    println!(
+        "mid {}  microprice {}  spread {}  imbalance {:.4}",
+        metrics.mid_price, metrics.microprice, metrics.spread, metrics.imbalance
+    );
+
+    for depth in &metrics.depth {
+        println!(
+            "within {:>6} bp  bids {:>16}  asks {:>16}",
+            depth.basis_points, depth.bid_amount, depth.ask_amount
+        );
+    }
+
+    for quote in &metrics.exchanges {
+        println!(
+            "{:<10} {:>16} x {:<16} {:>16} x {:<16}",
+            quote.exchange, quote.bid_price, quote.bid_amount, quote.ask_price, quote.ask_amount
+        );
+    }
+
+    println!();
+}
+
+fn print_index(index: &Index) {
+    println!("index {}", index.price);
+
+    for constituent in &index.constituents {
+        println!(
+            "{:<10} {:>16} {:>16} {:>7.2}%  {:?}",
+            constituent.exchange,
+            constituent.mid_price,
+            constituent.amount,
+            constituent.weight * 100.0,
+            constituent.status()
+        );
+    }
+
+    println!();
+}
+
+/// Prints the trade on one line, with the time it was traded at.
+fn print_trade(trade: &Trade) {
+    println!(
+        "{:<13} {:<10} {:<4?} {:>16} {:>16}",
+        trade.traded_at,
+        trade.exchange,
+        trade.aggressor_side(),
+        trade.price,
+        trade.amount
+    );
+}
@@ -0,0 +1,367 @@
+//! Settings of the server read from a TOML file, see `keyrocky.example.toml`.
+
+use std::{
+    fs,
+    net::{Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::LevelFilter;
+use serde::Deserialize;
+use tonic::transport::Identity;
+
+use crate::{
+    currencies::CurrencyPair,
+    exchanges::DEFAULT_RECONNECT_DELAY,
+    feeds::STALENESS_TIMEOUT,
+    fees::TakerFee,
+    merged_book::{MAX_SUMMARY_DEPTH, SUMMARY_DEPTH},
+    quotes::EquivalentQuote,
+    Error, Result,
+};
+
+/// Port the server is bound to when no address is given.
+pub const DEFAULT_PORT: u16 = 50051;
+
+/// Longest timeout or delay that can be configured, a day.
+const MAX_SECONDS: f64 = 24.0 * 60.0 * 60.0;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Currency pairs of the served books, the first one is served by default.
+    pub pairs: Vec<String>,
+    pub server: ServerConfig,
+    pub exchanges: ExchangesConfig,
+    pub feeds: FeedsConfig,
+    pub book: BookConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the server is bound to, like "[::1]:50051".
+    pub bind: Option<SocketAddr>,
+    pub tls: Option<TlsConfig>,
+}
+
+/// PEM files of the certificate chain and private key the server is served with.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExchangesConfig {
+    pub binance: ExchangeConfig,
+    pub bitstamp: ExchangeConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExchangeConfig {
+    /// Whether the exchange's books are merged, if it trades the pair.
+    pub enabled: bool,
+    /// Websocket base URL.
+    pub url: Option<String>,
+    /// URL or path of the instruments the exchange trades.
+    pub instruments: Option<String>,
+}
+
+impl Default for ExchangeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            url: None,
+            instruments: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeedsConfig {
+    /// Seconds without a valid book before an exchange is considered stale.
+    pub staleness_timeout_secs: Option<f64>,
+    /// Seconds before connecting again to an exchange after a connection failed.
+    pub reconnect_delay_secs: Option<f64>,
+    /// File caching the pairs the exchanges trade.
+    pub instruments_cache: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BookConfig {
+    /// Levels sent per side to clients.
+    pub depth: Option<usize>,
+    pub level3: bool,
+    /// Taker fees like the `--taker-fee` flag, "EXCHANGE[:PAIR]=BPS".
+    pub taker_fees: Vec<String>,
+    pub net_of_fees: bool,
+    /// Currency the book is composed through, like the `--via` flag.
+    pub via: Option<String>,
+    /// Equivalent quotes like the `--equivalent-quote` flag, "QUOTE[=RATE]".
+    pub equivalent_quotes: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// One of "off", "error", "warn", "info", "debug" or "trace".
+    pub level: Option<String>,
+}
+
+impl Config {
+    /// Reads and validates the config at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let invalid = |err: String| Error::InvalidConfig(format!("{}: {err}", path.display()));
+
+        let contents = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+        let config: Self = toml::from_str(&contents).map_err(|err| invalid(err.to_string()))?;
+        config.validate().map_err(|err| {
+            match err {
+                Error::InvalidConfig(err) => invalid(err),
+                err => err,
+            }
+        })?;
+
+        Ok(config)
+    }
+
+    /// Checks the values that can be checked without connecting to anything.
+    fn validate(&self) -> Result<()> {
+        let mut pairs = vec![];
+        for pair in &self.pairs {
+            let pair: CurrencyPair = pair
+                .parse()
+                .map_err(|err| invalid(format!("pairs: {err}")))?;
+
+            if pairs.contains(&pair) {
+                return Err(invalid(format!("pairs: {} is listed twice", pair.as_str())));
+            }
+            pairs.push(pair);
+        }
+
+        if !self.exchanges.binance.enabled && !self.exchanges.bitstamp.enabled {
+            return Err(invalid("no exchange is enabled in [exchanges]"));
+        }
+
+        if self.book.level3 && !self.exchanges.bitstamp.enabled {
+            return Err(invalid(
+                "book.level3 maintains Bitstamp's book, but exchanges.bitstamp is disabled",
+            ));
+        }
+
+        if let Some(depth) = self.book.depth {
+            if !(1..=MAX_SUMMARY_DEPTH).contains(&depth) {
+                return Err(invalid(format!(
+                    "book.depth must be between 1 and {MAX_SUMMARY_DEPTH}, not {depth}"
+                )));
+            }
+        }
+
+        for fee in &self.book.taker_fees {
+            fee.parse::<TakerFee>()
+                .map_err(|err| invalid(format!("book.taker_fees: {err}")))?;
+        }
+
+        if self.book.via.is_some() && !self.book.equivalent_quotes.is_empty() {
+            return Err(invalid(
+                "book.via and book.equivalent_quotes compose the book differently, set only one",
+            ));
+        }
+
+        for quote in &self.book.equivalent_quotes {
+            quote
+                .parse::<EquivalentQuote>()
+                .map_err(|err| invalid(format!("book.equivalent_quotes: {err}")))?;
+        }
+
+        seconds(
+            "feeds.staleness_timeout_secs",
+            self.feeds.staleness_timeout_secs,
+            false,
+        )?;
+        seconds(
+            "feeds.reconnect_delay_secs",
+            self.feeds.reconnect_delay_secs,
+            true,
+        )?;
+
+        if let Some(level) = &self.logging.level {
+            parse_log_level(level).map_err(|err| invalid(format!("logging.level: {err}")))?;
+        }
+
+        Ok(())
+    }
+
+    /// Address the server is bound to, `port` overriding the configured one.
+    pub fn bind_addr(&self, port: Option<u16>) -> SocketAddr {
+        let mut addr = self
+            .server
+            .bind
+            .unwrap_or_else(|| (Ipv6Addr::LOCALHOST, DEFAULT_PORT).into());
+
+        if let Some(port) = port {
+            addr.set_port(port);
+        }
+
+        addr
+    }
+
+    /// Reads the certificate and key to serve over TLS, if configured.
+    pub fn tls_identity(&self) -> Result<Option<Identity>> {
+        let tls = match &self.server.tls {
+            Some(tls) => tls,
+            None => return Ok(None),
+        };
+
+        let read = |field: &str, path: &Path| {
+            fs::read(path).map_err(|err| {
+                invalid(format!(
+                    "server.tls.{field}: can't read '{}': {err}",
+                    path.display()
+                ))
+            })
+        };
+
+        Ok(Some(Identity::from_pem(
+            read("cert", &tls.cert)?,
+            read("key", &tls.key)?,
+        )))
+    }
+
+    pub fn depth(&self) -> usize {
+        self.book.depth.unwrap_or(SUMMARY_DEPTH)
+    }
+
+    pub fn staleness_timeout(&self) -> Duration {
+        self.feeds
+            .staleness_timeout_secs
+            .map_or(STALENESS_TIMEOUT, Duration::from_secs_f64)
+    }
+
+    pub fn reconnect_delay(&self) -> Duration {
+        self.feeds
+            .reconnect_delay_secs
+            .map_or(DEFAULT_RECONNECT_DELAY, Duration::from_secs_f64)
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        self.logging
+            .level
+            .as_deref()
+            .and_then(|level| parse_log_level(level).ok())
+            .unwrap_or(LevelFilter::Info)
+    }
+}
+
+/// Parses a log level like "info", case insensitive.
+pub fn parse_log_level(level: &str) -> Result<LevelFilter, String> {
+    level
+        .parse()
+        .map_err(|_| format!("'{level}' isn't one of off, error, warn, info, debug or trace"))
+}
+
+/// Checks that the seconds of `field`, if set, are positive, or zero when
+/// `zero_allowed`, and at most `MAX_SECONDS`.
+///
+/// Out of range seconds would make `Duration::from_secs_f64` panic.
+fn seconds(field: &str, secs: Option<f64>, zero_allowed: bool) -> Result<()> {
+    match secs {
+        Some(secs) if !(0.0..=MAX_SECONDS).contains(&secs) || (secs == 0.0 && !zero_allowed) => {
+            let expected = if zero_allowed {
+                "zero or positive"
+            } else {
+                "positive"
+            };
+            Err(invalid(format!(
+                "{field} must be {expected} seconds up to {MAX_SECONDS}, not {secs}"
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::InvalidConfig(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<Config> {
+        let config: Config =
+            toml::from_str(contents).map_err(|err| Error::InvalidConfig(err.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_loading_the_example_config() {
+        let config = Config::load(Path::new("keyrocky.example.toml")).unwrap();
+
+        assert_eq!(config.pairs, ["ETH/BTC", "LTC/BTC"]);
+        assert_eq!(config.bind_addr(None), "[::1]:50051".parse().unwrap());
+        assert_eq!(config.bind_addr(Some(8080)), "[::1]:8080".parse().unwrap());
+        assert_eq!(config.depth(), 10);
+        assert_eq!(config.staleness_timeout(), Duration::from_secs(5));
+        assert_eq!(config.log_level(), LevelFilter::Info);
+        assert!(config.exchanges.binance.enabled);
+    }
+
+    #[test]
+    fn test_missing_values_are_defaults() {
+        let config = parse("[exchanges.binance]\nenabled = false\n").unwrap();
+
+        assert!(!config.exchanges.binance.enabled);
+        assert!(config.exchanges.bitstamp.enabled);
+        assert_eq!(config.depth(), SUMMARY_DEPTH);
+        assert_eq!(config.reconnect_delay(), DEFAULT_RECONNECT_DELAY);
+        assert!(config.tls_identity().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_configs_are_rejected_precisely() {
+        let error = |contents: &str| parse(contents).unwrap_err().to_string();
+
+        assert!(error("[book]\ndepht = 5\n").contains("unknown field `depht`"));
+        assert_eq!(
+            error("[book]\ndepth = 50\n"),
+            "Config error: book.depth must be between 1 and 10, not 50"
+        );
+        assert_eq!(
+            error("[feeds]\nstaleness_timeout_secs = 0\n"),
+            "Config error: feeds.staleness_timeout_secs must be positive seconds up to 86400, \
+             not 0"
+        );
+        assert_eq!(
+            error("[feeds]\nreconnect_delay_secs = 1e20\n"),
+            "Config error: feeds.reconnect_delay_secs must be zero or positive seconds up to \
+             86400, not 100000000000000000000"
+        );
+        assert_eq!(
+            error("[exchanges.binance]\nenabled = false\n[exchanges.bitstamp]\nenabled = false\n"),
+            "Config error: no exchange is enabled in [exchanges]"
+        );
+        assert_eq!(
+            error("[book]\nvia = \"USDT\"\nequivalent_quotes = [\"USDC\"]\n"),
+            "Config error: book.via and book.equivalent_quotes compose the book differently, set \
+             only one"
+        );
+        assert_eq!(
+            error("pairs = [\"ETHBTC\", \"eth/btc\"]\n"),
+            "Config error: pairs: ETHBTC is listed twice"
+        );
+        assert_eq!(
+            error("[logging]\nlevel = \"loud\"\n"),
+            "Config error: logging.level: 'loud' isn't one of off, error, warn, info, debug or trace"
+        );
+    }
+}
@@ -1,36 +1,124 @@
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
 
 use crate::Error;
 
-/// A curency pair like "ETHBTC".
-pub struct CurrencyPair(String);
+/// A currency pair like ETH/BTC, the base currency priced in the quote currency.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CurrencyPair {
+    base: String,
+    quote: String,
+    /// Base and quote without a separator, like "ETHBTC".
+    symbol: String,
+}
 
 impl CurrencyPair {
-    /// Extracts the inner string slice.
+    /// Pair of `base` priced in `quote`, like "ETH" and "USDT".
+    pub fn new(base: &str, quote: &str) -> Self {
+        let (base, quote) = (base.to_ascii_uppercase(), quote.to_ascii_uppercase());
+        let symbol = format!("{base}{quote}");
+
+        Self {
+            base,
+            quote,
+            symbol,
+        }
+    }
+
+    /// The symbol of the pair, like "ETHBTC".
     pub fn as_str(&self) -> &str {
-        &self.0
+        &self.symbol
+    }
+
+    /// The currency being traded.
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    /// The currency prices are in.
+    pub fn quote(&self) -> &str {
+        &self.quote
+    }
+
+    /// The quote priced in the base, BTC/ETH for ETH/BTC.
+    pub fn inverse(&self) -> Self {
+        Self::new(&self.quote, &self.base)
+    }
+}
+
+impl fmt::Display for CurrencyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.base, self.quote)
     }
 }
 
 impl FromStr for CurrencyPair {
     type Err = Error;
 
+    /// Parses "ETH/BTC", "eth-btc", "ETH_BTC" or "ETHBTC".
+    ///
+    /// Without a separator, the pair is split at the longest currency pairs
+    /// are quoted in, at its end or, for inverse pairs like "BTCETH", at its
+    /// start. Other pairs of 6 letters are split in half.
     fn from_str(text: &str) -> Result<Self, Self::Err> {
-        let is_ascii_string = text.is_ascii();
-        let has_expected_size = text.len() == 6;
-        let is_alphabetic_text = text.chars().all(char::is_alphabetic);
+        let bad_format = || Error::CurrencyPairBadFormat(text.to_owned());
+        let is_currency = |currency: &str| {
+            !currency.is_empty() && currency.chars().all(|char| char.is_ascii_alphanumeric())
+        };
+
+        let upper = text.to_ascii_uppercase();
 
-        let is_valid = is_ascii_string && has_expected_size && is_alphabetic_text;
+        let (base, quote) = match upper.split_once(['/', '-', '_']) {
+            Some(split) => split,
+            None => split_symbol(&upper).ok_or_else(bad_format)?,
+        };
 
-        if !is_valid {
-            return Err(Error::CurrencyPairBadFormat(text.to_owned()));
+        if !is_currency(base) || !is_currency(quote) {
+            return Err(bad_format());
         }
 
-        Ok(CurrencyPair(text.to_owned()))
+        Ok(Self::new(base, quote))
     }
 }
 
-/// All currency pairs supported by Binance and Bitstamp.
+/// Splits a symbol without separator into its base and quote.
+fn split_symbol(symbol: &str) -> Option<(&str, &str)> {
+    // The longest quote wins, like BUSD over USD in "ETHBUSD"
+    let quoted_in = QUOTE_CURRENCIES
+        .iter()
+        .filter_map(|quote| {
+            symbol
+                .strip_suffix(quote)
+                .filter(|base| !base.is_empty())
+                .map(|base| (base, *quote))
+        })
+        .max_by_key(|(_, quote)| quote.len());
+
+    let inversely_quoted_in = || {
+        QUOTE_CURRENCIES
+            .iter()
+            .filter_map(|base| {
+                symbol
+                    .strip_prefix(base)
+                    .filter(|quote| !quote.is_empty())
+                    .map(|quote| (*base, quote))
+            })
+            .max_by_key(|(base, _)| base.len())
+    };
+
+    let halves =
+        || Some(symbol.split_at(3)).filter(|_| symbol.len() == 6 && symbol.is_char_boundary(3));
+
+    quoted_in.or_else(inversely_quoted_in).or_else(halves)
+}
+
+/// Currencies the pairs of Binance and Bitstamp are quoted in.
+const QUOTE_CURRENCIES: [&str; 14] = [
+    "BNB", "BTC", "BUSD", "DAI", "ETH", "EUR", "GBP", "PAX", "TRY", "TUSD", "USD", "USDC", "USDP",
+    "USDT",
+];
+
+/// Currency pairs supported by Binance and Bitstamp, used when the pairs they
+/// trade can't be fetched, see `instruments::load_catalog`.
 pub const SUPPORTED_CURRENCY_PAIRS: [&str; 49] = [
     "AAVEBTC", "ADABTC", "ADAEUR", "ALGOBTC", "APEEUR", "AUDIOBTC", "AVAXEUR", "BCHBTC", "BCHEUR",
     "BTCEUR", "BTCGBP", "BTCPAX", "BTCUSDC", "BTCUSDT", "CHZEUR", "DOTEUR", "ENJEUR", "ETHBTC",
@@ -39,3 +127,44 @@ pub const SUPPORTED_CURRENCY_PAIRS: [&str; 49] = [
     "SOLEUR", "SXPEUR", "UNIBTC", "UNIEUR", "USDCUSDT", "WBTCBTC", "XLMBTC", "XLMEUR", "XRPBTC",
     "XRPEUR", "XRPGBP", "XRPUSDT", "YFIEUR",
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> (String, String) {
+        let pair: CurrencyPair = text.parse().unwrap();
+        (pair.base().to_owned(), pair.quote().to_owned())
+    }
+
+    #[test]
+    fn test_parsing_currency_pairs() {
+        let pair = |base: &str, quote: &str| (base.to_owned(), quote.to_owned());
+
+        assert_eq!(parse("ETHBTC"), pair("ETH", "BTC"));
+        assert_eq!(parse("eth/btc"), pair("ETH", "BTC"));
+        assert_eq!(parse("Eth-Btc"), pair("ETH", "BTC"));
+        assert_eq!(parse("AAVEBTC"), pair("AAVE", "BTC"));
+        assert_eq!(parse("USDCUSDT"), pair("USDC", "USDT"));
+        assert_eq!(parse("BTCETH"), pair("BTC", "ETH"));
+        assert_eq!(parse("ETHUSD"), pair("ETH", "USD"));
+        assert_eq!(parse("LINK/USD"), pair("LINK", "USD"));
+        assert_eq!(parse("LINKUSD"), pair("LINK", "USD"));
+        assert_eq!(parse("LINKETH"), pair("LINK", "ETH"));
+        assert_eq!(parse("DOGEBUSD"), pair("DOGE", "BUSD"));
+        assert_eq!(parse("BTCUSDT"), pair("BTC", "USDT"));
+
+        assert!("LINKXYZ".parse::<CurrencyPair>().is_err());
+        assert!("ETH/".parse::<CurrencyPair>().is_err());
+        assert!("ETH/B C".parse::<CurrencyPair>().is_err());
+    }
+
+    #[test]
+    fn test_every_supported_pair_parses() {
+        for symbol in SUPPORTED_CURRENCY_PAIRS {
+            let pair: CurrencyPair = symbol.parse().unwrap();
+            assert_eq!(pair.as_str(), symbol);
+            assert!(!SUPPORTED_CURRENCY_PAIRS.contains(&pair.inverse().as_str()));
+        }
+    }
+}
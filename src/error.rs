@@ -4,10 +4,47 @@ pub type Result<T, E = self::Error> = std::result::Result<T, E>;
 pub enum Error {
     #[error("Currency error: currency pair '{0}' is invalid")]
     CurrencyPairBadFormat(String),
-    #[error("{0} stream Error: stream was expected to send at least 10 {1}")]
+    #[error("Currency error: currency pair '{0}' isn't traded by any exchange")]
+    UnsupportedCurrencyPair(String),
+    #[error("Replay error: speed '{0}' is invalid, expected a positive multiplier or 'max'")]
+    ReplaySpeedBadFormat(String),
+    #[error("Bucket error: size '{0}' is invalid, expected a positive tick size or basis points like '1bp'")]
+    BucketSizeBadFormat(String),
+    #[error("Fee error: taker fee '{0}' is invalid, expected EXCHANGE=BPS or EXCHANGE:PAIR=BPS")]
+    TakerFeeBadFormat(String),
+    #[error(
+        "Quote error: equivalent quote '{0}' is invalid, expected QUOTE, QUOTE=RATE or QUOTE=live"
+    )]
+    EquivalentQuoteBadFormat(String),
+    #[error("Config error: {0}")]
+    InvalidConfig(String),
+    #[error("Replay error: recorded message from unknown exchange '{0}'")]
+    UnknownExchange(String),
+    #[error("{0} subscription error: {1}")]
+    SubscriptionFailed(String, String),
+    #[error("{0} subscription error: rejected, {1}")]
+    SubscriptionRejected(String, String),
+    #[error("{0} stream error: unexpected {1}")]
+    UnexpectedMessage(String, String),
+    #[error(
+        "{0} stream error: books were expected to have at least {} {1}",
+        crate::exchanges::EXCHANGE_DEPTH
+    )]
     NotEnoughOrders(String, String),
     #[error("WebSocket error: {0}")]
     TungsteniteError(#[from] tungstenite::error::Error),
     #[error("Failed to parse float: {0}")]
     ParseFloatError(#[from] std::num::ParseFloatError),
+    #[error("Transport error: {0}")]
+    TransportError(#[from] tonic::transport::Error),
+    #[error("gRPC error: {0}")]
+    GrpcError(#[from] tonic::Status),
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("HTTP error: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Reflection error: {0}")]
+    ReflectionError(#[from] tonic_reflection::server::Error),
 }
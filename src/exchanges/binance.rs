@@ -1,54 +1,121 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
+use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     currencies::CurrencyPair,
-    exchanges::ConnectToOrderBook,
-    order_book::{Level, Summary},
-    Error, Result,
+    exchanges::{self, ConnectToOrderBook, SubscriptionReply, EXCHANGE_DEPTH},
+    instruments::Instrument,
+    order_book::{Level, Summary, Trade, TradeSide},
+    recorder, Error, Result,
 };
 
 const BINANCE_WEBSOCKET_BASE_URL: &str = "wss://stream.binance.com:9443/ws";
+const BINANCE_INSTRUMENTS_URL: &str = "https://api.binance.com/api/v3/exchangeInfo";
 const EXCHANGE_NAME: &str = "Binance";
+/// Identifier of the subscription request, echoed back in its reply.
+const SUBSCRIBE_REQUEST_ID: usize = 1;
 
 pub struct BinanceExchange;
 
 impl ConnectToOrderBook for BinanceExchange {
     type SubscribeMessage = BinanceSubscribeMessage;
 
-    fn connect_url(currency_pair: &CurrencyPair) -> String {
-        let suffix = currency_pair.as_str();
-        format!("{BINANCE_WEBSOCKET_BASE_URL}/{suffix}")
+    const EXCHANGE_NAME: &'static str = EXCHANGE_NAME;
+    const DEFAULT_BASE_URL: &'static str = BINANCE_WEBSOCKET_BASE_URL;
+
+    /// Streams are named after the lowercase symbol, like "ethbtc".
+    fn symbol(currency_pair: &CurrencyPair) -> String {
+        format!("{}{}", currency_pair.base(), currency_pair.quote()).to_lowercase()
+    }
+
+    fn connect_url(base_url: &str, currency_pair: &CurrencyPair) -> String {
+        let suffix = Self::symbol(currency_pair);
+        format!("{base_url}/{suffix}")
     }
 
     fn subscribe_message(currency_pair: &CurrencyPair) -> Self::SubscribeMessage {
         BinanceSubscribeMessage::new(currency_pair)
     }
+
+    fn classify_subscription_reply(message: &str) -> SubscriptionReply {
+        // Book updates have no `id`, so they fail to parse as a reply
+        match serde_json::from_str(message) {
+            Ok(BinanceSubscribeReply {
+                id: SUBSCRIBE_REQUEST_ID,
+                error: None,
+            }) => SubscriptionReply::Acknowledged,
+            Ok(BinanceSubscribeReply {
+                id: SUBSCRIBE_REQUEST_ID,
+                error: Some(BinanceReplyError { code, msg }),
+            }) => SubscriptionReply::Rejected(format!("{msg} (code {code})")),
+            _ => SubscriptionReply::Unrelated,
+        }
+    }
 }
 
 impl BinanceExchange {
+    /// Where the pairs Binance trades are listed.
+    pub const INSTRUMENTS_URL: &'static str = BINANCE_INSTRUMENTS_URL;
+
+    /// Parses the symbols being traded from Binance's `exchangeInfo`.
+    ///
+    /// Increments come from the price and lot size filters of each symbol.
+    pub fn parse_instruments(message: &str) -> Result<Vec<Instrument>> {
+        let BinanceExchangeInfo { symbols } = serde_json::from_str(message)?;
+
+        symbols
+            .into_iter()
+            .filter(|symbol| symbol.status == "TRADING")
+            .map(|symbol| {
+                let increment = |filter_type: &str| -> Result<Option<f64>> {
+                    let increment = symbol
+                        .filters
+                        .iter()
+                        .filter(|filter| filter.filter_type == filter_type)
+                        .find_map(|filter| filter.tick_size.as_ref().or(filter.step_size.as_ref()));
+
+                    Ok(increment.map(|increment| increment.parse()).transpose()?)
+                };
+
+                Ok(Instrument {
+                    tick_size: increment("PRICE_FILTER")?,
+                    lot_size: increment("LOT_SIZE")?,
+                    base: symbol.base_asset,
+                    quote: symbol.quote_asset,
+                })
+            })
+            .collect()
+    }
+
     pub fn try_parse_summary(message: String) -> Result<Summary> {
-        let BinanceRawLevelBook { mut bids, mut asks } = serde_json::from_str(&message).unwrap();
+        let BinanceRawLevelBook { mut bids, mut asks } = serde_json::from_str(&message)?;
 
-        if asks.len() < 10 {
+        if bids.len() < EXCHANGE_DEPTH {
             return Err(Error::NotEnoughOrders(EXCHANGE_NAME.into(), "bids".into()));
         }
 
-        if asks.len() < 10 {
+        if asks.len() < EXCHANGE_DEPTH {
             return Err(Error::NotEnoughOrders(EXCHANGE_NAME.into(), "asks".into()));
         }
 
-        asks.resize_with(10, || unreachable!());
-        bids.resize_with(10, || unreachable!());
+        asks.truncate(EXCHANGE_DEPTH);
+        bids.truncate(EXCHANGE_DEPTH);
 
         let array_into_level = |array: RawLevel| -> Result<Level, <f64 as FromStr>::Err> {
             let [price, amount] = array;
 
+            let price = price.parse()?;
+
             Ok(Level {
-                price: price.parse()?,
+                price,
                 amount: amount.parse()?,
                 exchange: EXCHANGE_NAME.to_string(),
+                order_count: 0,
+                orders: vec![],
+                effective_price: price,
+                legs: vec![],
             })
         };
 
@@ -64,6 +131,89 @@ impl BinanceExchange {
 
         Ok(Summary::new(bids, asks))
     }
+
+    /// Connects to the order book, replacing the connection whenever Binance
+    /// drops it, like it does every day, see `exchanges::connect_with_reconnects`.
+    pub async fn connect_with_reconnects(
+        base_url: &str,
+        currency_pair: &CurrencyPair,
+        reconnect_delay: Duration,
+    ) -> Result<impl Stream<Item = Result<String>>> {
+        exchanges::connect_with_reconnects::<Self>(base_url, currency_pair, reconnect_delay).await
+    }
+
+    /// Streams the public trades of `currency_pair`, from its `@trade` stream.
+    ///
+    /// The connection is replaced like the book's, see `exchanges::connect_with_reconnects`.
+    pub async fn trades(
+        base_url: &str,
+        currency_pair: &CurrencyPair,
+        reconnect_delay: Duration,
+    ) -> Result<impl Stream<Item = Result<Trade>>> {
+        let messages = exchanges::connect_with_reconnects::<BinanceTrades>(
+            base_url,
+            currency_pair,
+            reconnect_delay,
+        )
+        .await?;
+
+        Ok(messages
+            .map(|message| Self::try_parse_trade(&message?, recorder::unix_timestamp_millis())))
+    }
+
+    /// Parses a trade event, received at `received_at` milliseconds since the Unix epoch.
+    pub fn try_parse_trade(message: &str, received_at: u64) -> Result<Trade> {
+        let BinanceRawTrade {
+            id,
+            price,
+            amount,
+            trade_time,
+            buyer_is_maker,
+        } = serde_json::from_str(message)?;
+
+        // The maker's order was resting, the taker is on the other side
+        let aggressor_side = if buyer_is_maker {
+            TradeSide::Sell
+        } else {
+            TradeSide::Buy
+        };
+
+        Ok(Trade {
+            exchange: EXCHANGE_NAME.into(),
+            id: id.to_string(),
+            price: price.parse()?,
+            amount: amount.parse()?,
+            aggressor_side: aggressor_side as i32,
+            traded_at: trade_time,
+            received_at,
+        })
+    }
+}
+
+/// Binance's `@trade` stream, streaming every trade.
+pub struct BinanceTrades;
+
+impl ConnectToOrderBook for BinanceTrades {
+    type SubscribeMessage = BinanceSubscribeMessage;
+
+    const EXCHANGE_NAME: &'static str = EXCHANGE_NAME;
+    const DEFAULT_BASE_URL: &'static str = BINANCE_WEBSOCKET_BASE_URL;
+
+    fn symbol(currency_pair: &CurrencyPair) -> String {
+        BinanceExchange::symbol(currency_pair)
+    }
+
+    fn connect_url(base_url: &str, currency_pair: &CurrencyPair) -> String {
+        BinanceExchange::connect_url(base_url, currency_pair)
+    }
+
+    fn subscribe_message(currency_pair: &CurrencyPair) -> Self::SubscribeMessage {
+        BinanceSubscribeMessage::trades(currency_pair)
+    }
+
+    fn classify_subscription_reply(message: &str) -> SubscriptionReply {
+        BinanceExchange::classify_subscription_reply(message)
+    }
 }
 
 type RawLevel = [String; 2];
@@ -74,6 +224,55 @@ struct BinanceRawLevelBook {
     asks: Vec<RawLevel>,
 }
 
+#[derive(Deserialize)]
+struct BinanceRawTrade {
+    #[serde(rename = "t")]
+    id: u64,
+    #[serde(rename = "p")]
+    price: String,
+    #[serde(rename = "q")]
+    amount: String,
+    /// Milliseconds since the Unix epoch.
+    #[serde(rename = "T")]
+    trade_time: u64,
+    #[serde(rename = "m")]
+    buyer_is_maker: bool,
+}
+
+#[derive(Deserialize)]
+struct BinanceExchangeInfo {
+    symbols: Vec<BinanceSymbol>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BinanceSymbol {
+    status: String,
+    base_asset: String,
+    quote_asset: String,
+    filters: Vec<BinanceSymbolFilter>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BinanceSymbolFilter {
+    filter_type: String,
+    tick_size: Option<String>,
+    step_size: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BinanceSubscribeReply {
+    id: usize,
+    error: Option<BinanceReplyError>,
+}
+
+#[derive(Deserialize)]
+struct BinanceReplyError {
+    code: i64,
+    msg: String,
+}
+
 #[derive(Serialize)]
 pub struct BinanceSubscribeMessage {
     method: String,
@@ -83,11 +282,21 @@ pub struct BinanceSubscribeMessage {
 
 impl BinanceSubscribeMessage {
     pub fn new(currency_pair: &CurrencyPair) -> Self {
-        let symbol = currency_pair.as_str().to_lowercase();
+        let symbol = BinanceExchange::symbol(currency_pair);
         Self {
             method: "SUBSCRIBE".into(),
-            params: vec![format!("{symbol}@depth10@100ms")],
-            id: 1,
+            // Binance sends books of 5, 10 or 20 levels
+            params: vec![format!("{symbol}@depth{EXCHANGE_DEPTH}@100ms")],
+            id: SUBSCRIBE_REQUEST_ID,
+        }
+    }
+
+    pub fn trades(currency_pair: &CurrencyPair) -> Self {
+        let symbol = BinanceExchange::symbol(currency_pair);
+        Self {
+            method: "SUBSCRIBE".into(),
+            params: vec![format!("{symbol}@trade")],
+            id: SUBSCRIBE_REQUEST_ID,
         }
     }
 }
@@ -112,6 +321,10 @@ mod tests {
                         price,
                         amount,
                         exchange: "Binance".to_string(),
+                        order_count: 0,
+                        orders: vec![],
+                        effective_price: price,
+                        legs: vec![],
                     }
                 })
                 .collect::<Vec<Level>>()
@@ -152,6 +365,18 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_binance_rejecting_thin_books() {
+        let raw_json = include_str!("../../test_data/binance_order_book_update_message.json");
+        let mut book: serde_json::Value = serde_json::from_str(raw_json).unwrap();
+        book["bids"].as_array_mut().unwrap().truncate(3);
+
+        assert!(matches!(
+            BinanceExchange::try_parse_summary(book.to_string()),
+            Err(Error::NotEnoughOrders(_, side)) if side == "bids"
+        ));
+    }
+
     #[test]
     fn test_binance_serializing_subscribe_message() {
         let currency_pair: CurrencyPair = "ETHBTC".parse().unwrap();
@@ -169,4 +394,45 @@ mod tests {
 
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_binance_classifying_subscription_replies() {
+        let classify = BinanceExchange::classify_subscription_reply;
+
+        assert_eq!(
+            classify(r#"{"result":null,"id":1}"#),
+            SubscriptionReply::Acknowledged
+        );
+        assert_eq!(
+            classify(r#"{"error":{"code":2,"msg":"Invalid request"},"id":1}"#),
+            SubscriptionReply::Rejected("Invalid request (code 2)".into())
+        );
+        assert_eq!(
+            classify(include_str!(
+                "../../test_data/binance_order_book_update_message.json"
+            )),
+            SubscriptionReply::Unrelated
+        );
+    }
+
+    #[test]
+    fn test_binance_deserializing_trade() {
+        let raw_json = include_str!("../../test_data/binance_trade_message.json");
+
+        let trade = BinanceExchange::try_parse_trade(raw_json, 1665754020050).unwrap();
+
+        assert_eq!(
+            trade,
+            Trade {
+                exchange: "Binance".into(),
+                id: "389764512".into(),
+                price: 0.06812,
+                amount: 0.25,
+                // The buyer was the maker, so a seller took its order
+                aggressor_side: TradeSide::Sell as i32,
+                traded_at: 1665754020011,
+                received_at: 1665754020050,
+            }
+        );
+    }
 }
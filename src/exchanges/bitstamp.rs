@@ -1,15 +1,21 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
+use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     currencies::CurrencyPair,
-    exchanges::ConnectToOrderBook,
-    order_book::{Level, Summary},
-    Error, Result,
+    exchanges::{
+        self, ConnectToOrderBook, MessageKind, MessageStream, SubscriptionReply, EXCHANGE_DEPTH,
+    },
+    instruments::Instrument,
+    level3::{Level3Book, RestingOrder, Side},
+    order_book::{Level, Summary, Trade, TradeSide},
+    recorder, Error, Result,
 };
 
 const BITSTAMP_WEBSOCKET_URL: &str = "wss://ws.bitstamp.net";
+const BITSTAMP_INSTRUMENTS_URL: &str = "https://www.bitstamp.net/api/v2/trading-pairs-info/";
 const EXCHANGE_NAME: &str = "Bitstamp";
 
 pub struct BitstampExchange;
@@ -17,39 +23,239 @@ pub struct BitstampExchange;
 impl ConnectToOrderBook for BitstampExchange {
     type SubscribeMessage = BitstampSubscribeMessage;
 
-    fn connect_url(_currency_pair: &CurrencyPair) -> String {
-        BITSTAMP_WEBSOCKET_URL.into()
+    const EXCHANGE_NAME: &'static str = EXCHANGE_NAME;
+    const DEFAULT_BASE_URL: &'static str = BITSTAMP_WEBSOCKET_URL;
+
+    /// Channels are named after the lowercase symbol, like "ethbtc".
+    fn symbol(currency_pair: &CurrencyPair) -> String {
+        format!("{}{}", currency_pair.base(), currency_pair.quote()).to_lowercase()
+    }
+
+    fn connect_url(base_url: &str, _currency_pair: &CurrencyPair) -> String {
+        base_url.into()
     }
 
     fn subscribe_message(currency_pair: &CurrencyPair) -> Self::SubscribeMessage {
         BitstampSubscribeMessage::new(currency_pair)
     }
+
+    fn classify_subscription_reply(message: &str) -> SubscriptionReply {
+        let event: BitstampEvent = match serde_json::from_str(message) {
+            Ok(event) => event,
+            Err(_) => return SubscriptionReply::Unrelated,
+        };
+
+        match event.event.as_str() {
+            "bts:subscription_succeeded" => SubscriptionReply::Acknowledged,
+            "bts:error" => {
+                let reason = event
+                    .data
+                    .get("message")
+                    .and_then(|message| message.as_str())
+                    .unwrap_or("unknown error");
+                SubscriptionReply::Rejected(reason.into())
+            }
+            _ => SubscriptionReply::Unrelated,
+        }
+    }
+
+    fn message_kind(message: &str) -> MessageKind {
+        message_kind(message)
+    }
 }
 
 impl BitstampExchange {
+    /// Connects to the order book, replacing the connection whenever Bitstamp drops it.
+    ///
+    /// On `bts:request_reconnect`, a replacement connection is subscribed while
+    /// the current one keeps delivering books. Only book messages are yielded,
+    /// other events are swallowed, see `exchanges::connect_with_reconnects`.
+    pub async fn connect_with_reconnects(
+        base_url: &str,
+        currency_pair: &CurrencyPair,
+        reconnect_delay: Duration,
+    ) -> Result<impl Stream<Item = Result<String>>> {
+        exchanges::connect_with_reconnects::<Self>(base_url, currency_pair, reconnect_delay).await
+    }
+
+    /// Streams the public trades of `currency_pair`, from its `live_trades` channel.
+    ///
+    /// The connection is replaced whenever Bitstamp drops it, like the order
+    /// book's in `connect_with_reconnects`.
+    pub async fn trades(
+        base_url: &str,
+        currency_pair: &CurrencyPair,
+        reconnect_delay: Duration,
+    ) -> Result<impl Stream<Item = Result<Trade>>> {
+        let messages = exchanges::connect_with_reconnects::<BitstampLiveTrades>(
+            base_url,
+            currency_pair,
+            reconnect_delay,
+        )
+        .await?;
+
+        Ok(messages
+            .map(|message| Self::try_parse_trade(&message?, recorder::unix_timestamp_millis())))
+    }
+
+    /// Streams summaries of a level-3 book, maintained order by order.
+    ///
+    /// The `live_orders` channel only sends order events, so the book is
+    /// seeded with a `detail_order_book` snapshot taken after subscribing to
+    /// the events, which are then applied if newer than the snapshot. Orders
+    /// outside the snapshot depth are only known once they change. The book
+    /// is synchronized again whenever the events connection is lost, retrying
+    /// after `reconnect_delay`. Connection errors and unexpected events are
+    /// logged, the stream only ends with an error once Bitstamp rejects the
+    /// subscription. Books too thin to fill a summary, like right after a
+    /// snapshot or while orders drain, aren't sent, so the last full one
+    /// stays merged.
+    pub async fn level3_summaries(
+        base_url: &str,
+        currency_pair: &CurrencyPair,
+        reconnect_delay: Duration,
+    ) -> Result<impl Stream<Item = Result<Summary>>> {
+        let base_url = base_url.to_owned();
+        let currency_pair = currency_pair.clone();
+
+        let synchronized = synchronize_level3_book(&base_url, &currency_pair).await?;
+
+        Ok(async_stream::stream! {
+            let (mut book, mut snapshot_time, mut events) = synchronized;
+            if let Ok(summary) = book.summary(EXCHANGE_NAME) {
+                yield Ok(summary);
+            }
+
+            loop {
+                while let Some(message) = events.next().await {
+                    let message = match message {
+                        Ok(message) => message,
+                        Err(err) => {
+                            log::warn!("{EXCHANGE_NAME} connection error: {err}.");
+                            continue;
+                        }
+                    };
+
+                    match message_kind(&message) {
+                        MessageKind::Data => {}
+                        MessageKind::RequestReconnect => break,
+                        MessageKind::Control => continue,
+                    }
+
+                    match apply_live_order_event(&mut book, snapshot_time, &message) {
+                        Ok(true) => {
+                            if let Ok(summary) = book.summary(EXCHANGE_NAME) {
+                                yield Ok(summary);
+                            }
+                        }
+                        Ok(false) => {}
+                        Err(err) => log::warn!("{EXCHANGE_NAME} order event skipped: {err}."),
+                    }
+                }
+
+                // Synchronize again, the events missed in between are lost
+                loop {
+                    match synchronize_level3_book(&base_url, &currency_pair).await {
+                        Ok(synchronized) => {
+                            (book, snapshot_time, events) = synchronized;
+                            if let Ok(summary) = book.summary(EXCHANGE_NAME) {
+                                yield Ok(summary);
+                            }
+                            break;
+                        }
+                        Err(err @ Error::SubscriptionRejected(..)) => {
+                            yield Err(err);
+                            return;
+                        }
+                        Err(err) => {
+                            log::warn!("{EXCHANGE_NAME} failed to synchronize the book, retrying: {err}.");
+                            tokio::time::sleep(reconnect_delay).await;
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Where the pairs Bitstamp trades are listed.
+    pub const INSTRUMENTS_URL: &'static str = BITSTAMP_INSTRUMENTS_URL;
+
+    /// Parses the enabled pairs of Bitstamp's `trading-pairs-info`.
+    ///
+    /// Increments are derived from the decimals of each currency.
+    pub fn parse_instruments(message: &str) -> Result<Vec<Instrument>> {
+        let pairs: Vec<BitstampPairInfo> = serde_json::from_str(message)?;
+
+        pairs
+            .into_iter()
+            .filter(|pair| pair.trading == "Enabled")
+            .map(|pair| {
+                let currency_pair: CurrencyPair = pair.name.parse()?;
+                let increment = |decimals: u32| format!("1e-{decimals}").parse::<f64>();
+
+                Ok(Instrument {
+                    base: currency_pair.base().to_owned(),
+                    quote: currency_pair.quote().to_owned(),
+                    tick_size: Some(increment(pair.counter_decimals)?),
+                    lot_size: Some(increment(pair.base_decimals)?),
+                })
+            })
+            .collect()
+    }
+
+    /// Parses a trade event, received at `received_at` milliseconds since the Unix epoch.
+    pub fn try_parse_trade(message: &str, received_at: u64) -> Result<Trade> {
+        let BitstampLiveTradeEvent { data } = serde_json::from_str(message)?;
+
+        let aggressor_side = if data.trade_type == 0 {
+            TradeSide::Buy
+        } else {
+            TradeSide::Sell
+        };
+
+        Ok(Trade {
+            exchange: EXCHANGE_NAME.into(),
+            id: data.id.to_string(),
+            price: data.price_str.parse()?,
+            amount: data.amount_str.parse()?,
+            aggressor_side: aggressor_side as i32,
+            traded_at: parse_microtimestamp(&data.microtimestamp)? / 1000,
+            received_at,
+        })
+    }
+
     pub fn try_parse_summary(message: String) -> Result<Summary> {
         let BitstampRawSummary {
-            data: BitstampSummaryData { mut bids, mut asks },
-        } = serde_json::from_str(&message).unwrap();
+            data: BitstampSummaryData {
+                mut bids, mut asks, ..
+            },
+        } = serde_json::from_str(&message)?;
 
-        if asks.len() < 10 {
+        if bids.len() < EXCHANGE_DEPTH {
             return Err(Error::NotEnoughOrders(EXCHANGE_NAME.into(), "bids".into()));
         }
 
-        if asks.len() < 10 {
+        if asks.len() < EXCHANGE_DEPTH {
             return Err(Error::NotEnoughOrders(EXCHANGE_NAME.into(), "asks".into()));
         }
 
-        asks.resize_with(10, || unreachable!());
-        bids.resize_with(10, || unreachable!());
+        asks.truncate(EXCHANGE_DEPTH);
+        bids.truncate(EXCHANGE_DEPTH);
 
         let array_into_level = |array: RawLevel| -> Result<Level, <f64 as FromStr>::Err> {
             let [price, amount, _identifier] = array;
 
+            let price = price.parse()?;
+
+            // Levels of the detail book are individual orders
             Ok(Level {
-                price: price.parse()?,
+                price,
                 amount: amount.parse()?,
                 exchange: EXCHANGE_NAME.to_string(),
+                order_count: 1,
+                orders: vec![],
+                effective_price: price,
+                legs: vec![],
             })
         };
 
@@ -67,8 +273,180 @@ impl BitstampExchange {
     }
 }
 
+/// Subscribes to order events, then seeds a book with a snapshot.
+///
+/// Returns the book, the snapshot time in microseconds, and the pending events.
+async fn synchronize_level3_book(
+    base_url: &str,
+    currency_pair: &CurrencyPair,
+) -> Result<(Level3Book, u64, MessageStream)> {
+    // Events arriving meanwhile wait in the socket until the snapshot is applied
+    let events = BitstampLiveOrders::connect_to_order_book(base_url, currency_pair).await?;
+
+    let snapshot = BitstampExchange::connect_to_order_book(base_url, currency_pair).await?;
+    let mut snapshot = Box::pin(snapshot.into_messages());
+
+    let message = loop {
+        match snapshot.next().await {
+            Some(Ok(message)) if message_kind(&message) == MessageKind::Data => break message,
+            Some(Ok(_)) => continue,
+            Some(Err(err)) => return Err(err),
+            None => {
+                return Err(Error::SubscriptionFailed(
+                    EXCHANGE_NAME.into(),
+                    "connection closed before the book snapshot".into(),
+                ))
+            }
+        }
+    };
+
+    let BitstampRawSummary {
+        data:
+            BitstampSummaryData {
+                bids,
+                asks,
+                microtimestamp,
+            },
+    } = serde_json::from_str(&message)?;
+
+    let mut book = Level3Book::default();
+    for (side, levels) in [(Side::Bid, bids), (Side::Ask, asks)] {
+        for [price, amount, id] in levels {
+            book.upsert(RestingOrder {
+                id: parse_order_id(&id)?,
+                side,
+                price: price.parse()?,
+                amount: amount.parse()?,
+            });
+        }
+    }
+
+    let snapshot_time = parse_microtimestamp(&microtimestamp)?;
+
+    Ok((book, snapshot_time, Box::pin(events.into_messages())))
+}
+
+/// Applies an order event to `book`, unless it's older than the snapshot.
+///
+/// Returns whether the book changed.
+fn apply_live_order_event(
+    book: &mut Level3Book,
+    snapshot_time: u64,
+    message: &str,
+) -> Result<bool> {
+    let BitstampLiveOrderEvent { event, data } = serde_json::from_str(message)?;
+
+    if parse_microtimestamp(&data.microtimestamp)? <= snapshot_time {
+        return Ok(false);
+    }
+
+    let id = data.id;
+    match event.as_str() {
+        "order_created" | "order_changed" => {
+            book.upsert(RestingOrder {
+                id,
+                side: if data.order_type == 0 {
+                    Side::Bid
+                } else {
+                    Side::Ask
+                },
+                price: data.price_str.parse()?,
+                amount: data.amount_str.parse()?,
+            });
+        }
+        "order_deleted" => book.remove(id),
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+fn parse_order_id(id: &str) -> Result<u64> {
+    id.parse()
+        .map_err(|_| Error::UnexpectedMessage(EXCHANGE_NAME.into(), format!("order ID '{id}'")))
+}
+
+fn parse_microtimestamp(microtimestamp: &str) -> Result<u64> {
+    microtimestamp.parse().map_err(|_| {
+        Error::UnexpectedMessage(
+            EXCHANGE_NAME.into(),
+            format!("microtimestamp '{microtimestamp}'"),
+        )
+    })
+}
+
+/// Bitstamp's `live_orders` channel, streaming every order event.
+pub struct BitstampLiveOrders;
+
+impl ConnectToOrderBook for BitstampLiveOrders {
+    type SubscribeMessage = BitstampSubscribeMessage;
+
+    const EXCHANGE_NAME: &'static str = EXCHANGE_NAME;
+    const DEFAULT_BASE_URL: &'static str = BITSTAMP_WEBSOCKET_URL;
+
+    fn symbol(currency_pair: &CurrencyPair) -> String {
+        BitstampExchange::symbol(currency_pair)
+    }
+
+    fn connect_url(base_url: &str, currency_pair: &CurrencyPair) -> String {
+        BitstampExchange::connect_url(base_url, currency_pair)
+    }
+
+    fn subscribe_message(currency_pair: &CurrencyPair) -> Self::SubscribeMessage {
+        BitstampSubscribeMessage::live_orders(currency_pair)
+    }
+
+    fn classify_subscription_reply(message: &str) -> SubscriptionReply {
+        BitstampExchange::classify_subscription_reply(message)
+    }
+
+    fn message_kind(message: &str) -> MessageKind {
+        message_kind(message)
+    }
+}
+
+/// Bitstamp's `live_trades` channel, streaming every trade.
+pub struct BitstampLiveTrades;
+
+impl ConnectToOrderBook for BitstampLiveTrades {
+    type SubscribeMessage = BitstampSubscribeMessage;
+
+    const EXCHANGE_NAME: &'static str = EXCHANGE_NAME;
+    const DEFAULT_BASE_URL: &'static str = BITSTAMP_WEBSOCKET_URL;
+
+    fn symbol(currency_pair: &CurrencyPair) -> String {
+        BitstampExchange::symbol(currency_pair)
+    }
+
+    fn connect_url(base_url: &str, currency_pair: &CurrencyPair) -> String {
+        BitstampExchange::connect_url(base_url, currency_pair)
+    }
+
+    fn subscribe_message(currency_pair: &CurrencyPair) -> Self::SubscribeMessage {
+        BitstampSubscribeMessage::live_trades(currency_pair)
+    }
+
+    fn classify_subscription_reply(message: &str) -> SubscriptionReply {
+        BitstampExchange::classify_subscription_reply(message)
+    }
+
+    fn message_kind(message: &str) -> MessageKind {
+        message_kind(message)
+    }
+}
+
 type RawLevel = [String; 3];
 
+#[derive(Deserialize)]
+struct BitstampPairInfo {
+    /// Like "ETH/BTC".
+    name: String,
+    /// "Enabled" or "Disabled".
+    trading: String,
+    base_decimals: u32,
+    counter_decimals: u32,
+}
+
 #[derive(Deserialize)]
 struct BitstampRawSummary {
     data: BitstampSummaryData,
@@ -78,6 +456,58 @@ struct BitstampRawSummary {
 struct BitstampSummaryData {
     bids: Vec<RawLevel>,
     asks: Vec<RawLevel>,
+    #[serde(default)]
+    microtimestamp: String,
+}
+
+#[derive(Deserialize)]
+struct BitstampLiveOrderEvent {
+    event: String,
+    data: BitstampLiveOrder,
+}
+
+#[derive(Deserialize)]
+struct BitstampLiveOrder {
+    id: u64,
+    /// 0 for buy orders, 1 for sell orders.
+    order_type: u8,
+    price_str: String,
+    amount_str: String,
+    microtimestamp: String,
+}
+
+#[derive(Deserialize)]
+struct BitstampLiveTradeEvent {
+    data: BitstampLiveTrade,
+}
+
+#[derive(Deserialize)]
+struct BitstampLiveTrade {
+    id: u64,
+    /// 0 when the taker bought, 1 when it sold.
+    #[serde(rename = "type")]
+    trade_type: u8,
+    price_str: String,
+    amount_str: String,
+    microtimestamp: String,
+}
+
+/// Tells `bts:` events apart from book data.
+fn message_kind(message: &str) -> MessageKind {
+    // Messages that aren't events are left for the book parser to report
+    match serde_json::from_str::<BitstampEvent>(message) {
+        Ok(event) if event.event == "bts:request_reconnect" => MessageKind::RequestReconnect,
+        Ok(event) if event.event.starts_with("bts:") => MessageKind::Control,
+        _ => MessageKind::Data,
+    }
+}
+
+/// Any message, only used to tell events apart.
+#[derive(Deserialize)]
+struct BitstampEvent {
+    event: String,
+    #[serde(default)]
+    data: serde_json::Value,
 }
 
 #[derive(Serialize)]
@@ -93,6 +523,26 @@ impl BitstampSubscribeMessage {
             data: BitstampChannelInformation::new(currency_pair),
         }
     }
+
+    pub fn live_orders(currency_pair: &CurrencyPair) -> Self {
+        let symbol = BitstampExchange::symbol(currency_pair);
+        Self {
+            event: "bts:subscribe".into(),
+            data: BitstampChannelInformation {
+                channel: format!("live_orders_{symbol}"),
+            },
+        }
+    }
+
+    pub fn live_trades(currency_pair: &CurrencyPair) -> Self {
+        let symbol = BitstampExchange::symbol(currency_pair);
+        Self {
+            event: "bts:subscribe".into(),
+            data: BitstampChannelInformation {
+                channel: format!("live_trades_{symbol}"),
+            },
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -102,7 +552,7 @@ pub struct BitstampChannelInformation {
 
 impl BitstampChannelInformation {
     pub fn new(currency_pair: &CurrencyPair) -> Self {
-        let symbol = currency_pair.as_str().to_lowercase();
+        let symbol = BitstampExchange::symbol(currency_pair);
         Self {
             channel: format!("detail_order_book_{symbol}"),
         }
@@ -129,6 +579,10 @@ mod tests {
                         price,
                         amount,
                         exchange: "Bitstamp".to_string(),
+                        order_count: 1,
+                        orders: vec![],
+                        effective_price: price,
+                        legs: vec![],
                     }
                 })
                 .collect::<Vec<Level>>()
@@ -170,6 +624,18 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_bitstamp_rejecting_thin_books() {
+        let raw_json = include_str!("../../test_data/bitstamp_order_book_update_message.json");
+        let mut book: serde_json::Value = serde_json::from_str(raw_json).unwrap();
+        book["data"]["bids"].as_array_mut().unwrap().clear();
+
+        assert!(matches!(
+            BitstampExchange::try_parse_summary(book.to_string()),
+            Err(Error::NotEnoughOrders(_, side)) if side == "bids"
+        ));
+    }
+
     #[test]
     fn test_bitstamp_serializing_subscribe_message() {
         let currency_pair: CurrencyPair = "ETHBTC".parse().unwrap();
@@ -187,4 +653,106 @@ mod tests {
 
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_bitstamp_classifying_subscription_replies() {
+        let classify = BitstampExchange::classify_subscription_reply;
+
+        assert_eq!(
+            classify(
+                r#"{"event":"bts:subscription_succeeded","channel":"detail_order_book_ethbtc","data":{}}"#
+            ),
+            SubscriptionReply::Acknowledged
+        );
+        assert_eq!(
+            classify(
+                r#"{"event":"bts:error","channel":"","data":{"code":null,"message":"Bad subscription string."}}"#
+            ),
+            SubscriptionReply::Rejected("Bad subscription string.".into())
+        );
+        assert_eq!(
+            classify(include_str!(
+                "../../test_data/bitstamp_order_book_update_message.json"
+            )),
+            SubscriptionReply::Unrelated
+        );
+    }
+
+    #[test]
+    fn test_bitstamp_telling_books_from_control_events() {
+        assert_eq!(
+            message_kind(include_str!(
+                "../../test_data/bitstamp_order_book_update_message.json"
+            )),
+            MessageKind::Data
+        );
+        assert_eq!(
+            message_kind(r#"{"event":"bts:request_reconnect","channel":"","data":""}"#),
+            MessageKind::RequestReconnect
+        );
+        assert_eq!(
+            message_kind(r#"{"event":"bts:heartbeat","channel":"","data":{}}"#),
+            MessageKind::Control
+        );
+    }
+
+    #[test]
+    fn test_bitstamp_applying_live_order_events() {
+        let event = |event: &str, id: u64, order_type: u8, price: &str, microtimestamp: &str| {
+            json!({
+                "event": event,
+                "channel": "live_orders_ethbtc",
+                "data": {
+                    "id": id,
+                    "id_str": id.to_string(),
+                    "order_type": order_type,
+                    "datetime": "1663532910",
+                    "microtimestamp": microtimestamp,
+                    "amount": 1.5,
+                    "amount_str": "1.5",
+                    "price": 0.0,
+                    "price_str": price,
+                },
+            })
+            .to_string()
+        };
+
+        let mut book = Level3Book::default();
+        let created = event("order_created", 7, 1, "0.07", "20");
+        assert!(apply_live_order_event(&mut book, 10, &created).unwrap());
+
+        // Older than the snapshot
+        let stale = event("order_created", 8, 0, "0.06", "5");
+        assert!(!apply_live_order_event(&mut book, 10, &stale).unwrap());
+
+        let asks = book.top_levels(Side::Ask, 10, EXCHANGE_NAME);
+        assert_eq!(asks.len(), 1);
+        assert_eq!(asks[0].price, 0.07);
+        assert_eq!(asks[0].order_count, 1);
+        assert!(book.top_levels(Side::Bid, 10, EXCHANGE_NAME).is_empty());
+
+        let deleted = event("order_deleted", 7, 1, "0.07", "30");
+        assert!(apply_live_order_event(&mut book, 10, &deleted).unwrap());
+        assert!(book.top_levels(Side::Ask, 10, EXCHANGE_NAME).is_empty());
+    }
+
+    #[test]
+    fn test_bitstamp_deserializing_trade() {
+        let raw_json = include_str!("../../test_data/bitstamp_trade_message.json");
+
+        let trade = BitstampExchange::try_parse_trade(raw_json, 1665754020150).unwrap();
+
+        assert_eq!(
+            trade,
+            Trade {
+                exchange: "Bitstamp".into(),
+                id: "254836201".into(),
+                price: 0.06815,
+                amount: 0.5,
+                aggressor_side: TradeSide::Buy as i32,
+                traded_at: 1665754020123,
+                received_at: 1665754020150,
+            }
+        );
+    }
 }
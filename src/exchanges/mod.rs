@@ -5,31 +5,76 @@ pub use self::{binance::BinanceExchange, bitstamp::BitstampExchange};
 mod binance;
 mod bitstamp;
 
+use std::{future::Future, pin::Pin, time::Duration};
+
 use async_trait::async_trait;
-use futures::{SinkExt, StreamExt};
+use futures::{stream, SinkExt, Stream, StreamExt};
 use serde::Serialize;
 use tungstenite::Message;
 
 use crate::{
     currencies::CurrencyPair,
-    websocket::{websocket_connect, WebSocket},
-    Result,
+    websocket::{answer_websocket_pings_adapter, websocket_connect, WebSocket},
+    Error, Result,
 };
 
+/// Levels per side read from the book of each exchange.
+pub const EXCHANGE_DEPTH: usize = 10;
+
+/// Maximum time to wait for an exchange to answer a subscription.
+const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a message received while subscribing relates to the subscription.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubscriptionReply {
+    /// The exchange accepted the subscription.
+    Acknowledged,
+    /// The exchange refused the subscription, for the given reason.
+    Rejected(String),
+    /// Not a reply to the subscription, like a book update sent ahead of it.
+    Unrelated,
+}
+
+/// A websocket subscribed to an order book channel.
+pub struct OrderBookConnection {
+    pub websocket: WebSocket,
+    /// Messages received before the subscription was acknowledged.
+    pub early_messages: Vec<String>,
+}
+
+impl OrderBookConnection {
+    /// Text messages of the connection, starting with the early ones, answering pings.
+    pub fn into_messages(self) -> impl Stream<Item = Result<String>> {
+        let early_messages = stream::iter(self.early_messages.into_iter().map(Ok));
+        early_messages.chain(answer_websocket_pings_adapter(self.websocket))
+    }
+}
+
 /// A trait for connecting to an exchange order book.
 ///
 /// Connecting to an order book consists in two steps:
 /// - Connect websocket to specific URL.
 /// - Send message to subscribe to a specific order book channel.
+/// - Wait for the exchange to acknowledge the subscription.
 ///
-/// An exchange that implements `connect_url` and `subscribe_message`
-/// can call `connect_to_order_book` to receive a ready-to-use websocket.
+/// An exchange that implements `connect_url`, `subscribe_message` and
+/// `classify_subscription_reply` can call `connect_to_order_book` to
+/// receive a ready-to-use connection.
 #[async_trait]
 pub trait ConnectToOrderBook {
     type SubscribeMessage: Serialize + Send;
 
-    async fn connect_to_order_book(currency_pair: &CurrencyPair) -> Result<WebSocket> {
-        let url = Self::connect_url(currency_pair);
+    /// Name used to tag the levels of this exchange.
+    const EXCHANGE_NAME: &'static str;
+
+    /// Base URL of the exchange websocket API.
+    const DEFAULT_BASE_URL: &'static str;
+
+    async fn connect_to_order_book(
+        base_url: &str,
+        currency_pair: &CurrencyPair,
+    ) -> Result<OrderBookConnection> {
+        let url = Self::connect_url(base_url, currency_pair);
 
         let mut websocket = websocket_connect(url).await?;
 
@@ -39,19 +84,216 @@ pub trait ConnectToOrderBook {
 
         if let err @ Err(_) = websocket.send(subscribe_message).await {
             // If possible, try closing the websocket before returning error
-            let _ = websocket.close(Default::default());
+            let _ = websocket.close(None).await;
             err?;
         }
 
-        // Skip the subscription response
-        if let Some(err @ Err(_)) = websocket.next().await {
-            err?;
+        let mut early_messages = vec![];
+        let wait_for_acknowledgement =
+            Self::wait_for_acknowledgement(&mut websocket, &mut early_messages);
+
+        let result =
+            match tokio::time::timeout(SUBSCRIPTION_TIMEOUT, wait_for_acknowledgement).await {
+                Ok(result) => result,
+                Err(_) => {
+                    Err(Error::SubscriptionFailed(
+                        Self::EXCHANGE_NAME.into(),
+                        "timed out waiting for the acknowledgement".into(),
+                    ))
+                }
+            };
+
+        if let Err(err) = result {
+            let _ = websocket.close(None).await;
+            return Err(err);
+        }
+
+        Ok(OrderBookConnection {
+            websocket,
+            early_messages,
+        })
+    }
+
+    /// Reads messages until the subscription reply, keeping unrelated text messages.
+    async fn wait_for_acknowledgement(
+        websocket: &mut WebSocket,
+        early_messages: &mut Vec<String>,
+    ) -> Result<()> {
+        let subscription_failed = |reason: String| {
+            Err(Error::SubscriptionFailed(
+                Self::EXCHANGE_NAME.into(),
+                reason,
+            ))
+        };
+
+        while let Some(message) = websocket.next().await {
+            match message? {
+                Message::Text(text) => {
+                    match Self::classify_subscription_reply(&text) {
+                        SubscriptionReply::Acknowledged => return Ok(()),
+                        SubscriptionReply::Rejected(reason) => {
+                            return Err(Error::SubscriptionRejected(
+                                Self::EXCHANGE_NAME.into(),
+                                reason,
+                            ))
+                        }
+                        SubscriptionReply::Unrelated => early_messages.push(text),
+                    }
+                }
+                Message::Ping(data) => websocket.send(Message::Pong(data)).await?,
+                Message::Close(_) => break,
+                _ => {}
+            }
         }
 
-        Ok(websocket)
+        subscription_failed("connection closed before the acknowledgement".into())
     }
 
-    fn connect_url(currency_pair: &CurrencyPair) -> String;
+    /// The symbol the exchange knows `currency_pair` by.
+    fn symbol(currency_pair: &CurrencyPair) -> String;
+
+    fn connect_url(base_url: &str, currency_pair: &CurrencyPair) -> String;
 
     fn subscribe_message(currency_pair: &CurrencyPair) -> Self::SubscribeMessage;
+
+    /// Tells whether `message` acknowledges, rejects, or is unrelated to the subscription.
+    fn classify_subscription_reply(message: &str) -> SubscriptionReply;
+
+    /// Tells data apart from the events of the connection itself, all
+    /// messages are data unless the exchange sends such events.
+    fn message_kind(_message: &str) -> MessageKind {
+        MessageKind::Data
+    }
+}
+
+/// Text messages of a connection, see `OrderBookConnection::into_messages`.
+pub type MessageStream = Pin<Box<dyn Stream<Item = Result<String>> + Send>>;
+type PendingConnection = Pin<Box<dyn Future<Output = Result<OrderBookConnection>> + Send>>;
+
+/// Connects to channel `C`, replacing the connection whenever the exchange drops it.
+///
+/// When the exchange asks to reconnect, a replacement connection is
+/// subscribed while the current one keeps delivering messages, and the
+/// current one is only dropped once the replacement is acknowledged.
+/// Connections closed by the server are reopened right away, failed attempts
+/// are retried after `reconnect_delay`. Only data messages are yielded, see
+/// `ConnectToOrderBook::message_kind`, and connection errors are logged. The
+/// stream only ends with an error once the exchange rejects the subscription.
+pub async fn connect_with_reconnects<C: ConnectToOrderBook + Send + Sync>(
+    base_url: &str,
+    currency_pair: &CurrencyPair,
+    reconnect_delay: Duration,
+) -> Result<impl Stream<Item = Result<String>>> {
+    let connect = {
+        let base_url = base_url.to_owned();
+        let currency_pair = currency_pair.clone();
+
+        move || -> PendingConnection {
+            let base_url = base_url.clone();
+            let currency_pair = currency_pair.clone();
+            Box::pin(async move { C::connect_to_order_book(&base_url, &currency_pair).await })
+        }
+    };
+
+    let connection = connect().await?;
+    let exchange = C::EXCHANGE_NAME;
+
+    Ok(async_stream::stream! {
+        let mut messages: MessageStream = Box::pin(connection.into_messages());
+        let mut replacement: Option<PendingConnection> = None;
+
+        loop {
+            let next = tokio::select! {
+                message = messages.next() => Next::Message(message),
+                connection = async { replacement.as_mut().unwrap().await }, if replacement.is_some() => {
+                    Next::Replacement(connection)
+                }
+            };
+
+            match next {
+                Next::Message(Some(Ok(message))) => match C::message_kind(&message) {
+                    MessageKind::Data => yield Ok(message),
+                    MessageKind::RequestReconnect => {
+                        if replacement.is_none() {
+                            replacement = Some(connect());
+                        }
+                    }
+                    MessageKind::Control => {}
+                },
+                // The connection is usually closed right after
+                Next::Message(Some(Err(err))) => log::warn!("{exchange} connection error: {err}."),
+                // Closed by the server, wait for the pending replacement or open a new one
+                Next::Message(None) => {
+                    let connection = match replacement.take() {
+                        Some(replacement) => replacement.await,
+                        None => connect().await,
+                    };
+
+                    match connection {
+                        Ok(connection) => messages = Box::pin(connection.into_messages()),
+                        Err(err @ Error::SubscriptionRejected(..)) => {
+                            yield Err(err);
+                            break;
+                        }
+                        Err(err) => {
+                            log::warn!("{exchange} failed to reconnect, retrying: {err}.");
+                            tokio::time::sleep(reconnect_delay).await;
+                        }
+                    }
+                }
+                // Switch over, dropping the current connection
+                Next::Replacement(Ok(connection)) => {
+                    replacement = None;
+                    messages = Box::pin(connection.into_messages());
+                }
+                // Keep the current connection, it might still be closed later
+                Next::Replacement(Err(err)) => {
+                    replacement = None;
+                    log::warn!("{exchange} failed to open a replacement connection: {err}.");
+                }
+            }
+        }
+    })
+}
+
+/// What woke up the reconnecting stream.
+enum Next {
+    Message(Option<Result<String>>),
+    Replacement(Result<OrderBookConnection>),
+}
+
+/// Kinds of messages received after subscribing.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    /// Book data, like book snapshots, order events or trades.
+    Data,
+    /// The server is about to go down, and asks clients to reconnect.
+    RequestReconnect,
+    /// Other events, like repeated subscription replies.
+    Control,
+}
+
+/// Delay between attempts to reconnect to an exchange, unless configured otherwise.
+pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Websocket base URLs used to connect to each exchange.
+///
+/// Defaults to the real exchanges, but can point anywhere speaking the
+/// same protocol, like a local mock server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExchangeEndpoints {
+    pub binance: String,
+    pub bitstamp: String,
+    /// Delay between attempts to reconnect after a connection failed.
+    pub reconnect_delay: Duration,
+}
+
+impl Default for ExchangeEndpoints {
+    fn default() -> Self {
+        Self {
+            binance: BinanceExchange::DEFAULT_BASE_URL.into(),
+            bitstamp: BitstampExchange::DEFAULT_BASE_URL.into(),
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+        }
+    }
 }
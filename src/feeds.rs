@@ -0,0 +1,307 @@
+//! Tracking of the exchange feeds that contribute to the merged book.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{Stream, StreamExt};
+
+use crate::{currencies::CurrencyPair, order_book::Summary, Result};
+
+/// How long a feed can go without a valid book before being considered
+/// stale, unless configured otherwise.
+pub const STALENESS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Overall state of the exchange feeds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedsStatus {
+    /// Some feed hasn't delivered its first valid book yet.
+    Starting,
+    /// Every feed delivered a book, and every pair has a fresh one.
+    Live,
+    /// Every feed of some pair is stale, or there are no feeds.
+    Stale,
+}
+
+/// Book feed of one pair on one exchange.
+type Feed = (&'static str, String);
+
+#[derive(Debug, Default)]
+struct FeedRecord {
+    last_update: Option<Instant>,
+    /// Number of streams tracking the feed, it's forgotten once none are left.
+    streams: usize,
+}
+
+/// Records the last time each feed delivered a valid book.
+///
+/// Feeds are told apart by exchange and pair, so a composed book is only as
+/// fresh as its stalest pair. Cloning is cheap, all clones share the same
+/// records.
+#[derive(Debug, Clone)]
+pub struct FeedMonitor {
+    feeds: Arc<Mutex<HashMap<Feed, FeedRecord>>>,
+    staleness_timeout: Arc<Mutex<Duration>>,
+}
+
+impl Default for FeedMonitor {
+    /// A monitor with no feeds, they are monitored as they are tracked.
+    fn default() -> Self {
+        Self {
+            feeds: Arc::default(),
+            staleness_timeout: Arc::new(Mutex::new(STALENESS_TIMEOUT)),
+        }
+    }
+}
+
+impl FeedMonitor {
+    /// Creates a monitor for `currency_pair` on the given exchanges, none of
+    /// them updated yet.
+    pub fn new(currency_pair: &CurrencyPair, exchanges: &[&'static str]) -> Self {
+        let feeds = exchanges
+            .iter()
+            .map(|&exchange| {
+                let feed = (exchange, currency_pair.as_str().to_owned());
+                (feed, FeedRecord::default())
+            })
+            .collect();
+
+        Self {
+            feeds: Arc::new(Mutex::new(feeds)),
+            ..Self::default()
+        }
+    }
+
+    /// Considers feeds stale after `staleness_timeout` without a valid book.
+    pub fn with_staleness_timeout(self, staleness_timeout: Duration) -> Self {
+        self.set_staleness_timeout(staleness_timeout);
+        self
+    }
+
+    /// Changes the staleness timeout of every clone of this monitor.
+    pub fn set_staleness_timeout(&self, staleness_timeout: Duration) {
+        *self.staleness_timeout.lock().unwrap() = staleness_timeout;
+    }
+
+    pub fn staleness_timeout(&self) -> Duration {
+        *self.staleness_timeout.lock().unwrap()
+    }
+
+    pub fn record_update(&self, exchange: &'static str, currency_pair: &str) {
+        let mut feeds = self.feeds.lock().unwrap();
+        let record = feeds
+            .entry((exchange, currency_pair.to_owned()))
+            .or_default();
+        record.last_update = Some(Instant::now());
+    }
+
+    /// Wraps a stream of `currency_pair` summaries from `exchange`, recording
+    /// an update for every valid one.
+    ///
+    /// The feed is monitored until every stream tracking it is dropped.
+    pub fn track(
+        &self,
+        exchange: &'static str,
+        currency_pair: &CurrencyPair,
+        stream: impl Stream<Item = Result<Summary>>,
+    ) -> impl Stream<Item = Result<Summary>> {
+        let tracking = Tracking {
+            monitor: self.clone(),
+            feed: (exchange, currency_pair.as_str().to_owned()),
+        };
+        self.feeds
+            .lock()
+            .unwrap()
+            .entry(tracking.feed.clone())
+            .or_default()
+            .streams += 1;
+
+        stream.inspect(move |summary| {
+            if summary.is_ok() {
+                let (exchange, currency_pair) = &tracking.feed;
+                tracking.monitor.record_update(exchange, currency_pair);
+            }
+        })
+    }
+
+    /// Time elapsed since each exchange last delivered a valid book, sorted by exchange.
+    ///
+    /// An exchange feeding several pairs is as old as its oldest feed.
+    pub fn update_ages(&self) -> Vec<(&'static str, Option<Duration>)> {
+        self.update_ages_where(|_| true)
+    }
+
+    /// Like `update_ages`, counting only the feeds of `currency_pairs`.
+    pub fn update_ages_of(
+        &self,
+        currency_pairs: &[CurrencyPair],
+    ) -> Vec<(&'static str, Option<Duration>)> {
+        self.update_ages_where(|currency_pair| is_one_of(currency_pair, currency_pairs))
+    }
+
+    fn update_ages_where(
+        &self,
+        counted: impl Fn(&str) -> bool,
+    ) -> Vec<(&'static str, Option<Duration>)> {
+        let feeds = self.feeds.lock().unwrap();
+
+        let mut ages: HashMap<&'static str, Option<Duration>> = HashMap::new();
+        for ((exchange, currency_pair), record) in feeds.iter() {
+            if !counted(currency_pair) {
+                continue;
+            }
+
+            let age = record.last_update.map(|instant| instant.elapsed());
+            ages.entry(exchange)
+                .and_modify(|oldest| {
+                    *oldest = match (*oldest, age) {
+                        (Some(oldest), Some(age)) => Some(oldest.max(age)),
+                        _ => None,
+                    }
+                })
+                .or_insert(age);
+        }
+
+        let mut ages: Vec<_> = ages.into_iter().collect();
+        ages.sort_unstable_by_key(|&(exchange, _)| exchange);
+        ages
+    }
+
+    pub fn status(&self) -> FeedsStatus {
+        self.status_at(Instant::now(), |_| true)
+    }
+
+    /// Like `status`, counting only the feeds of `currency_pairs`.
+    pub fn status_of(&self, currency_pairs: &[CurrencyPair]) -> FeedsStatus {
+        self.status_at(Instant::now(), |currency_pair| {
+            is_one_of(currency_pair, currency_pairs)
+        })
+    }
+
+    fn status_at(&self, now: Instant, counted: impl Fn(&str) -> bool) -> FeedsStatus {
+        let feeds = self.feeds.lock().unwrap();
+        let staleness_timeout = self.staleness_timeout();
+
+        // A pair is fresh as long as one of its exchanges is
+        let mut fresh_pairs: HashMap<&str, bool> = HashMap::new();
+
+        for ((_, currency_pair), record) in feeds.iter() {
+            if !counted(currency_pair) {
+                continue;
+            }
+
+            match record.last_update {
+                None => return FeedsStatus::Starting,
+                Some(instant) => {
+                    let fresh = now.saturating_duration_since(instant) < staleness_timeout;
+                    *fresh_pairs.entry(currency_pair).or_default() |= fresh;
+                }
+            }
+        }
+
+        if !fresh_pairs.is_empty() && fresh_pairs.values().all(|&fresh| fresh) {
+            FeedsStatus::Live
+        } else {
+            FeedsStatus::Stale
+        }
+    }
+}
+
+fn is_one_of(currency_pair: &str, currency_pairs: &[CurrencyPair]) -> bool {
+    currency_pairs
+        .iter()
+        .any(|one| one.as_str() == currency_pair)
+}
+
+/// Forgets its feed when the last stream tracking it is dropped.
+struct Tracking {
+    monitor: FeedMonitor,
+    feed: Feed,
+}
+
+impl Drop for Tracking {
+    fn drop(&mut self) {
+        let mut feeds = self.monitor.feeds.lock().unwrap();
+        if let Some(record) = feeds.get_mut(&self.feed) {
+            record.streams -= 1;
+            if record.streams == 0 {
+                feeds.remove(&self.feed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+
+    use super::*;
+
+    fn pair(text: &str) -> CurrencyPair {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_feed_monitor_waits_for_every_exchange() {
+        let monitor = FeedMonitor::new(&pair("ETHBTC"), &["Binance", "Bitstamp"]);
+        assert_eq!(monitor.status(), FeedsStatus::Starting);
+
+        monitor.record_update("Binance", "ETHBTC");
+        assert_eq!(monitor.status(), FeedsStatus::Starting);
+
+        monitor.record_update("Bitstamp", "ETHBTC");
+        assert_eq!(monitor.status(), FeedsStatus::Live);
+    }
+
+    #[test]
+    fn test_feed_monitor_is_stale_when_all_exchanges_are_stale() {
+        let monitor = FeedMonitor::new(&pair("ETHBTC"), &["Binance", "Bitstamp"]);
+        monitor.record_update("Binance", "ETHBTC");
+        monitor.record_update("Bitstamp", "ETHBTC");
+
+        let later = Instant::now() + STALENESS_TIMEOUT;
+        assert_eq!(monitor.status_at(later, |_| true), FeedsStatus::Stale);
+    }
+
+    #[test]
+    fn test_feed_monitor_is_stale_when_a_leg_is_stale() {
+        let monitor = FeedMonitor::new(&pair("ETHBTC"), &["Binance", "Bitstamp"]);
+        monitor.record_update("Binance", "ETHBTC");
+        monitor.record_update("Bitstamp", "ETHBTC");
+
+        let later = Instant::now() + STALENESS_TIMEOUT;
+        monitor
+            .feeds
+            .lock()
+            .unwrap()
+            .entry(("Binance", "BTCUSDT".to_owned()))
+            .or_default()
+            .last_update = Some(later);
+
+        // The fresh BTCUSDT leg doesn't make up for the stale ETHBTC one
+        assert_eq!(monitor.status_at(later, |_| true), FeedsStatus::Stale);
+
+        // Unless the book is made of BTCUSDT alone
+        let btcusdt = |currency_pair: &str| currency_pair == "BTCUSDT";
+        assert_eq!(monitor.status_at(later, btcusdt), FeedsStatus::Live);
+        assert_eq!(monitor.update_ages_of(&[pair("BTCUSDT")]).len(), 1);
+    }
+
+    #[test]
+    fn test_feed_monitor_follows_reloaded_settings() {
+        let monitor = FeedMonitor::new(&pair("ETHBTC"), &["Binance"]);
+        monitor.record_update("Binance", "ETHBTC");
+
+        // A feed no stream tracks anymore doesn't hold back the others
+        let bitstamp = monitor.track("Bitstamp", &pair("ETHBTC"), stream::empty());
+        assert_eq!(monitor.status(), FeedsStatus::Starting);
+        drop(bitstamp);
+        assert_eq!(monitor.status(), FeedsStatus::Live);
+
+        monitor.clone().set_staleness_timeout(STALENESS_TIMEOUT * 2);
+        let later = Instant::now() + STALENESS_TIMEOUT;
+        assert_eq!(monitor.status_at(later, |_| true), FeedsStatus::Live);
+    }
+}
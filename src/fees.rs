@@ -0,0 +1,135 @@
+//! Taker fees, to compare the prices of exchanges net of fees.
+
+use std::{collections::HashMap, str::FromStr};
+
+use crate::{currencies::CurrencyPair, order_book::Level, Error, Result};
+
+/// A taker fee in basis points, for every pair of an exchange or for a single one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TakerFee {
+    pub exchange: String,
+    pub currency_pair: Option<CurrencyPair>,
+    pub basis_points: f64,
+}
+
+impl FromStr for TakerFee {
+    type Err = Error;
+
+    /// Parses "EXCHANGE=BPS" or "EXCHANGE:PAIR=BPS", like "Binance=10" or "Bitstamp:ETH/BTC=30".
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let bad_format = || Error::TakerFeeBadFormat(text.to_owned());
+
+        let (target, basis_points) = text.split_once('=').ok_or_else(bad_format)?;
+
+        let basis_points: f64 = basis_points.trim().parse().map_err(|_| bad_format())?;
+        if !(basis_points.is_finite() && (0.0..10_000.0).contains(&basis_points)) {
+            return Err(bad_format());
+        }
+
+        let (exchange, currency_pair) = match target.split_once(':') {
+            Some((exchange, currency_pair)) => {
+                let currency_pair = currency_pair.trim().parse().map_err(|_| bad_format())?;
+                (exchange, Some(currency_pair))
+            }
+            None => (target, None),
+        };
+
+        let exchange = exchange.trim();
+        if exchange.is_empty() {
+            return Err(bad_format());
+        }
+
+        Ok(Self {
+            exchange: exchange.to_owned(),
+            currency_pair,
+            basis_points,
+        })
+    }
+}
+
+/// Taker fee rates of each exchange for the pair being merged.
+///
+/// Exchanges without a configured fee are taken as free.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeeSchedule {
+    /// Fee rates keyed by lowercase exchange name, 0.001 for 10 bp.
+    rates: HashMap<String, f64>,
+    /// Whether merged books are ordered by the prices net of fees.
+    pub net_of_fees: bool,
+}
+
+impl FeeSchedule {
+    /// Resolves the fees of `currency_pair`, fees of the pair override the ones of the exchange.
+    pub fn new(fees: &[TakerFee], currency_pair: &CurrencyPair, net_of_fees: bool) -> Self {
+        let mut rates = HashMap::new();
+
+        let applies_to_every_pair = fees.iter().filter(|fee| fee.currency_pair.is_none());
+        let applies_to_this_pair = fees
+            .iter()
+            .filter(|fee| fee.currency_pair.as_ref() == Some(currency_pair));
+
+        for fee in applies_to_every_pair.chain(applies_to_this_pair) {
+            rates.insert(fee.exchange.to_lowercase(), fee.basis_points / 10_000.0);
+        }
+
+        Self { rates, net_of_fees }
+    }
+
+    fn rate(&self, exchange: &str) -> f64 {
+        self.rates
+            .get(&exchange.to_lowercase())
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    /// What selling to this bid yields, after paying the fee.
+    pub fn effective_bid(&self, level: &Level) -> f64 {
+        level.price * (1.0 - self.rate(&level.exchange))
+    }
+
+    /// What buying from this ask costs, after paying the fee.
+    pub fn effective_ask(&self, level: &Level) -> f64 {
+        level.price * (1.0 + self.rate(&level.exchange))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::level;
+
+    #[test]
+    fn test_fees_of_the_pair_override_the_ones_of_the_exchange() {
+        let fees: Vec<TakerFee> = [
+            "Binance=10",
+            "Bitstamp=40",
+            "bitstamp:ethbtc=20",
+            "Bitstamp:BTCEUR=5",
+        ]
+        .iter()
+        .map(|fee| fee.parse().unwrap())
+        .collect();
+
+        let schedule = FeeSchedule::new(&fees, &"ETHBTC".parse().unwrap(), true);
+
+        assert!((schedule.effective_bid(&level("Binance", 100.0, 1.0)) - 99.9).abs() < 1e-9);
+        assert!((schedule.effective_ask(&level("Binance", 100.0, 1.0)) - 100.1).abs() < 1e-9);
+        assert!((schedule.effective_ask(&level("Bitstamp", 100.0, 1.0)) - 100.2).abs() < 1e-9);
+        assert_eq!(schedule.effective_bid(&level("Kraken", 100.0, 1.0)), 100.0);
+    }
+
+    #[test]
+    fn test_taker_fee_parsing() {
+        assert_eq!(
+            "Bitstamp:eth/btc=7.5".parse::<TakerFee>().unwrap(),
+            TakerFee {
+                exchange: "Bitstamp".into(),
+                currency_pair: Some("ETHBTC".parse().unwrap()),
+                basis_points: 7.5,
+            }
+        );
+        assert!("Binance".parse::<TakerFee>().is_err());
+        assert!("Binance=-1".parse::<TakerFee>().is_err());
+        assert!("=10".parse::<TakerFee>().is_err());
+    }
+}
@@ -0,0 +1,238 @@
+//! Index price, a median of the mid prices of the exchanges that rejects outliers.
+
+use std::time::Duration;
+
+use itertools::Itertools;
+
+use crate::{
+    merged_book::MergedBook,
+    order_book::{ConstituentStatus, Index, IndexConstituent},
+};
+
+/// Which exchanges an index excludes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexConfig {
+    /// Mid prices further than this from the median, in basis points, are outliers.
+    pub max_deviation_bps: f64,
+    /// Exchanges without a valid book for this long are stale.
+    pub max_age: Duration,
+}
+
+/// Computes the index of the exchanges in `book`.
+///
+/// `ages` is the time since each exchange last delivered a valid book, see
+/// `FeedMonitor::update_ages`. Stale exchanges are excluded first, then the
+/// ones too far from the median of the others. Two exchanges that disagree
+/// can't tell which one is off, so an outlier needs at least two others, and
+/// none are excluded when they would all be. Mid prices are effective ones
+/// when the book is ordered net of fees.
+pub fn index_price(
+    book: &MergedBook,
+    ages: &[(&str, Option<Duration>)],
+    config: &IndexConfig,
+) -> Index {
+    let mut constituents: Vec<IndexConstituent> = book
+        .bids
+        .iter()
+        .chain(&book.asks)
+        .map(|level| level.exchange.as_str())
+        .sorted_unstable()
+        .dedup()
+        .filter_map(|exchange| {
+            let best_bid = book.bids.iter().find(|level| level.exchange == exchange)?;
+            let best_ask = book.asks.iter().find(|level| level.exchange == exchange)?;
+
+            let age = ages
+                .iter()
+                .find(|(name, _)| *name == exchange)
+                .and_then(|(_, age)| *age);
+
+            let status = match age {
+                Some(age) if age > config.max_age => ConstituentStatus::Stale,
+                _ => ConstituentStatus::Included,
+            };
+
+            Some(IndexConstituent {
+                exchange: exchange.to_owned(),
+                mid_price: (book.price_of(best_bid) + book.price_of(best_ask)) / 2.0,
+                amount: book
+                    .bids
+                    .iter()
+                    .chain(&book.asks)
+                    .filter(|level| level.exchange == exchange)
+                    .map(|level| level.amount)
+                    .sum(),
+                weight: 0.0,
+                status: status as i32,
+            })
+        })
+        .collect();
+
+    let outliers: Vec<usize> = (0..constituents.len())
+        .filter(|&position| {
+            let constituent = &constituents[position];
+            let mut others = constituents.clone();
+            others.remove(position);
+
+            if constituent.status() != ConstituentStatus::Included || included(&others).count() < 2
+            {
+                return false;
+            }
+
+            match weighted_median(&others) {
+                Some(median) => {
+                    (constituent.mid_price - median).abs() / median * 10_000.0
+                        > config.max_deviation_bps
+                }
+                None => false,
+            }
+        })
+        .collect();
+
+    if outliers.len() < included(&constituents).count() {
+        for position in outliers {
+            constituents[position].set_status(ConstituentStatus::Outlier);
+        }
+    }
+
+    let included_amount: f64 = included(&constituents)
+        .map(|constituent| constituent.amount)
+        .sum();
+
+    for constituent in &mut constituents {
+        if constituent.status() == ConstituentStatus::Included {
+            constituent.weight = constituent.amount / included_amount;
+        }
+    }
+
+    Index {
+        price: weighted_median(&constituents).unwrap_or(0.0),
+        constituents,
+    }
+}
+
+fn included(constituents: &[IndexConstituent]) -> impl Iterator<Item = &IndexConstituent> {
+    constituents
+        .iter()
+        .filter(|constituent| constituent.status() == ConstituentStatus::Included)
+}
+
+/// Median of the mid prices of the included constituents, weighted by their amounts.
+///
+/// It's always the mid price of a constituent, the lower one when the amounts
+/// are evenly split.
+fn weighted_median(constituents: &[IndexConstituent]) -> Option<f64> {
+    let sorted: Vec<_> = included(constituents)
+        .sorted_by(|left, right| left.mid_price.partial_cmp(&right.mid_price).unwrap())
+        .collect();
+
+    let half = sorted
+        .iter()
+        .map(|constituent| constituent.amount)
+        .sum::<f64>()
+        / 2.0;
+    let mut cumulative = 0.0;
+
+    for constituent in sorted {
+        cumulative += constituent.amount;
+
+        if cumulative >= half {
+            return Some(constituent.mid_price);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::level;
+
+    fn book() -> MergedBook {
+        MergedBook {
+            bids: vec![
+                level("Kraken", 150.0, 1.0),
+                level("Binance", 100.0, 3.0),
+                level("Bitstamp", 99.0, 1.0),
+            ],
+            asks: vec![
+                level("Binance", 102.0, 3.0),
+                level("Bitstamp", 103.0, 1.0),
+                level("Kraken", 152.0, 1.0),
+            ],
+            ..Default::default()
+        }
+    }
+
+    const CONFIG: IndexConfig = IndexConfig {
+        max_deviation_bps: 100.0,
+        max_age: Duration::from_secs(10),
+    };
+
+    #[test]
+    fn test_index_rejects_outliers() {
+        let index = index_price(&book(), &[], &CONFIG);
+
+        // Binance has the most amount, Kraken is far from it
+        assert_eq!(index.price, 101.0);
+
+        let constituents: Vec<_> = index
+            .constituents
+            .iter()
+            .map(|constituent| {
+                (
+                    constituent.exchange.as_str(),
+                    constituent.mid_price,
+                    constituent.weight,
+                    constituent.status(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            constituents,
+            [
+                ("Binance", 101.0, 0.75, ConstituentStatus::Included),
+                ("Bitstamp", 101.0, 0.25, ConstituentStatus::Included),
+                ("Kraken", 151.0, 0.0, ConstituentStatus::Outlier),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_index_excludes_stale_exchanges() {
+        let ages = [
+            ("Binance", Some(Duration::from_secs(11))),
+            ("Bitstamp", Some(Duration::from_secs(1))),
+        ];
+
+        let index = index_price(&book(), &ages, &CONFIG);
+
+        // Bitstamp and Kraken have the same amount, the lower mid price is the median
+        assert_eq!(index.price, 101.0);
+        assert_eq!(index.constituents[0].status(), ConstituentStatus::Stale);
+        assert_eq!(index.constituents[1].weight, 0.5);
+        assert_eq!(index.constituents[2].weight, 0.5);
+    }
+
+    #[test]
+    fn test_index_keeps_two_diverging_exchanges() {
+        let book = MergedBook {
+            bids: vec![level("Kraken", 150.0, 1.0), level("Binance", 100.0, 3.0)],
+            asks: vec![level("Binance", 102.0, 3.0), level("Kraken", 152.0, 1.0)],
+            ..Default::default()
+        };
+
+        let index = index_price(&book, &[], &CONFIG);
+
+        // Neither can be told off, Binance has the most amount
+        assert_eq!(index.price, 101.0);
+        assert!(index
+            .constituents
+            .iter()
+            .all(|constituent| constituent.status() == ConstituentStatus::Included));
+        assert_eq!(index.constituents[0].weight, 0.75);
+        assert_eq!(index.constituents[1].weight, 0.25);
+    }
+}
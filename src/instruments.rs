@@ -0,0 +1,415 @@
+//! Pairs each exchange trades, discovered from their instrument metadata and cached on disk.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    currencies::{CurrencyPair, SUPPORTED_CURRENCY_PAIRS},
+    exchanges::{BinanceExchange, BitstampExchange, ConnectToOrderBook},
+    recorder, Error, Result,
+};
+
+/// How long cached instruments are used before being fetched again.
+const CACHE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long fetching the instruments of an exchange can take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A pair an exchange trades, with the increments of its prices and amounts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Instrument {
+    pub base: String,
+    pub quote: String,
+    /// Smallest price increment, unknown for the built-in pairs.
+    pub tick_size: Option<f64>,
+    /// Smallest amount increment, unknown for the built-in pairs.
+    pub lot_size: Option<f64>,
+}
+
+impl Instrument {
+    pub fn currency_pair(&self) -> CurrencyPair {
+        CurrencyPair::new(&self.base, &self.quote)
+    }
+}
+
+/// Where the instruments of each exchange are fetched from, URLs or paths of local files.
+#[derive(Debug, Clone)]
+pub struct InstrumentSources {
+    pub binance: String,
+    pub bitstamp: String,
+}
+
+impl Default for InstrumentSources {
+    fn default() -> Self {
+        Self {
+            binance: BinanceExchange::INSTRUMENTS_URL.into(),
+            bitstamp: BitstampExchange::INSTRUMENTS_URL.into(),
+        }
+    }
+}
+
+/// The instruments of every exchange, as fetched at some point.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PairCatalog {
+    /// Milliseconds since the Unix epoch when the instruments were fetched.
+    pub fetched_at: u64,
+    /// Instruments keyed by exchange name.
+    pub exchanges: BTreeMap<String, Vec<Instrument>>,
+}
+
+impl PairCatalog {
+    /// Every exchange trading `SUPPORTED_CURRENCY_PAIRS`, when nothing better is known.
+    pub fn builtin() -> Self {
+        let instruments: Vec<_> = SUPPORTED_CURRENCY_PAIRS
+            .iter()
+            .map(|symbol| {
+                let currency_pair: CurrencyPair = symbol.parse().unwrap();
+
+                Instrument {
+                    base: currency_pair.base().to_owned(),
+                    quote: currency_pair.quote().to_owned(),
+                    tick_size: None,
+                    lot_size: None,
+                }
+            })
+            .collect();
+
+        Self {
+            fetched_at: 0,
+            exchanges: [
+                BinanceExchange::EXCHANGE_NAME,
+                BitstampExchange::EXCHANGE_NAME,
+            ]
+            .into_iter()
+            .map(|exchange| (exchange.to_owned(), instruments.clone()))
+            .collect(),
+        }
+    }
+
+    /// Names of the exchanges trading `currency_pair`, sorted.
+    pub fn exchanges_trading(&self, currency_pair: &CurrencyPair) -> Vec<&str> {
+        self.exchanges
+            .iter()
+            .filter(|(_, instruments)| {
+                instruments
+                    .iter()
+                    .any(|instrument| instrument.currency_pair() == *currency_pair)
+            })
+            .map(|(exchange, _)| exchange.as_str())
+            .collect()
+    }
+
+    /// Whether some exchange trades `currency_pair`.
+    pub fn supports(&self, currency_pair: &CurrencyPair) -> bool {
+        !self.exchanges_trading(currency_pair).is_empty()
+    }
+
+    /// Every pair some exchange trades, sorted, with the instrument of each exchange trading it.
+    ///
+    /// Exchanges are in the order of `exchanges`.
+    pub fn coverage(&self) -> Vec<(CurrencyPair, Vec<Option<&Instrument>>)> {
+        let currency_pairs: BTreeSet<(String, String)> = self
+            .exchanges
+            .values()
+            .flatten()
+            .map(|instrument| (instrument.base.clone(), instrument.quote.clone()))
+            .collect();
+
+        currency_pairs
+            .into_iter()
+            .map(|(base, quote)| {
+                let currency_pair = CurrencyPair::new(&base, &quote);
+                let instruments = self
+                    .exchanges
+                    .values()
+                    .map(|instruments| {
+                        instruments
+                            .iter()
+                            .find(|instrument| instrument.currency_pair() == currency_pair)
+                    })
+                    .collect();
+
+                (currency_pair, instruments)
+            })
+            .collect()
+    }
+}
+
+/// Prints which exchanges trade each pair, one pair per line.
+///
+/// Each exchange trading a pair shows its tick and lot sizes, as "tick/lot",
+/// or "x" when they're unknown.
+pub fn print_coverage(catalog: &PairCatalog) {
+    print!("{}", coverage_table(catalog));
+}
+
+fn coverage_table(catalog: &PairCatalog) -> String {
+    let mut table = format!("{:<14}", "PAIR");
+    for exchange in catalog.exchanges.keys() {
+        table += &format!("{exchange:<22}");
+    }
+    table = table.trim_end().to_owned() + "\n";
+
+    for (currency_pair, instruments) in catalog.coverage() {
+        let mut row = format!("{:<14}", currency_pair.to_string());
+        for instrument in instruments {
+            let cell = match instrument {
+                Some(Instrument {
+                    tick_size: Some(tick_size),
+                    lot_size: Some(lot_size),
+                    ..
+                }) => format!("{tick_size}/{lot_size}"),
+                Some(_) => "x".to_owned(),
+                None => "-".to_owned(),
+            };
+            row += &format!("{cell:<22}");
+        }
+        table += row.trim_end();
+        table += "\n";
+    }
+
+    table
+}
+
+/// Loads the pairs the exchanges trade, from `cache` while it's fresh.
+///
+/// Fetched instruments are written to `cache`, failing to do so is only
+/// logged. The instruments of an exchange that can't be fetched are taken
+/// from a stale cache instead, or are the built-in pairs if there's none, and
+/// are fetched again the next time.
+pub async fn load_catalog(sources: &InstrumentSources, cache: &Path) -> Result<PairCatalog> {
+    let cached: Option<PairCatalog> = fs::read(cache)
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok());
+
+    let now = recorder::unix_timestamp_millis();
+
+    if let Some(cached) = &cached {
+        if now.saturating_sub(cached.fetched_at) < CACHE_MAX_AGE.as_millis() as u64 {
+            return Ok(cached.clone());
+        }
+    }
+
+    let (binance, bitstamp) = futures::join!(
+        fetch_instruments(&sources.binance, BinanceExchange::parse_instruments),
+        fetch_instruments(&sources.bitstamp, BitstampExchange::parse_instruments),
+    );
+
+    let mut catalog = PairCatalog {
+        fetched_at: now,
+        exchanges: BTreeMap::new(),
+    };
+    let mut fetched_any = false;
+
+    for (exchange, fetched) in [
+        (BinanceExchange::EXCHANGE_NAME, binance),
+        (BitstampExchange::EXCHANGE_NAME, bitstamp),
+    ] {
+        let instruments = match fetched {
+            Ok(instruments) => {
+                fetched_any = true;
+                instruments
+            }
+            Err(err) => {
+                // A catalog missing some exchange is stale, so it's fetched again
+                catalog.fetched_at = 0;
+
+                let cached = cached
+                    .as_ref()
+                    .and_then(|cached| cached.exchanges.get(exchange));
+                let (instruments, kind) = match cached {
+                    Some(instruments) => (instruments.clone(), "cached"),
+                    None => {
+                        (
+                            PairCatalog::builtin().exchanges[exchange].clone(),
+                            "built-in",
+                        )
+                    }
+                };
+
+                log::warn!(
+                    "Failed to fetch the pairs of {exchange}, using the {kind} ones: {err}."
+                );
+                instruments
+            }
+        };
+
+        catalog.exchanges.insert(exchange.to_owned(), instruments);
+    }
+
+    if fetched_any {
+        let written = serde_json::to_vec_pretty(&catalog)
+            .map_err(Error::from)
+            .and_then(|contents| Ok(fs::write(cache, contents)?));
+        if let Err(err) = written {
+            log::warn!(
+                "Failed to cache the pairs of the exchanges at {}: {err}.",
+                cache.display()
+            );
+        }
+    }
+
+    Ok(catalog)
+}
+
+/// Fetches `source` and parses the instruments it lists with `parse`.
+async fn fetch_instruments(
+    source: &str,
+    parse: fn(&str) -> Result<Vec<Instrument>>,
+) -> Result<Vec<Instrument>> {
+    parse(&fetch(source).await?)
+}
+
+/// Reads `source` over HTTP if it's a URL, from disk otherwise.
+async fn fetch(source: &str) -> Result<String> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+        Ok(client
+            .get(source)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?)
+    } else {
+        let path = source.strip_prefix("file://").unwrap_or(source);
+        Ok(fs::read_to_string(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_SOURCES: (&str, &str) = (
+        "test_data/binance_exchange_info.json",
+        "test_data/bitstamp_trading_pairs_info.json",
+    );
+
+    fn sources(binance: &str, bitstamp: &str) -> InstrumentSources {
+        InstrumentSources {
+            binance: binance.into(),
+            bitstamp: bitstamp.into(),
+        }
+    }
+
+    #[test]
+    fn test_parsing_instruments() {
+        let binance = fs::read_to_string(TEST_SOURCES.0).unwrap();
+        let binance = BinanceExchange::parse_instruments(&binance).unwrap();
+
+        // Symbols that aren't trading are left out
+        assert_eq!(binance.len(), 3);
+        assert_eq!(
+            binance[0],
+            Instrument {
+                base: "ETH".into(),
+                quote: "BTC".into(),
+                tick_size: Some(0.000001),
+                lot_size: Some(0.0001),
+            }
+        );
+
+        let bitstamp = fs::read_to_string(TEST_SOURCES.1).unwrap();
+        let bitstamp = BitstampExchange::parse_instruments(&bitstamp).unwrap();
+
+        assert_eq!(bitstamp.len(), 3);
+        assert_eq!(bitstamp[2].currency_pair().as_str(), "USDCUSDT");
+        assert_eq!(bitstamp[2].tick_size, Some(0.00001));
+        assert_eq!(bitstamp[2].lot_size, Some(0.00001));
+    }
+
+    #[tokio::test]
+    async fn test_catalog_is_cached_on_disk() {
+        let cache =
+            std::env::temp_dir().join(format!("keyrocky-instruments-{}.json", std::process::id()));
+        let _ = fs::remove_file(&cache);
+
+        let catalog = load_catalog(&sources(TEST_SOURCES.0, TEST_SOURCES.1), &cache)
+            .await
+            .unwrap();
+
+        // Pairs listed by a single exchange are supported by that one alone
+        let exchanges_trading =
+            |currency_pair: &str| catalog.exchanges_trading(&currency_pair.parse().unwrap());
+        assert_eq!(exchanges_trading("ETH/BTC"), ["Binance", "Bitstamp"]);
+        assert_eq!(exchanges_trading("BNBBTC"), ["Binance"]);
+        assert_eq!(exchanges_trading("ETHEUR"), ["Bitstamp"]);
+        assert!(!catalog.supports(&"XYZBTC".parse().unwrap()));
+
+        // The fresh cache is used without fetching anything
+        let missing = sources("test_data/missing.json", "test_data/missing.json");
+        assert_eq!(load_catalog(&missing, &cache).await.unwrap(), catalog);
+
+        // A stale cache is still better than the built-in pairs
+        let stale = PairCatalog {
+            fetched_at: 0,
+            ..catalog.clone()
+        };
+        fs::write(&cache, serde_json::to_vec(&stale).unwrap()).unwrap();
+        assert_eq!(load_catalog(&missing, &cache).await.unwrap(), stale);
+
+        fs::remove_file(&cache).unwrap();
+        let builtin = load_catalog(&missing, &cache).await.unwrap();
+        assert!(builtin.supports(&"AAVEBTC".parse().unwrap()));
+
+        // Each exchange falls back on its own, and is fetched again the next time
+        let bitstamp_missing = sources(TEST_SOURCES.0, "test_data/missing.json");
+        let partial = load_catalog(&bitstamp_missing, &cache).await.unwrap();
+        assert_eq!(partial.fetched_at, 0);
+        assert_eq!(partial.exchanges["Binance"], catalog.exchanges["Binance"]);
+        assert_eq!(
+            partial.exchanges["Bitstamp"],
+            PairCatalog::builtin().exchanges["Bitstamp"]
+        );
+
+        fs::write(&cache, serde_json::to_vec(&stale).unwrap()).unwrap();
+        let partial = load_catalog(&bitstamp_missing, &cache).await.unwrap();
+        assert_eq!(partial.exchanges, catalog.exchanges);
+        fs::remove_file(&cache).unwrap();
+
+        // A cache that can't be written doesn't keep the fetched pairs from being used
+        let unwritable = cache.join("instruments.json");
+        let fetched = load_catalog(&sources(TEST_SOURCES.0, TEST_SOURCES.1), &unwritable)
+            .await
+            .unwrap();
+        assert_eq!(fetched.exchanges, catalog.exchanges);
+    }
+
+    #[test]
+    fn test_coverage_of_each_pair() {
+        let binance = fs::read_to_string(TEST_SOURCES.0).unwrap();
+        let bitstamp = fs::read_to_string(TEST_SOURCES.1).unwrap();
+
+        let catalog = PairCatalog {
+            fetched_at: 0,
+            exchanges: [
+                (
+                    "Binance".to_owned(),
+                    BinanceExchange::parse_instruments(&binance).unwrap(),
+                ),
+                (
+                    "Bitstamp".to_owned(),
+                    BitstampExchange::parse_instruments(&bitstamp).unwrap(),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        assert_eq!(
+            coverage_table(&catalog),
+            "PAIR          Binance               Bitstamp\n\
+             BNB/BTC       0.000001/0.001        -\n\
+             ETH/BTC       0.000001/0.0001       0.00000001/0.00000001\n\
+             ETH/EUR       -                     0.01/0.00000001\n\
+             USDC/USDT     0.0001/1              0.00001/0.00001\n"
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Level-3 order book, maintained order by order.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+};
+
+use crate::{
+    exchanges::EXCHANGE_DEPTH,
+    order_book::{Level, Order, Summary},
+    Error, Result,
+};
+
+/// Levels sent per side in a summary, as many as the other books read from exchanges.
+const SUMMARY_DEPTH: usize = EXCHANGE_DEPTH;
+
+/// Side of the book an order rests on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+/// An order resting in the book.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestingOrder {
+    pub id: u64,
+    pub side: Side,
+    pub price: f64,
+    pub amount: f64,
+}
+
+/// Price usable as a map key, ordered by `f64::total_cmp`.
+#[derive(Debug, Clone, Copy)]
+struct Price(f64);
+
+impl PartialEq for Price {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Price {}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Orders indexed by ID, and queued by arrival at each price level.
+#[derive(Debug, Default)]
+pub struct Level3Book {
+    orders: HashMap<u64, RestingOrder>,
+    bids: BTreeMap<Price, Vec<u64>>,
+    asks: BTreeMap<Price, Vec<u64>>,
+}
+
+impl Level3Book {
+    /// Adds an order at the back of its price level queue, or updates it if already known.
+    ///
+    /// Orders that only change amount keep their place in the queue, orders
+    /// that change price or side go to the back of their new queue.
+    pub fn upsert(&mut self, order: RestingOrder) {
+        if let Some(existing) = self.orders.get_mut(&order.id) {
+            if existing.side == order.side && existing.price == order.price {
+                existing.amount = order.amount;
+                return;
+            }
+
+            self.remove(order.id);
+        }
+
+        self.queues_mut(order.side)
+            .entry(Price(order.price))
+            .or_default()
+            .push(order.id);
+        self.orders.insert(order.id, order);
+    }
+
+    /// Removes an order, unknown IDs are ignored.
+    pub fn remove(&mut self, id: u64) {
+        let order = match self.orders.remove(&id) {
+            Some(order) => order,
+            None => return,
+        };
+
+        let queues = self.queues_mut(order.side);
+        if let Some(queue) = queues.get_mut(&Price(order.price)) {
+            queue.retain(|&queued| queued != id);
+
+            if queue.is_empty() {
+                queues.remove(&Price(order.price));
+            }
+        }
+    }
+
+    /// Best `depth` levels of `side`, best first, with their orders in queue order.
+    pub fn top_levels(&self, side: Side, depth: usize, exchange: &str) -> Vec<Level> {
+        let queues: Box<dyn Iterator<Item = (&Price, &Vec<u64>)>> = match side {
+            Side::Bid => Box::new(self.bids.iter().rev()),
+            Side::Ask => Box::new(self.asks.iter()),
+        };
+
+        queues
+            .take(depth)
+            .map(|(price, queue)| {
+                let orders: Vec<Order> = queue
+                    .iter()
+                    .enumerate()
+                    .map(|(position, id)| {
+                        Order {
+                            id: id.to_string(),
+                            amount: self.orders[id].amount,
+                            queue_position: position as u32,
+                        }
+                    })
+                    .collect();
+
+                Level {
+                    exchange: exchange.to_string(),
+                    price: price.0,
+                    amount: orders.iter().map(|order| order.amount).sum(),
+                    order_count: orders.len() as u32,
+                    orders,
+                    effective_price: price.0,
+                    legs: vec![],
+                }
+            })
+            .collect()
+    }
+
+    /// Summary of the best levels, failing if a side doesn't have enough levels yet.
+    pub fn summary(&self, exchange: &str) -> Result<Summary> {
+        let bids = self.top_levels(Side::Bid, SUMMARY_DEPTH, exchange);
+        let asks = self.top_levels(Side::Ask, SUMMARY_DEPTH, exchange);
+
+        if bids.len() < SUMMARY_DEPTH {
+            return Err(Error::NotEnoughOrders(exchange.into(), "bids".into()));
+        }
+
+        if asks.len() < SUMMARY_DEPTH {
+            return Err(Error::NotEnoughOrders(exchange.into(), "asks".into()));
+        }
+
+        Ok(Summary::new(bids, asks))
+    }
+
+    fn queues_mut(&mut self, side: Side) -> &mut BTreeMap<Price, Vec<u64>> {
+        match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(id: u64, side: Side, price: f64, amount: f64) -> RestingOrder {
+        RestingOrder {
+            id,
+            side,
+            price,
+            amount,
+        }
+    }
+
+    #[test]
+    fn test_level3_book_keeps_queue_priority() {
+        let mut book = Level3Book::default();
+        book.upsert(order(1, Side::Bid, 10.0, 1.0));
+        book.upsert(order(2, Side::Bid, 10.0, 2.0));
+        book.upsert(order(3, Side::Bid, 11.0, 0.5));
+        book.upsert(order(4, Side::Ask, 12.0, 3.0));
+
+        // A partial fill keeps the place in the queue
+        book.upsert(order(1, Side::Bid, 10.0, 0.25));
+
+        let bids = book.top_levels(Side::Bid, 10, "Bitstamp");
+        assert_eq!(bids.len(), 2);
+        assert_eq!(bids[0].price, 11.0);
+        assert_eq!(bids[1].price, 10.0);
+        assert_eq!(bids[1].amount, 2.25);
+        assert_eq!(bids[1].order_count, 2);
+
+        let queue: Vec<_> = bids[1]
+            .orders
+            .iter()
+            .map(|order| (order.id.as_str(), order.queue_position))
+            .collect();
+        assert_eq!(queue, [("1", 0), ("2", 1)]);
+
+        // Moving to another price goes to the back of that queue
+        book.upsert(order(1, Side::Bid, 11.0, 0.25));
+        let bids = book.top_levels(Side::Bid, 10, "Bitstamp");
+        assert_eq!(bids[0].orders[1].id, "1");
+        assert_eq!(bids[1].order_count, 1);
+        assert_eq!(bids[1].orders[0].queue_position, 0);
+    }
+
+    #[test]
+    fn test_level3_book_removes_empty_levels() {
+        let mut book = Level3Book::default();
+        book.upsert(order(1, Side::Ask, 12.0, 1.0));
+        book.upsert(order(2, Side::Ask, 13.0, 1.0));
+
+        book.remove(1);
+        book.remove(42);
+
+        let asks = book.top_levels(Side::Ask, 10, "Bitstamp");
+        assert_eq!(asks.len(), 1);
+        assert_eq!(asks[0].price, 13.0);
+        assert!(book.summary("Bitstamp").is_err());
+    }
+}
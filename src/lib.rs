@@ -0,0 +1,6 @@
+//! Client library for the keyrocky order book aggregator.
+//!
+//! Exposes the generated gRPC client and messages, so other crates can
+//! consume a running server without compiling the proto file themselves.
+
+pub mod order_book;
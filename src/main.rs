@@ -1,25 +1,52 @@
 /// Re-export items at the root crate for other modules.
 pub use self::error::{Error, Result};
 
+mod books;
 mod cli;
+mod client;
+mod config;
 mod currencies;
 mod error;
 mod exchanges;
-mod order_book;
+mod feeds;
+mod fees;
+mod index;
+mod instruments;
+mod level3;
+mod merged_book;
+mod quotes;
+mod recorder;
+mod reload;
+mod replay;
+mod routing;
 mod server;
+mod synthetic;
+mod terminal_ui;
+#[cfg(test)]
+mod test_utils;
 mod websocket;
 
-use std::collections::HashMap;
+use std::path::PathBuf;
 
 use exchanges::{BinanceExchange, BitstampExchange};
-use futures::{future, Stream, StreamExt};
-use itertools::Itertools;
-use merge_streams::MergeStreams;
-use tokio::sync::broadcast;
-
-use crate::{currencies::CurrencyPair, exchanges::ConnectToOrderBook, order_book::Summary};
-
-const BROADCAST_QUEUE_CAPACITY: usize = 100;
+use futures::StreamExt;
+use keyrocky::order_book;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::broadcast,
+};
+
+use crate::{
+    books::Books,
+    cli::{Command, ServeCommand, ServeReloader},
+    currencies::CurrencyPair,
+    exchanges::ConnectToOrderBook,
+    feeds::FeedMonitor,
+    fees::FeeSchedule,
+    recorder::Recorder,
+    replay::ReplaySpeed,
+    server::{ServedPairs, ServerOptions, BROADCAST_QUEUE_CAPACITY},
+};
 
 #[tokio::main]
 async fn main() {
@@ -30,86 +57,192 @@ async fn main() {
 }
 
 async fn run() -> Result<()> {
-    let (currency_pair, port) = cli::parse_arguments()?;
-
-    let mut stream = build_aggregated_book_order(&currency_pair).await?;
+    match cli::parse_arguments().await? {
+        Command::Serve(command, reloader) => serve(*command, reloader).await,
+        Command::Watch {
+            addr,
+            currency_pair,
+            format,
+            view,
+        } => client::watch(addr, currency_pair, format, view).await,
+        Command::Tui {
+            source,
+            currency_pair,
+        } => terminal_ui::run(source, currency_pair).await,
+        Command::Replay {
+            files,
+            speed,
+            currency_pair,
+            port,
+        } => replay(files, speed, currency_pair, port).await,
+        Command::ListPairs { catalog } => {
+            instruments::print_coverage(&catalog);
+            Ok(())
+        }
+    }
+}
 
-    let (channel_subscriber, _) = broadcast::channel(BROADCAST_QUEUE_CAPACITY);
-    let publisher = channel_subscriber.clone();
+/// Serves the aggregated book order until SIGINT or SIGTERM, see `serve_book`.
+///
+/// Raw exchange messages are recorded in `record_dir`, if given.
+async fn serve(command: ServeCommand, reloader: Option<ServeReloader>) -> Result<()> {
+    let recorder = match &command.record_dir {
+        Some(dir) => Recorder::start(dir)?,
+        None => Recorder::disabled(),
+    };
 
-    // Consume the stream and transmit all summaries to the publisher
-    tokio::spawn(async move {
-        let stringify_error = |err| format!("{err}");
+    let result = tokio::select! {
+        result = serve_book(command, reloader, &recorder) => result,
+        result = shutdown_requested() => result,
+    };
 
-        while let Some(updated_summary) = stream.next().await {
-            let summary = updated_summary.map_err(stringify_error);
+    // Otherwise the last recorded file is left without its gzip trailer
+    recorder.stop().await;
 
-            // Ignore send errors, nobody might be listening to this publisher now,
-            // however, new listeners are spawned on-demand when requests are received.
-            let _ = publisher.send(summary);
-        }
+    result
+}
 
-        Ok(()) as Result<()>
-    });
+/// Waits for SIGINT or SIGTERM.
+async fn shutdown_requested() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
 
-    server::run_server(channel_subscriber, port)
-        .await
-        .expect("cannot run server");
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
 
+    log::info!("Shutting down.");
     Ok(())
 }
 
-/// Connects to exchanges and returns the aggregated book order stream.
-async fn build_aggregated_book_order(
-    currency_pair: &CurrencyPair,
-) -> Result<impl Stream<Item = Result<Summary>>> {
-    // Connect to exchange websockets, answer pings and parse summaries.
-    let binance = BinanceExchange::connect_to_order_book(currency_pair).await?;
-    let binance = websocket::answer_websocket_pings_adapter(binance);
-    let binance = binance.map(|message| BinanceExchange::try_parse_summary(message?));
+/// Serves the aggregated book orders of the served pairs with the `server` options.
+///
+/// The config is reloaded by `reloader` whenever it changes or on SIGHUP, if given.
+async fn serve_book(
+    ServeCommand {
+        pairs,
+        server,
+        record_dir: _,
+        book,
+        staleness_timeout,
+        log_level: _,
+    }: ServeCommand,
+    reloader: Option<ServeReloader>,
+    recorder: &Recorder,
+) -> Result<()> {
+    // Exchanges are monitored as their feeds are connected
+    let feed_monitor = FeedMonitor::default().with_staleness_timeout(staleness_timeout);
+
+    let served_pairs = ServedPairs::default();
+    let mut books = Books::connect(
+        &pairs,
+        &book,
+        served_pairs.clone(),
+        feed_monitor.clone(),
+        recorder.clone(),
+    )
+    .await?;
+
+    let addr = server.addr;
+    let running_server = server::run_server(served_pairs, feed_monitor.clone(), server).await?;
+    log::info!(
+        "Serving {} order books at {}.",
+        pairs
+            .iter()
+            .map(|served_pair| served_pair.currency_pair.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        running_server.local_addr
+    );
+
+    let reloader = match reloader {
+        Some(reloader) => reloader,
+        None => return running_server.wait().await,
+    };
+
+    let server_handle = running_server.handle();
+    let mut reload_requests = Box::pin(reload::reload_requests(reloader.config_path())?);
+
+    let served = running_server.wait();
+    tokio::pin!(served);
+
+    loop {
+        tokio::select! {
+            result = &mut served => return result,
+            Some(()) = reload_requests.next() => {}
+        }
 
-    let bitstamp = BitstampExchange::connect_to_order_book(currency_pair).await?;
-    let bitstamp = websocket::answer_websocket_pings_adapter(bitstamp);
-    let bitstamp = bitstamp.map(|message| BitstampExchange::try_parse_summary(message?));
+        let reloaded = match reloader.reload().await {
+            Ok(reloaded) => reloaded,
+            Err(err) => {
+                log::error!("Failed to reload the config, keeping the current one: {err}.");
+                continue;
+            }
+        };
+
+        if reloaded.server.addr != addr {
+            log::error!(
+                "Failed to reload the config, keeping the current one: the address can't \
+                 change without a restart."
+            );
+            continue;
+        }
 
-    Ok(combine_streams(binance, bitstamp))
-}
+        log::set_max_level(reloaded.log_level);
+        feed_monitor.set_staleness_timeout(reloaded.staleness_timeout);
 
-// Combine streams into a new stream, summaries are cached by
-// the (hopefully) unique exchange names, and overwritten every
-// time the same exchange updates it's latest summary.
-fn combine_streams(
-    left_stream: impl Stream<Item = Result<Summary>>,
-    right_stream: impl Stream<Item = Result<Summary>>,
-) -> impl Stream<Item = Result<Summary>> {
-    let stream = (left_stream, right_stream).merge();
-    stream.scan(
-        HashMap::<String, Summary>::new(),
-        |cached_summaries, next_summary| {
-            let next_summary = match next_summary {
-                Ok(next_summary) => next_summary,
-                Err(err) => return future::ready(Some(Err(err))),
-            };
-
-            let cache_key = next_summary.asks[0].exchange.clone();
-            cached_summaries.insert(cache_key, next_summary);
-
-            let ordered_bids = cached_summaries
-                .values()
-                .flat_map(|summary| summary.bids.iter())
-                .sorted_by(|left, right| left.price.partial_cmp(&right.price).unwrap().reverse());
-
-            let ordered_asks = cached_summaries
-                .values()
-                .flat_map(|summary| summary.asks.iter())
-                .sorted_by(|left, right| left.price.partial_cmp(&right.price).unwrap());
-
-            let combined_order_book = Summary::new(
-                ordered_bids.take(10).cloned().collect(),
-                ordered_asks.take(10).cloned().collect(),
-            );
+        // Only the feeds of added, removed or changed pairs and exchanges are connected again
+        books.reload(&reloaded.pairs, &reloaded.book).await;
 
-            future::ready(Some(Ok(combined_order_book)))
-        },
-    )
+        if let Err(err) = server_handle.reload(reloaded.server.depth, reloaded.server.tls) {
+            log::error!("Failed to serve with the reloaded options: {err}.");
+            continue;
+        }
+
+        log::info!("Reloaded {}.", reloader.config_path().display());
+    }
+}
+
+/// Serves books replayed from the recordings in `files` at `port`.
+async fn replay(
+    files: Vec<PathBuf>,
+    speed: ReplaySpeed,
+    currency_pair: CurrencyPair,
+    port: u16,
+) -> Result<()> {
+    let messages = recorder::read_recordings(&files)?;
+
+    let feed_monitor = FeedMonitor::new(
+        &currency_pair,
+        &[
+            BinanceExchange::EXCHANGE_NAME,
+            BitstampExchange::EXCHANGE_NAME,
+        ],
+    );
+
+    let summaries = replay::replay_summaries(messages, speed, &currency_pair, feed_monitor.clone());
+    let stream = books::merge_summaries(summaries, FeeSchedule::default());
+
+    let (book_subscriber, _) = broadcast::channel(BROADCAST_QUEUE_CAPACITY);
+    books::spawn_publisher(stream, book_subscriber.clone());
+    // Trades aren't recorded, none are replayed
+    let (trade_subscriber, _) = broadcast::channel(BROADCAST_QUEUE_CAPACITY);
+
+    let served_pairs = ServedPairs::default();
+    served_pairs.insert(
+        &currency_pair,
+        book_subscriber,
+        trade_subscriber,
+        vec![currency_pair.clone()],
+    );
+
+    let server =
+        server::run_server(served_pairs, feed_monitor, ServerOptions::localhost(port)).await?;
+    log::info!(
+        "Serving {} order book at {}.",
+        currency_pair.as_str(),
+        server.local_addr
+    );
+
+    server.wait().await
 }
@@ -0,0 +1,823 @@
+//! Book merged from every exchange, and the views derived from it.
+
+use std::str::FromStr;
+
+use itertools::Itertools;
+
+use crate::{
+    exchanges::EXCHANGE_DEPTH,
+    fees::FeeSchedule,
+    order_book::{
+        AggregatedLevel, AggregatedSummary, DepthWithin, ExchangeQuote, Level, Metrics, Order,
+        Summary, VenueAmount,
+    },
+    Error, Result,
+};
+
+/// Levels sent per side to clients, unless configured otherwise.
+pub const SUMMARY_DEPTH: usize = 10;
+/// Most levels that can be sent per side, as many as each exchange is read to.
+///
+/// Pairs traded by a single exchange, and synthetic books, can't fill more.
+pub const MAX_SUMMARY_DEPTH: usize = EXCHANGE_DEPTH;
+
+/// Tolerance when rounding prices to buckets, so prices already on a bucket
+/// boundary aren't pushed to the next one by floating point error.
+const BUCKET_ROUNDING_TOLERANCE: f64 = 1e-9;
+
+/// Width of the price buckets of a bucketed book.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BucketSize {
+    /// In units of the quote currency.
+    Tick(f64),
+    /// In basis points of the mid price.
+    BasisPoints(f64),
+}
+
+impl FromStr for BucketSize {
+    type Err = Error;
+
+    /// Parses a tick size like "0.5", or basis points like "1bp".
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (number, bucket_size): (_, fn(f64) -> Self) = match text.strip_suffix("bp") {
+            Some(number) => (number, Self::BasisPoints),
+            None => (text, Self::Tick),
+        };
+
+        match number.parse::<f64>() {
+            Ok(size) if size.is_finite() && size > 0.0 => Ok(bucket_size(size)),
+            _ => Err(Error::BucketSizeBadFormat(text.to_owned())),
+        }
+    }
+}
+
+/// Every level of every exchange, best first.
+///
+/// Clients are sent views of the top of it, the full depth is kept so views
+/// that merge levels still have enough of them.
+///
+/// Net of fees, levels are ordered by their effective prices, and every view
+/// compares and reports effective prices instead of raw ones.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MergedBook {
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+    pub net_of_fees: bool,
+    /// Set while the best bid is at or above the best ask.
+    pub crossing: Option<Crossing>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrossingKind {
+    /// The best bid is above the best ask.
+    Crossed,
+    /// The best bid is at the best ask.
+    Locked,
+}
+
+/// A best bid at or above the best ask, usually from different exchanges.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Crossing {
+    pub kind: CrossingKind,
+    pub bid_exchange: String,
+    pub ask_exchange: String,
+    pub bid_price: f64,
+    pub ask_price: f64,
+    /// Amount that can be bought from the asks and sold to the bids at a profit, or even.
+    pub amount: f64,
+    /// Milliseconds since the Unix epoch when this crossing started.
+    pub started_at: u64,
+}
+
+impl Crossing {
+    /// Whether `self` is the same crossing as `other`, at possibly different prices.
+    pub fn continues(&self, other: &Crossing) -> bool {
+        self.kind == other.kind
+            && self.bid_exchange == other.bid_exchange
+            && self.ask_exchange == other.ask_exchange
+    }
+}
+
+impl MergedBook {
+    /// Merges the books of each exchange, bids descending and asks ascending.
+    ///
+    /// The effective price of every level is set from `fees`.
+    pub fn merge<'a>(books: impl Iterator<Item = &'a Summary> + Clone, fees: &FeeSchedule) -> Self {
+        let bids = books
+            .clone()
+            .flat_map(|summary| summary.bids.iter())
+            .map(|level| {
+                Level {
+                    effective_price: fees.effective_bid(level),
+                    ..level.clone()
+                }
+            })
+            .collect();
+
+        let asks = books
+            .flat_map(|summary| summary.asks.iter())
+            .map(|level| {
+                Level {
+                    effective_price: fees.effective_ask(level),
+                    ..level.clone()
+                }
+            })
+            .collect();
+
+        Self::from_levels(bids, asks, fees.net_of_fees)
+    }
+
+    /// Sorts levels that already have their effective prices, bids descending and asks ascending.
+    pub fn from_levels(bids: Vec<Level>, asks: Vec<Level>, net_of_fees: bool) -> Self {
+        let mut book = Self {
+            net_of_fees,
+            ..Default::default()
+        };
+
+        book.bids = bids
+            .into_iter()
+            .sorted_by(|left, right| {
+                let (left, right) = (book.price_of(left), book.price_of(right));
+                left.partial_cmp(&right).unwrap().reverse()
+            })
+            .collect();
+
+        book.asks = asks
+            .into_iter()
+            .sorted_by(|left, right| {
+                let (left, right) = (book.price_of(left), book.price_of(right));
+                left.partial_cmp(&right).unwrap()
+            })
+            .collect();
+
+        book
+    }
+
+    /// The book of the inverse pair, BTC/ETH from the book of ETH/BTC.
+    ///
+    /// Buying the inverse pair sells this one, so its bids are the asks of this
+    /// book and its asks are the bids, at inverse prices, with amounts in the
+    /// quote currency of this book.
+    pub fn inverted(&self) -> Self {
+        let invert = |levels: &[Level]| {
+            levels
+                .iter()
+                .map(|level| {
+                    Level {
+                        price: 1.0 / level.price,
+                        amount: level.amount * level.price,
+                        orders: level
+                            .orders
+                            .iter()
+                            .map(|order| {
+                                Order {
+                                    amount: order.amount * level.price,
+                                    ..order.clone()
+                                }
+                            })
+                            .collect(),
+                        effective_price: 1.0 / level.effective_price,
+                        ..level.clone()
+                    }
+                })
+                .collect()
+        };
+
+        let mut book = Self {
+            bids: invert(&self.asks),
+            asks: invert(&self.bids),
+            net_of_fees: self.net_of_fees,
+            crossing: None,
+        };
+
+        // The inverse of a crossed book is crossed at the same venues, since the same time
+        if let Some(crossing) = &self.crossing {
+            book.detect_crossing(None, crossing.started_at);
+        }
+
+        book
+    }
+
+    /// The price levels are compared by, effective or raw.
+    ///
+    /// Every view of the book compares and reports prices on this basis.
+    pub fn price_of(&self, level: &Level) -> f64 {
+        if self.net_of_fees {
+            level.effective_price
+        } else {
+            level.price
+        }
+    }
+
+    /// Sets `crossing` if the book is crossed or locked.
+    ///
+    /// A crossing that continues `previous` keeps its start time, others start `now`.
+    pub fn detect_crossing(&mut self, previous: Option<&Crossing>, now: u64) {
+        self.crossing = None;
+
+        let (best_bid, best_ask) = match (self.bids.first(), self.asks.first()) {
+            (Some(best_bid), Some(best_ask)) => (best_bid, best_ask),
+            _ => return,
+        };
+
+        let (bid_price, ask_price) = (self.price_of(best_bid), self.price_of(best_ask));
+
+        let kind = if bid_price > ask_price {
+            CrossingKind::Crossed
+        } else if bid_price == ask_price {
+            CrossingKind::Locked
+        } else {
+            return;
+        };
+
+        let mut crossing = Crossing {
+            kind,
+            bid_exchange: best_bid.exchange.clone(),
+            ask_exchange: best_ask.exchange.clone(),
+            bid_price,
+            ask_price,
+            amount: self.crossed_amount(),
+            started_at: now,
+        };
+
+        if let Some(previous) = previous.filter(|previous| crossing.continues(previous)) {
+            crossing.started_at = previous.started_at;
+        }
+
+        self.crossing = Some(crossing);
+    }
+
+    /// Amount matched by walking the bids down and the asks up while they still cross.
+    fn crossed_amount(&self) -> f64 {
+        let mut bids = self
+            .bids
+            .iter()
+            .map(|level| (self.price_of(level), level.amount));
+        let mut asks = self
+            .asks
+            .iter()
+            .map(|level| (self.price_of(level), level.amount));
+        let (mut bid, mut ask) = (bids.next(), asks.next());
+        let mut amount = 0.0;
+
+        while let (Some((bid_price, bid_amount)), Some((ask_price, ask_amount))) = (bid, ask) {
+            if bid_price < ask_price {
+                break;
+            }
+
+            let matched = bid_amount.min(ask_amount);
+            amount += matched;
+
+            bid = if bid_amount > matched {
+                Some((bid_price, bid_amount - matched))
+            } else {
+                bids.next()
+            };
+            ask = if ask_amount > matched {
+                Some((ask_price, ask_amount - matched))
+            } else {
+                asks.next()
+            };
+        }
+
+        amount
+    }
+
+    /// The best `depth` levels of each side.
+    ///
+    /// The spread and the crossed and locked flags compare the prices the book
+    /// is ordered by, like `detect_crossing`.
+    pub fn summary(&self, depth: usize) -> Summary {
+        let spread = self.spread();
+
+        Summary {
+            spread,
+            crossed: spread < 0.0,
+            locked: spread == 0.0,
+            ..Summary::new(
+                self.bids.iter().take(depth).cloned().collect(),
+                self.asks.iter().take(depth).cloned().collect(),
+            )
+        }
+    }
+
+    /// The best `depth` prices of each side, with the levels at equal prices merged.
+    pub fn aggregated(&self, depth: usize) -> AggregatedSummary {
+        AggregatedSummary {
+            spread: self.spread(),
+            bids: aggregate_levels(&self.bids, depth, |level| self.price_of(level)),
+            asks: aggregate_levels(&self.asks, depth, |level| self.price_of(level)),
+        }
+    }
+
+    /// The best `depth` buckets of each side, bids rounded down and asks rounded up.
+    ///
+    /// The spread is still the one of the best levels, not of the buckets.
+    pub fn bucketed(&self, bucket_size: BucketSize, depth: usize) -> AggregatedSummary {
+        let width = match bucket_size {
+            BucketSize::Tick(width) => width,
+            BucketSize::BasisPoints(basis_points) => {
+                match (self.bids.first(), self.asks.first()) {
+                    (Some(bid), Some(ask)) => {
+                        (self.price_of(bid) + self.price_of(ask)) / 2.0 * basis_points / 10_000.0
+                    }
+                    _ => return AggregatedSummary::default(),
+                }
+            }
+        };
+
+        let round_down = |level: &Level| {
+            (self.price_of(level) / width + BUCKET_ROUNDING_TOLERANCE).floor() * width
+        };
+        let round_up = |level: &Level| {
+            (self.price_of(level) / width - BUCKET_ROUNDING_TOLERANCE).ceil() * width
+        };
+
+        AggregatedSummary {
+            spread: self.spread(),
+            bids: aggregate_levels(&self.bids, depth, round_down),
+            asks: aggregate_levels(&self.asks, depth, round_up),
+        }
+    }
+
+    /// Analytics of the book, with the imbalance over the best `imbalance_depth`
+    /// prices of each side, and the depth within each of `depth_basis_points`.
+    pub fn metrics(&self, imbalance_depth: usize, depth_basis_points: &[f64]) -> Metrics {
+        let bids = aggregate_levels(&self.bids, imbalance_depth, |level| self.price_of(level));
+        let asks = aggregate_levels(&self.asks, imbalance_depth, |level| self.price_of(level));
+
+        let (best_bid, best_ask) = match (bids.first(), asks.first()) {
+            (Some(best_bid), Some(best_ask)) => (best_bid, best_ask),
+            _ => return Metrics::default(),
+        };
+
+        // Aggregated prices are the ones the book is ordered by, like the spread's
+        let mid_price = (best_bid.price + best_ask.price) / 2.0;
+        let microprice = (best_bid.price * best_ask.amount + best_ask.price * best_bid.amount)
+            / (best_bid.amount + best_ask.amount);
+
+        let bid_amount: f64 = bids.iter().map(|level| level.amount).sum();
+        let ask_amount: f64 = asks.iter().map(|level| level.amount).sum();
+
+        let depth = depth_basis_points
+            .iter()
+            .map(|&basis_points| {
+                let distance = mid_price * basis_points / 10_000.0;
+
+                DepthWithin {
+                    basis_points,
+                    bid_amount: self
+                        .bids
+                        .iter()
+                        .take_while(|level| self.price_of(level) >= mid_price - distance)
+                        .map(|level| level.amount)
+                        .sum(),
+                    ask_amount: self
+                        .asks
+                        .iter()
+                        .take_while(|level| self.price_of(level) <= mid_price + distance)
+                        .map(|level| level.amount)
+                        .sum(),
+                }
+            })
+            .collect();
+
+        let exchanges = self
+            .bids
+            .iter()
+            .chain(&self.asks)
+            .map(|level| level.exchange.as_str())
+            .sorted_unstable()
+            .dedup()
+            .map(|exchange| {
+                let (bid_price, bid_amount) = self.best_of(&self.bids, exchange);
+                let (ask_price, ask_amount) = self.best_of(&self.asks, exchange);
+
+                ExchangeQuote {
+                    exchange: exchange.to_owned(),
+                    bid_price,
+                    bid_amount,
+                    ask_price,
+                    ask_amount,
+                }
+            })
+            .collect();
+
+        Metrics {
+            mid_price,
+            microprice,
+            spread: self.spread(),
+            imbalance: (bid_amount - ask_amount) / (bid_amount + ask_amount),
+            depth,
+            exchanges,
+        }
+    }
+
+    /// Best price of `exchange` in `levels`, and its amount there, zero if it has none.
+    fn best_of(&self, levels: &[Level], exchange: &str) -> (f64, f64) {
+        let mut levels = levels.iter().filter(|level| level.exchange == exchange);
+
+        let best = match levels.next() {
+            Some(best) => best,
+            None => return (0.0, 0.0),
+        };
+        let best_price = self.price_of(best);
+
+        // Level-3 books have a level per order, several at the best price
+        let amount = best.amount
+            + levels
+                .take_while(|level| self.price_of(level) == best_price)
+                .map(|level| level.amount)
+                .sum::<f64>();
+
+        (best_price, amount)
+    }
+
+    /// Middle of the best prices, none while a side is empty.
+    pub fn mid_price(&self) -> Option<f64> {
+        match (self.bids.first(), self.asks.first()) {
+            (Some(bid), Some(ask)) => Some((self.price_of(bid) + self.price_of(ask)) / 2.0),
+            _ => None,
+        }
+    }
+
+    fn spread(&self) -> f64 {
+        match (self.bids.first(), self.asks.first()) {
+            (Some(bid), Some(ask)) => self.price_of(ask) - self.price_of(bid),
+            _ => 0.0,
+        }
+    }
+}
+
+/// Merges consecutive levels with the same `bucket` price, keeping the amount of each venue.
+///
+/// `bucket` must keep the order of the levels, or equal prices won't be consecutive.
+fn aggregate_levels(
+    levels: &[Level],
+    depth: usize,
+    bucket: impl Fn(&Level) -> f64,
+) -> Vec<AggregatedLevel> {
+    levels
+        .iter()
+        .group_by(|&level| bucket(level))
+        .into_iter()
+        .take(depth)
+        .map(|(price, levels)| {
+            let mut venues: Vec<VenueAmount> = vec![];
+
+            for level in levels {
+                match venues
+                    .iter_mut()
+                    .find(|venue| venue.exchange == level.exchange)
+                {
+                    Some(venue) => {
+                        venue.amount += level.amount;
+                        venue.order_count += level.order_count;
+                    }
+                    None => {
+                        venues.push(VenueAmount {
+                            exchange: level.exchange.clone(),
+                            amount: level.amount,
+                            order_count: level.order_count,
+                        });
+                    }
+                }
+            }
+
+            venues.sort_by(|left, right| left.exchange.cmp(&right.exchange));
+
+            AggregatedLevel {
+                price,
+                amount: venues.iter().map(|venue| venue.amount).sum(),
+                venues,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fees::TakerFee, test_utils::level};
+
+    #[test]
+    fn test_aggregating_equal_prices_across_venues() {
+        let book = MergedBook {
+            bids: vec![
+                Level {
+                    order_count: 1,
+                    ..level("Bitstamp", 10.0, 1.0)
+                },
+                level("Binance", 10.0, 2.0),
+                Level {
+                    order_count: 1,
+                    ..level("Bitstamp", 10.0, 3.0)
+                },
+                level("Binance", 9.5, 4.0),
+            ],
+            asks: vec![level("Binance", 11.0, 1.0)],
+            ..Default::default()
+        };
+
+        let aggregated = book.aggregated(SUMMARY_DEPTH);
+        assert_eq!(aggregated.spread, 1.0);
+        assert_eq!(aggregated.bids.len(), 2);
+        assert_eq!(aggregated.asks.len(), 1);
+
+        let best_bid = &aggregated.bids[0];
+        assert_eq!(best_bid.price, 10.0);
+        assert_eq!(best_bid.amount, 6.0);
+        assert_eq!(
+            best_bid.venues,
+            [
+                VenueAmount {
+                    exchange: "Binance".into(),
+                    amount: 2.0,
+                    order_count: 0,
+                },
+                VenueAmount {
+                    exchange: "Bitstamp".into(),
+                    amount: 4.0,
+                    order_count: 2,
+                },
+            ]
+        );
+        assert_eq!(aggregated.bids[1].price, 9.5);
+    }
+
+    #[test]
+    fn test_bucketing_rounds_bids_down_and_asks_up() {
+        let book = MergedBook {
+            bids: vec![
+                level("Bitstamp", 1377.2, 1.0),
+                level("Binance", 1377.0, 2.0),
+                level("Binance", 1376.5, 4.0),
+            ],
+            asks: vec![
+                level("Binance", 1377.5, 1.0),
+                level("Bitstamp", 1377.8, 3.0),
+            ],
+            ..Default::default()
+        };
+
+        let bucketed = book.bucketed(BucketSize::Tick(0.5), 10);
+
+        let bids: Vec<_> = bucketed
+            .bids
+            .iter()
+            .map(|level| (level.price, level.amount))
+            .collect();
+        assert_eq!(bids, [(1377.0, 3.0), (1376.5, 4.0)]);
+
+        let asks: Vec<_> = bucketed
+            .asks
+            .iter()
+            .map(|level| (level.price, level.amount))
+            .collect();
+        assert_eq!(asks, [(1377.5, 1.0), (1378.0, 3.0)]);
+
+        assert_eq!(bucketed.bids[0].venues.len(), 2);
+        assert!((bucketed.spread - 0.3).abs() < 1e-9);
+
+        // 1 bp of a mid around 1377.35 is about 0.138
+        let bucketed = book.bucketed(BucketSize::BasisPoints(1.0), 1);
+        assert_eq!(bucketed.bids.len(), 1);
+        assert!(bucketed.bids[0].price <= 1377.2 && bucketed.bids[0].price > 1377.2 - 0.14);
+        assert_eq!(bucketed.bids[0].amount, 1.0);
+    }
+
+    #[test]
+    fn test_metrics_of_the_merged_book() {
+        let book = MergedBook {
+            bids: vec![
+                level("Bitstamp", 100.0, 1.0),
+                level("Bitstamp", 100.0, 2.0),
+                level("Binance", 99.0, 3.0),
+                level("Binance", 90.0, 10.0),
+            ],
+            asks: vec![level("Binance", 102.0, 1.0), level("Bitstamp", 103.0, 1.0)],
+            ..Default::default()
+        };
+
+        let metrics = book.metrics(2, &[100.0, 1000.0]);
+        assert_eq!(metrics.mid_price, 101.0);
+        assert_eq!(metrics.spread, 2.0);
+
+        // Three at the best bid and one at the best ask, closer to the ask
+        assert_eq!(metrics.microprice, (100.0 * 1.0 + 102.0 * 3.0) / 4.0);
+        assert_eq!(metrics.imbalance, (6.0 - 2.0) / 8.0);
+
+        // 1% of the mid price is 1.01, 10% is 10.1
+        let depth: Vec<_> = metrics
+            .depth
+            .iter()
+            .map(|depth| (depth.bid_amount, depth.ask_amount))
+            .collect();
+        assert_eq!(depth, [(3.0, 1.0), (6.0, 2.0)]);
+
+        assert_eq!(
+            metrics.exchanges,
+            [
+                ExchangeQuote {
+                    exchange: "Binance".into(),
+                    bid_price: 99.0,
+                    bid_amount: 3.0,
+                    ask_price: 102.0,
+                    ask_amount: 1.0,
+                },
+                ExchangeQuote {
+                    exchange: "Bitstamp".into(),
+                    bid_price: 100.0,
+                    bid_amount: 3.0,
+                    ask_price: 103.0,
+                    ask_amount: 1.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metrics_net_of_fees_use_effective_prices() {
+        let book = MergedBook::from_levels(
+            vec![
+                Level {
+                    effective_price: 99.0,
+                    ..level("Bitstamp", 101.0, 1.0)
+                },
+                Level {
+                    effective_price: 99.5,
+                    ..level("Binance", 99.6, 1.0)
+                },
+            ],
+            vec![Level {
+                effective_price: 100.5,
+                ..level("Binance", 100.4, 1.0)
+            }],
+            true,
+        );
+
+        let metrics = book.metrics(1, &[]);
+        assert_eq!(Some(metrics.mid_price), book.mid_price());
+        assert_eq!(metrics.mid_price, 100.0);
+        assert_eq!(metrics.spread, 1.0);
+        assert_eq!(metrics.microprice, 100.0);
+    }
+
+    #[test]
+    fn test_bucket_size_parsing() {
+        assert_eq!("0.5".parse::<BucketSize>().unwrap(), BucketSize::Tick(0.5));
+        assert_eq!(
+            "2.5bp".parse::<BucketSize>().unwrap(),
+            BucketSize::BasisPoints(2.5)
+        );
+        assert!("0".parse::<BucketSize>().is_err());
+        assert!("bp".parse::<BucketSize>().is_err());
+    }
+
+    #[test]
+    fn test_detecting_crossed_and_locked_books() {
+        let mut book = MergedBook {
+            bids: vec![
+                level("Binance", 101.0, 1.0),
+                level("Binance", 100.5, 2.0),
+                level("Bitstamp", 99.0, 5.0),
+            ],
+            asks: vec![
+                level("Bitstamp", 100.0, 1.5),
+                level("Bitstamp", 100.5, 1.0),
+                level("Binance", 102.0, 5.0),
+            ],
+            ..Default::default()
+        };
+
+        book.detect_crossing(None, 10);
+        let crossing = book.crossing.clone().unwrap();
+        assert_eq!(crossing.kind, CrossingKind::Crossed);
+        assert_eq!(crossing.bid_exchange, "Binance");
+        assert_eq!(crossing.ask_exchange, "Bitstamp");
+        // 1 at 101 against 100, 0.5 at 100.5 against 100, 1 at 100.5 against 100.5
+        assert_eq!(crossing.amount, 2.5);
+        assert_eq!(crossing.started_at, 10);
+
+        // Same venues, the crossing continues
+        book.bids.remove(0);
+        book.detect_crossing(Some(&crossing), 20);
+        let crossing = book.crossing.clone().unwrap();
+        assert_eq!(crossing.started_at, 10);
+
+        book.asks.remove(0);
+        book.detect_crossing(Some(&crossing), 30);
+        let locked = book.crossing.clone().unwrap();
+        assert_eq!(locked.kind, CrossingKind::Locked);
+        assert_eq!(locked.started_at, 30);
+
+        book.bids.remove(0);
+        book.detect_crossing(Some(&locked), 40);
+        assert_eq!(book.crossing, None);
+    }
+
+    #[test]
+    fn test_inverting_a_book() {
+        let mut book = MergedBook {
+            bids: vec![
+                level("Binance", 0.03125, 2.0),
+                level("Bitstamp", 0.015625, 1.0),
+            ],
+            asks: vec![level("Bitstamp", 0.0625, 1.0), level("Binance", 0.125, 2.0)],
+            ..Default::default()
+        };
+        book.asks[0].effective_price = 0.125;
+
+        let inverted = book.inverted();
+
+        // Selling BTC for ETH buys ETH at the asks, the best one gives the most ETH
+        let bids: Vec<_> = inverted
+            .bids
+            .iter()
+            .map(|level| (level.exchange.as_str(), level.price, level.amount))
+            .collect();
+        assert_eq!(bids, [("Bitstamp", 16.0, 0.0625), ("Binance", 8.0, 0.25)]);
+        assert_eq!(inverted.bids[0].effective_price, 8.0);
+
+        let asks: Vec<_> = inverted.asks.iter().map(|level| level.price).collect();
+        assert_eq!(asks, [32.0, 64.0]);
+        assert_eq!(inverted.asks[0].amount, 0.0625);
+        assert_eq!(inverted.crossing, None);
+
+        // Crossed books stay crossed, between the same exchanges the other way around
+        book.bids[0].price = 0.1;
+        book.detect_crossing(None, 10);
+
+        let crossing = book.inverted().crossing.unwrap();
+        assert_eq!(crossing.bid_exchange, "Bitstamp");
+        assert_eq!(crossing.ask_exchange, "Binance");
+        assert_eq!(crossing.started_at, 10);
+    }
+
+    #[test]
+    fn test_merging_net_of_fees() {
+        let binance = Summary {
+            bids: vec![level("Binance", 100.0, 1.0)],
+            asks: vec![level("Binance", 101.0, 1.0)],
+            ..Default::default()
+        };
+        let bitstamp = Summary {
+            bids: vec![level("Bitstamp", 100.05, 1.0)],
+            asks: vec![level("Bitstamp", 100.95, 1.0)],
+            ..Default::default()
+        };
+        let fees: Vec<TakerFee> = ["Binance=1", "Bitstamp=10"]
+            .iter()
+            .map(|fee| fee.parse().unwrap())
+            .collect();
+        let currency_pair = "ETHBTC".parse().unwrap();
+
+        // Bitstamp's prices are better, but not after its higher fee
+        let raw = MergedBook::merge(
+            [&binance, &bitstamp].into_iter(),
+            &FeeSchedule::new(&fees, &currency_pair, false),
+        );
+        assert_eq!(raw.bids[0].exchange, "Bitstamp");
+        assert_eq!(raw.asks[0].exchange, "Bitstamp");
+        assert!(raw.bids[0].effective_price < raw.bids[0].price);
+
+        let net = MergedBook::merge(
+            [&binance, &bitstamp].into_iter(),
+            &FeeSchedule::new(&fees, &currency_pair, true),
+        );
+        assert_eq!(net.bids[0].exchange, "Binance");
+        assert_eq!(net.asks[0].exchange, "Binance");
+        assert_eq!(net.bids[1].price, 100.05);
+        assert!((net.aggregated(SUMMARY_DEPTH).spread - (101.0101 - 99.99)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_summary_flags_follow_the_book_order() {
+        let mut book = MergedBook::from_levels(
+            vec![
+                Level {
+                    effective_price: 99.0,
+                    ..level("Bitstamp", 101.5, 1.0)
+                },
+                Level {
+                    effective_price: 99.9,
+                    ..level("Binance", 100.0, 1.0)
+                },
+            ],
+            vec![Level {
+                effective_price: 101.1,
+                ..level("Binance", 101.0, 1.0)
+            }],
+            true,
+        );
+        book.detect_crossing(None, 10);
+
+        // Bitstamp's raw bid is above the ask, but not once its fee is paid
+        let summary = book.summary(SUMMARY_DEPTH);
+        assert_eq!(summary.bids[0].exchange, "Binance");
+        assert_eq!(book.crossing, None);
+        assert!(!summary.crossed);
+        assert!(!summary.locked);
+        assert!((summary.spread - 1.2).abs() < 1e-9);
+    }
+}
@@ -1,20 +1,88 @@
 // Re-export proto definitions
 pub use orderbook::{
+    bucketed_book_request::BucketSize as RequestedBucketSize,
+    crossing_event::Kind as CrossingEventKind,
+    fill_request::{Side as FillSide, Size as RequestedFillSize},
+    index_constituent::Status as ConstituentStatus,
+    orderbook_aggregator_client::OrderbookAggregatorClient,
     orderbook_aggregator_server::{
         OrderbookAggregator, OrderbookAggregatorServer as OrderbookAggregatorService,
     },
-    Empty, Level, Summary,
+    trade::Side as TradeSide,
+    AggregatedLevel, AggregatedSummary, BucketedBookRequest, CrossingEvent, DepthWithin, Empty,
+    ExchangeQuote, FillRequest, FillSimulation, Index, IndexConstituent, IndexRequest, Leg, Level,
+    Metrics, MetricsRequest, Order, Summary, Trade, VenueAmount, VenueFill,
 };
 
 mod orderbook {
     tonic::include_proto!("orderbook");
 }
 
+/// Metadata key a client can set to the currency pair it wants to receive.
+///
+/// Servers not streaming that pair refuse the request, requests without it
+/// get the server's first pair.
+pub const CURRENCY_PAIR_METADATA_KEY: &str = "x-currency-pair";
+
+/// Encoded descriptors of the proto definitions, used by the reflection service.
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("orderbook_descriptor");
+
 impl Summary {
+    /// Summarizes the best levels of each side, at least one per side.
     pub fn new(bids: Vec<Level>, asks: Vec<Level>) -> Self {
-        assert!(bids.len() == 10);
-        assert!(asks.len() == 10);
-        let spread = asks[0].price - bids[0].price;
-        Self { bids, asks, spread }
+        assert!(!bids.is_empty());
+        assert!(!asks.is_empty());
+        let (best_bid, best_ask) = (bids[0].price, asks[0].price);
+        Self {
+            spread: best_ask - best_bid,
+            effective_spread: asks[0].effective_price - bids[0].effective_price,
+            crossed: best_bid > best_ask,
+            locked: best_bid == best_ask,
+            bids,
+            asks,
+        }
+    }
+}
+
+impl Trade {
+    /// The same trade as a trade of the inverse pair.
+    ///
+    /// Buying the base is selling the quote, so the aggressor switches sides.
+    pub fn inverted(self) -> Self {
+        let aggressor_side = match self.aggressor_side() {
+            TradeSide::Buy => TradeSide::Sell,
+            TradeSide::Sell => TradeSide::Buy,
+        };
+
+        Self {
+            price: 1.0 / self.price,
+            amount: self.amount * self.price,
+            aggressor_side: aggressor_side as i32,
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inverting_a_trade() {
+        let trade = Trade {
+            exchange: "Binance".into(),
+            id: "1".into(),
+            price: 0.25,
+            amount: 2.0,
+            aggressor_side: TradeSide::Buy as i32,
+            traded_at: 1,
+            received_at: 2,
+        };
+
+        let inverted = trade.clone().inverted();
+        assert_eq!(inverted.price, 4.0);
+        assert_eq!(inverted.amount, 0.5);
+        assert_eq!(inverted.aggressor_side(), TradeSide::Sell);
+        assert_eq!(inverted.inverted(), trade);
     }
 }
@@ -0,0 +1,374 @@
+//! Books of a currency against equivalent quote currencies, like USDT and USDC, merged into one.
+
+use std::str::FromStr;
+
+use futures::{
+    future,
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+
+use crate::{
+    currencies::CurrencyPair,
+    fees::{FeeSchedule, TakerFee},
+    instruments::PairCatalog,
+    merged_book::{MergedBook, SUMMARY_DEPTH},
+    order_book::{Leg, Level},
+    recorder, Error, Result,
+};
+
+/// A quote currency taken as equivalent to the quote of the served pair.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EquivalentQuote {
+    pub quote: String,
+    pub rate: QuoteRate,
+}
+
+/// Rate converting prices of an equivalent quote to the served quote.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuoteRate {
+    Fixed(f64),
+    /// The mid price of the book between both quotes.
+    Live,
+}
+
+impl FromStr for EquivalentQuote {
+    type Err = Error;
+
+    /// Parses "QUOTE", "QUOTE=RATE" or "QUOTE=live", like "USDC", "PAX=0.999" or "USDC=live".
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let bad_format = || Error::EquivalentQuoteBadFormat(text.to_owned());
+
+        let (quote, rate) = match text.split_once('=') {
+            Some((quote, "live")) => (quote, QuoteRate::Live),
+            Some((quote, rate)) => {
+                match rate.parse::<f64>() {
+                    Ok(rate) if rate.is_finite() && rate > 0.0 => (quote, QuoteRate::Fixed(rate)),
+                    _ => return Err(bad_format()),
+                }
+            }
+            None => (text, QuoteRate::Fixed(1.0)),
+        };
+
+        if quote.is_empty() || !quote.chars().all(|char| char.is_ascii_alphabetic()) {
+            return Err(bad_format());
+        }
+
+        Ok(Self {
+            quote: quote.to_ascii_uppercase(),
+            rate,
+        })
+    }
+}
+
+/// How prices of a merged pair are converted to the served quote.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Conversion {
+    Fixed(f64),
+    /// At the mid price of the book of `currency_pair`, or its inverse.
+    Live {
+        currency_pair: CurrencyPair,
+        inverted: bool,
+    },
+}
+
+/// A pair whose book is merged into the served one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuoteMember {
+    pub currency_pair: CurrencyPair,
+    pub fees: FeeSchedule,
+    pub conversion: Conversion,
+}
+
+/// Resolves the pairs merged into the book of `currency_pair`.
+///
+/// The pair itself is merged if the exchanges trade it, along with its base
+/// against each of `equivalents`. Every pair must be supported by `catalog`,
+/// including the ones live rates are read from.
+pub fn resolve_members(
+    currency_pair: &CurrencyPair,
+    equivalents: &[EquivalentQuote],
+    catalog: &PairCatalog,
+    taker_fees: &[TakerFee],
+    net_of_fees: bool,
+) -> Result<Vec<QuoteMember>> {
+    let supported = |pair: CurrencyPair| {
+        if catalog.supports(&pair) {
+            Ok(pair)
+        } else {
+            Err(Error::UnsupportedCurrencyPair(pair.as_str().to_owned()))
+        }
+    };
+
+    let mut members = vec![];
+
+    if catalog.supports(currency_pair) {
+        members.push(QuoteMember {
+            currency_pair: currency_pair.clone(),
+            fees: FeeSchedule::new(taker_fees, currency_pair, net_of_fees),
+            conversion: Conversion::Fixed(1.0),
+        });
+    }
+
+    for equivalent in equivalents {
+        let member_pair = supported(CurrencyPair::new(currency_pair.base(), &equivalent.quote))?;
+
+        let conversion = match equivalent.rate {
+            QuoteRate::Fixed(rate) => Conversion::Fixed(rate),
+            QuoteRate::Live => {
+                let direct = CurrencyPair::new(&equivalent.quote, currency_pair.quote());
+
+                if catalog.supports(&direct) {
+                    Conversion::Live {
+                        currency_pair: direct,
+                        inverted: false,
+                    }
+                } else {
+                    Conversion::Live {
+                        currency_pair: supported(direct.inverse())?,
+                        inverted: true,
+                    }
+                }
+            }
+        };
+
+        members.push(QuoteMember {
+            fees: FeeSchedule::new(taker_fees, &member_pair, net_of_fees),
+            currency_pair: member_pair,
+            conversion,
+        });
+    }
+
+    if members.is_empty() {
+        return Err(Error::UnsupportedCurrencyPair(
+            currency_pair.as_str().to_owned(),
+        ));
+    }
+
+    Ok(members)
+}
+
+/// Mid price of `book`, or its inverse, none while a side is empty.
+pub fn mid_price(book: &MergedBook, inverted: bool) -> Option<f64> {
+    let mid_price = book.mid_price()?;
+
+    if inverted {
+        Some(1.0 / mid_price)
+    } else {
+        Some(mid_price)
+    }
+}
+
+/// Books of a merged pair, and the rates converting them to the served quote.
+pub struct MemberBooks {
+    pub currency_pair: CurrencyPair,
+    pub books: BoxStream<'static, Result<MergedBook>>,
+    pub rates: BoxStream<'static, Result<f64>>,
+}
+
+enum Update {
+    Book(usize, MergedBook),
+    Rate(usize, f64),
+}
+
+/// Merges the converted books of every member each time one of them changes.
+///
+/// Members are left out until they have both a book and a rate, and nothing
+/// is sent while that leaves too few levels to fill a summary. Every level
+/// keeps the pair and price it was quoted in as its only leg.
+pub fn equivalent_quote_books(members: Vec<MemberBooks>) -> impl Stream<Item = Result<MergedBook>> {
+    let mut currency_pairs = vec![];
+    let mut updates = vec![];
+
+    for (
+        member,
+        MemberBooks {
+            currency_pair,
+            books,
+            rates,
+        },
+    ) in members.into_iter().enumerate()
+    {
+        currency_pairs.push(currency_pair);
+        updates.push(
+            books
+                .map(move |book| book.map(|book| Update::Book(member, book)))
+                .boxed(),
+        );
+        updates.push(
+            rates
+                .map(move |rate| rate.map(|rate| Update::Rate(member, rate)))
+                .boxed(),
+        );
+    }
+
+    let latest: Vec<(Option<MergedBook>, Option<f64>)> = vec![(None, None); currency_pairs.len()];
+
+    stream::select_all(updates)
+        .scan((latest, None), move |(latest, last_crossing), update| {
+            match update {
+                Ok(Update::Book(member, book)) => latest[member].0 = Some(book),
+                Ok(Update::Rate(member, rate)) => latest[member].1 = Some(rate),
+                Err(err) => return future::ready(Some(Some(Err(err)))),
+            }
+
+            let mut bids = vec![];
+            let mut asks = vec![];
+            let mut net_of_fees = false;
+
+            for ((book, rate), currency_pair) in latest.iter().zip(&currency_pairs) {
+                if let (Some(book), Some(rate)) = (book, rate) {
+                    let currency_pair = currency_pair.as_str();
+                    bids.extend(convert_levels(&book.bids, currency_pair, *rate));
+                    asks.extend(convert_levels(&book.asks, currency_pair, *rate));
+                    net_of_fees = book.net_of_fees;
+                }
+            }
+
+            if bids.len() < SUMMARY_DEPTH || asks.len() < SUMMARY_DEPTH {
+                return future::ready(Some(None));
+            }
+
+            let mut book = MergedBook::from_levels(bids, asks, net_of_fees);
+            book.detect_crossing(last_crossing.as_ref(), recorder::unix_timestamp_millis());
+            *last_crossing = book.crossing.clone();
+
+            future::ready(Some(Some(Ok(book))))
+        })
+        .filter_map(future::ready)
+}
+
+/// Levels priced in the served quote, with the level as quoted as their leg.
+fn convert_levels<'a>(
+    levels: &'a [Level],
+    currency_pair: &'a str,
+    rate: f64,
+) -> impl Iterator<Item = Level> + 'a {
+    levels.iter().map(move |level| {
+        Level {
+            price: level.price * rate,
+            effective_price: level.effective_price * rate,
+            legs: vec![Leg {
+                currency_pair: currency_pair.to_owned(),
+                exchange: level.exchange.clone(),
+                price: level.price,
+                amount: level.amount,
+            }],
+            ..level.clone()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::level;
+
+    #[test]
+    fn test_equivalent_quote_parsing() {
+        assert_eq!(
+            "usdc=live".parse::<EquivalentQuote>().unwrap(),
+            EquivalentQuote {
+                quote: "USDC".into(),
+                rate: QuoteRate::Live,
+            }
+        );
+        assert_eq!(
+            "PAX".parse::<EquivalentQuote>().unwrap().rate,
+            QuoteRate::Fixed(1.0)
+        );
+        assert!("PAX=0".parse::<EquivalentQuote>().is_err());
+        assert!("=1".parse::<EquivalentQuote>().is_err());
+    }
+
+    #[test]
+    fn test_resolving_members_with_live_rates() {
+        let equivalents = ["USDC=live".parse().unwrap(), "PAX=0.999".parse().unwrap()];
+        let catalog = PairCatalog::builtin();
+
+        let served_pair = CurrencyPair::new("ETH", "USDT");
+        let members = resolve_members(&served_pair, &equivalents, &catalog, &[], false).unwrap();
+
+        let members: Vec<_> = members
+            .iter()
+            .map(|member| (member.currency_pair.as_str(), member.conversion.clone()))
+            .collect();
+
+        assert_eq!(
+            members,
+            [
+                ("ETHUSDT", Conversion::Fixed(1.0)),
+                (
+                    "ETHUSDC",
+                    Conversion::Live {
+                        currency_pair: CurrencyPair::new("USDC", "USDT"),
+                        inverted: false,
+                    }
+                ),
+                ("ETHPAX", Conversion::Fixed(0.999)),
+            ]
+        );
+
+        // ETHUSD isn't traded, and neither is USDC against USD
+        assert!(matches!(
+            resolve_members(&"ETHUSD".parse().unwrap(), &equivalents, &catalog, &[], false),
+            Err(Error::UnsupportedCurrencyPair(pair)) if pair == "USDUSDC"
+        ));
+    }
+
+    #[test]
+    fn test_no_mid_price_without_both_sides() {
+        let book = MergedBook {
+            bids: vec![level("Binance", 1.9, 1.0)],
+            asks: vec![level("Binance", 2.1, 1.0)],
+            ..Default::default()
+        };
+        assert_eq!(mid_price(&book, false), Some(2.0));
+        assert_eq!(mid_price(&book, true), Some(0.5));
+
+        let one_sided = MergedBook {
+            asks: vec![],
+            ..book
+        };
+        assert_eq!(mid_price(&one_sided, false), None);
+    }
+
+    #[tokio::test]
+    async fn test_books_of_equivalent_quotes_are_merged() {
+        let book = |bid: f64, ask: f64, exchange: &str| {
+            MergedBook {
+                bids: (0..10)
+                    .map(|step| level(exchange, bid - step as f64, 1.0))
+                    .collect(),
+                asks: (0..10)
+                    .map(|step| level(exchange, ask + step as f64, 1.0))
+                    .collect(),
+                ..Default::default()
+            }
+        };
+
+        let members = vec![
+            MemberBooks {
+                currency_pair: CurrencyPair::new("ETH", "USDT"),
+                books: stream::iter([Ok(book(1000.0, 1001.0, "Binance"))]).boxed(),
+                rates: stream::iter([Ok(1.0)]).boxed(),
+            },
+            MemberBooks {
+                currency_pair: CurrencyPair::new("ETH", "USDC"),
+                books: stream::iter([Ok(book(999.0, 1000.0, "Bitstamp"))]).boxed(),
+                rates: stream::iter([Ok(1.002)]).boxed(),
+            },
+        ];
+
+        let books: Vec<_> = equivalent_quote_books(members).collect().await;
+        let last = books.last().unwrap().as_ref().unwrap();
+
+        // 999 USDC are worth 1000.998 USDT, better than Binance's bid
+        assert_eq!(last.bids.len(), 20);
+        assert_eq!(last.bids[0].exchange, "Bitstamp");
+        assert!((last.bids[0].price - 999.0 * 1.002).abs() < 1e-9);
+        assert_eq!(last.bids[0].legs[0].currency_pair, "ETHUSDC");
+        assert_eq!(last.bids[0].legs[0].price, 999.0);
+        assert_eq!(last.asks[0].exchange, "Binance");
+    }
+}
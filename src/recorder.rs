@@ -0,0 +1,346 @@
+//! Recording of the raw exchange messages, to reproduce merged books later.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Messages written to a file before rotating to the next one.
+const MESSAGES_PER_FILE: usize = 100_000;
+/// Maximum time a file is kept open before rotating to the next one.
+const FILE_ROTATION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Messages waiting to be written, further ones are dropped until the disk catches up.
+const RECORDING_QUEUE_CAPACITY: usize = 10_000;
+
+/// A raw websocket text message, as received from an exchange.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecordedMessage {
+    /// Milliseconds since the Unix epoch at the moment the message was received.
+    pub received_at: u64,
+    pub exchange: String,
+    pub message: String,
+}
+
+/// What the writer thread is sent.
+#[derive(Debug)]
+enum Recording {
+    Message(RecordedMessage),
+    /// Finish the current file and stop writing.
+    Stop,
+}
+
+/// Tees exchange messages to gzip-compressed JSON lines files.
+///
+/// Files are written by a dedicated thread, so recording never blocks the
+/// streams being tapped: when the disk can't keep up, messages are dropped
+/// and counted. Cloning is cheap, all clones write to the same files.
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    sender: Option<SyncSender<Recording>>,
+    writer: Arc<Mutex<Option<JoinHandle<()>>>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Recorder {
+    /// Starts recording to files inside of `dir`, creating it if needed.
+    pub fn start(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let (sender, receiver) = mpsc::sync_channel(RECORDING_QUEUE_CAPACITY);
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        let writer = {
+            let dropped = dropped.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = write_recordings(&dir, receiver, FILE_ROTATION_INTERVAL, &dropped)
+                {
+                    log::error!("Recorder error: {err}.");
+                }
+            })
+        };
+
+        Ok(Self {
+            sender: Some(sender),
+            writer: Arc::new(Mutex::new(Some(writer))),
+            dropped,
+        })
+    }
+
+    /// A recorder that doesn't record anything.
+    pub fn disabled() -> Self {
+        Self {
+            sender: None,
+            writer: Arc::default(),
+            dropped: Arc::default(),
+        }
+    }
+
+    /// Finishes the file being written, so it ends with a valid gzip trailer.
+    ///
+    /// Messages tapped afterwards aren't recorded.
+    pub async fn stop(&self) {
+        let writer = self.writer.lock().unwrap().take();
+
+        if let (Some(sender), Some(writer)) = (self.sender.clone(), writer) {
+            // Waits for room in the queue, and for the file to be finished
+            let _ = tokio::task::spawn_blocking(move || {
+                let _ = sender.send(Recording::Stop);
+                let _ = writer.join();
+            })
+            .await;
+        }
+    }
+
+    /// Wraps a stream of raw messages, recording every message that passes through.
+    pub fn tap(
+        &self,
+        exchange: &'static str,
+        stream: impl Stream<Item = Result<String>>,
+    ) -> impl Stream<Item = Result<String>> {
+        let sender = self.sender.clone();
+        let dropped = self.dropped.clone();
+
+        stream.inspect(move |message| {
+            if let (Some(sender), Ok(message)) = (&sender, message) {
+                let recorded = RecordedMessage {
+                    received_at: unix_timestamp_millis(),
+                    exchange: exchange.into(),
+                    message: message.clone(),
+                };
+
+                match sender.try_send(Recording::Message(recorded)) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    // Stopped, or after an IO error which was already reported
+                    Err(TrySendError::Disconnected(_)) => {}
+                }
+            }
+        })
+    }
+}
+
+/// Writes received messages until stopped or every sender is dropped, rotating
+/// files once they hold `MESSAGES_PER_FILE` messages or every `rotation_interval`.
+///
+/// Files without any message aren't rotated, so an idle recorder doesn't fill
+/// `dir` with empty files.
+fn write_recordings(
+    dir: &Path,
+    receiver: Receiver<Recording>,
+    rotation_interval: Duration,
+    dropped: &AtomicUsize,
+) -> Result<()> {
+    let started_at = unix_timestamp_millis();
+
+    for file_index in 0.. {
+        let path = dir.join(format!("keyrocky-{started_at}-{file_index:05}.jsonl.gz"));
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = GzEncoder::new(file, Compression::default());
+
+        let mut rotate_at = Instant::now() + rotation_interval;
+        let mut written = 0;
+
+        let stopped = loop {
+            if written == MESSAGES_PER_FILE {
+                break false;
+            }
+
+            let timeout = rotate_at.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(Recording::Message(message)) => {
+                    serde_json::to_writer(&mut encoder, &message)?;
+                    encoder.write_all(b"\n")?;
+                    written += 1;
+                }
+                Ok(Recording::Stop) | Err(RecvTimeoutError::Disconnected) => break true,
+                Err(RecvTimeoutError::Timeout) if written == 0 => {
+                    rotate_at = Instant::now() + rotation_interval;
+                }
+                Err(RecvTimeoutError::Timeout) => break false,
+            }
+        };
+
+        encoder.finish()?.flush()?;
+
+        let dropped = dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!("Recorder dropped {dropped} messages, the disk couldn't keep up.");
+        }
+
+        if stopped {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads every message recorded in `paths`, sorted by receive timestamp.
+///
+/// Files cut short, like the last one of a recorder that was killed, are read
+/// up to the last complete message.
+pub fn read_recordings(paths: &[PathBuf]) -> Result<Vec<RecordedMessage>> {
+    let mut messages = vec![];
+
+    for path in paths {
+        let reader = BufReader::new(MultiGzDecoder::new(File::open(path)?));
+
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            };
+
+            match serde_json::from_str(&line) {
+                Ok(message) => messages.push(message),
+                // A truncated last line
+                Err(err) if err.is_eof() => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    // Stable sort, messages received at the same millisecond keep their order
+    messages.sort_by_key(|message: &RecordedMessage| message.received_at);
+
+    Ok(messages)
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn unix_timestamp_millis() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is set before the Unix epoch");
+
+    since_epoch.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("keyrocky-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Reads a whole file, failing unless it ends with a valid gzip trailer.
+    fn read_complete(path: &Path) -> String {
+        io::read_to_string(MultiGzDecoder::new(File::open(path).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_recordings_round_trip() {
+        let dir = temp_dir("recorder");
+
+        let recorded = |received_at, exchange: &str| {
+            RecordedMessage {
+                received_at,
+                exchange: exchange.into(),
+                message: format!("{{\"from\":\"{exchange}\"}}"),
+            }
+        };
+
+        let expected = vec![recorded(1, "Binance"), recorded(2, "Bitstamp")];
+
+        let (sender, receiver) = mpsc::sync_channel(RECORDING_QUEUE_CAPACITY);
+        for message in expected.iter().rev() {
+            sender.send(Recording::Message(message.clone())).unwrap();
+        }
+        drop(sender);
+
+        write_recordings(&dir, receiver, FILE_ROTATION_INTERVAL, &AtomicUsize::new(0)).unwrap();
+
+        let files: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        let result = read_recordings(&files).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result, expected);
+    }
+
+    #[tokio::test]
+    async fn test_stopping_finishes_the_last_file() {
+        let dir = temp_dir("recorder-stop");
+        let recorder = Recorder::start(&dir).unwrap();
+
+        let messages = futures::stream::iter(["{}".to_owned()].map(Ok));
+        let tapped: Vec<_> = recorder.tap("Binance", messages).collect().await;
+        assert_eq!(tapped.len(), 1);
+
+        recorder.stop().await;
+
+        let files: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        let contents = read_complete(&files[0]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(contents.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_files_rotate_without_new_messages() {
+        let dir = temp_dir("recorder-rotation");
+        let (sender, receiver) = mpsc::sync_channel(RECORDING_QUEUE_CAPACITY);
+
+        let writer = {
+            let dir = dir.clone();
+            std::thread::spawn(move || {
+                write_recordings(
+                    &dir,
+                    receiver,
+                    Duration::from_millis(50),
+                    &AtomicUsize::new(0),
+                )
+            })
+        };
+
+        let message = RecordedMessage {
+            received_at: 1,
+            exchange: "Binance".into(),
+            message: "{}".into(),
+        };
+        sender.send(Recording::Message(message)).unwrap();
+
+        // The first file is finished while nothing else is received, the next
+        // one stays open until it gets a message
+        std::thread::sleep(Duration::from_millis(200));
+        let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        let first = read_complete(&files[0]);
+
+        sender.send(Recording::Stop).unwrap();
+        writer.join().unwrap().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(first.lines().count(), 1);
+    }
+}
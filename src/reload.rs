@@ -0,0 +1,48 @@
+//! Requests to reload the config file, on SIGHUP or when the file changes.
+
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use futures::Stream;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::Result;
+
+/// Interval between checks of the modification time of the config file.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Yields whenever the config at `path` should be reloaded: on SIGHUP, and
+/// when its modification time changes.
+pub fn reload_requests(path: &Path) -> Result<impl Stream<Item = ()>> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    let path = path.to_owned();
+    let mut modified = modified_at(&path);
+
+    Ok(async_stream::stream! {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+        loop {
+            let requested = tokio::select! {
+                _ = hangups.recv() => true,
+                _ = interval.tick() => {
+                    let previously_modified = modified;
+                    modified = modified_at(&path);
+                    modified != previously_modified
+                }
+            };
+
+            if requested {
+                yield ();
+            }
+        }
+    })
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
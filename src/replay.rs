@@ -0,0 +1,149 @@
+//! Replay of recorded exchange messages through the parsing pipeline.
+
+use std::{str::FromStr, time::Duration};
+
+use futures::Stream;
+use tokio::time::Instant;
+
+use crate::{
+    currencies::CurrencyPair,
+    exchanges::{BinanceExchange, BitstampExchange, ConnectToOrderBook},
+    feeds::FeedMonitor,
+    order_book::Summary,
+    recorder::RecordedMessage,
+    Error, Result,
+};
+
+/// Pacing of a replay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// Original pacing, sped up (or slowed down) by this factor.
+    Multiplier(f64),
+    /// As fast as possible.
+    Unlimited,
+}
+
+impl FromStr for ReplaySpeed {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if text.eq_ignore_ascii_case("max") {
+            return Ok(Self::Unlimited);
+        }
+
+        match text.parse::<f64>() {
+            Ok(multiplier) if multiplier.is_finite() && multiplier > 0.0 => {
+                Ok(Self::Multiplier(multiplier))
+            }
+            _ => Err(Error::ReplaySpeedBadFormat(text.to_owned())),
+        }
+    }
+}
+
+/// Parses recorded messages in order, respecting the recorded pacing at `speed`.
+///
+/// Messages are expected sorted by timestamp, and to be books of
+/// `currency_pair`. Every valid summary is recorded in `feed_monitor`, as the
+/// live exchange streams do.
+pub fn replay_summaries(
+    messages: Vec<RecordedMessage>,
+    speed: ReplaySpeed,
+    currency_pair: &CurrencyPair,
+    feed_monitor: FeedMonitor,
+) -> impl Stream<Item = Result<Summary>> {
+    let currency_pair = currency_pair.as_str().to_owned();
+
+    async_stream::stream! {
+        let started_at = Instant::now();
+        let first_timestamp = messages.first().map(|message| message.received_at);
+
+        for message in messages {
+            if let (ReplaySpeed::Multiplier(multiplier), Some(first_timestamp)) = (speed, first_timestamp) {
+                let offset = message.received_at.saturating_sub(first_timestamp);
+                let offset = Duration::from_millis(offset).div_f64(multiplier);
+                tokio::time::sleep_until(started_at + offset).await;
+            }
+
+            let summary = match message.exchange.as_str() {
+                BinanceExchange::EXCHANGE_NAME => BinanceExchange::try_parse_summary(message.message)
+                    .map(|summary| (BinanceExchange::EXCHANGE_NAME, summary)),
+                BitstampExchange::EXCHANGE_NAME => BitstampExchange::try_parse_summary(message.message)
+                    .map(|summary| (BitstampExchange::EXCHANGE_NAME, summary)),
+                _ => Err(Error::UnknownExchange(message.exchange)),
+            };
+
+            yield summary.map(|(exchange, summary)| {
+                feed_monitor.record_update(exchange, &currency_pair);
+                summary
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::fees::FeeSchedule;
+
+    #[test]
+    fn test_replay_speed_parsing() {
+        assert_eq!(
+            "max".parse::<ReplaySpeed>().unwrap(),
+            ReplaySpeed::Unlimited
+        );
+        assert_eq!(
+            "2.5".parse::<ReplaySpeed>().unwrap(),
+            ReplaySpeed::Multiplier(2.5)
+        );
+        assert!("0".parse::<ReplaySpeed>().is_err());
+        assert!("fast".parse::<ReplaySpeed>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replaying_recorded_books_through_the_merge() {
+        let recorded = |received_at, exchange: &str, message: &str| {
+            RecordedMessage {
+                received_at,
+                exchange: exchange.into(),
+                message: message.into(),
+            }
+        };
+
+        let messages = vec![
+            recorded(
+                1,
+                "Binance",
+                include_str!("../test_data/binance_order_book_update_message.json"),
+            ),
+            recorded(
+                2,
+                "Bitstamp",
+                include_str!("../test_data/bitstamp_order_book_update_message.json"),
+            ),
+        ];
+
+        let currency_pair = "ETHUSDT".parse().unwrap();
+        let feed_monitor = FeedMonitor::new(&currency_pair, &["Binance", "Bitstamp"]);
+        let summaries = replay_summaries(
+            messages,
+            ReplaySpeed::Unlimited,
+            &currency_pair,
+            feed_monitor,
+        );
+        let merged: Vec<_> = crate::books::merge_summaries(summaries, FeeSchedule::default())
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        assert_eq!(merged.len(), 2);
+
+        // Bitstamp's bids are all above Binance's, and Binance's asks are all below Bitstamp's
+        let last = &merged[1];
+        assert_eq!(last.bids[0].exchange, "Bitstamp");
+        assert_eq!(last.bids[0].price, 1377.2);
+        assert_eq!(last.asks[0].exchange, "Binance");
+        assert_eq!(last.asks[0].price, 1336.39);
+    }
+}
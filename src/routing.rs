@@ -0,0 +1,225 @@
+//! Fill simulation, routing an order across the exchanges of the merged book.
+
+use itertools::Itertools;
+
+use crate::{
+    merged_book::MergedBook,
+    order_book::{FillSide, FillSimulation, Level, VenueFill},
+};
+
+/// Size of an order to fill.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FillSize {
+    /// In units of the base currency.
+    Quantity(f64),
+    /// In units of the quote currency, at prices before fees.
+    Notional(f64),
+}
+
+/// Fills an order by taking the best levels of `book` first, fees included.
+///
+/// Levels are taken by effective price whether or not the book is ordered
+/// net of fees, as that's what the order actually pays or receives. The
+/// slippage compares the average and mid prices the book is ordered by,
+/// effective or raw.
+pub fn simulate_fill(book: &MergedBook, side: FillSide, size: FillSize) -> FillSimulation {
+    let levels = match side {
+        FillSide::Buy => {
+            book.asks
+                .iter()
+                .sorted_by(|left, right| {
+                    left.effective_price
+                        .partial_cmp(&right.effective_price)
+                        .unwrap()
+                })
+                .collect::<Vec<&Level>>()
+        }
+        FillSide::Sell => {
+            book.bids
+                .iter()
+                .sorted_by(|left, right| {
+                    left.effective_price
+                        .partial_cmp(&right.effective_price)
+                        .unwrap()
+                        .reverse()
+                })
+                .collect()
+        }
+    };
+
+    let (FillSize::Quantity(mut remaining) | FillSize::Notional(mut remaining)) = size;
+    let mut simulation = FillSimulation::default();
+
+    for level in levels {
+        if remaining <= 0.0 {
+            break;
+        }
+
+        let wanted = match size {
+            FillSize::Quantity(_) => remaining,
+            FillSize::Notional(_) => remaining / level.price,
+        };
+        let quantity = wanted.min(level.amount);
+        let notional = quantity * level.price;
+        let fees = quantity * (level.effective_price - level.price).abs();
+
+        // Avoid leaving floating point dust to be filled by the next level
+        remaining = if wanted <= level.amount {
+            0.0
+        } else {
+            match size {
+                FillSize::Quantity(_) => remaining - quantity,
+                FillSize::Notional(_) => remaining - notional,
+            }
+        };
+
+        simulation.filled_quantity += quantity;
+        simulation.notional += notional;
+        simulation.fees += fees;
+        simulation.worst_price = level.price;
+
+        match simulation
+            .venues
+            .iter_mut()
+            .find(|venue| venue.exchange == level.exchange)
+        {
+            Some(venue) => {
+                venue.quantity += quantity;
+                venue.notional += notional;
+                venue.fees += fees;
+            }
+            None => {
+                simulation.venues.push(VenueFill {
+                    exchange: level.exchange.clone(),
+                    quantity,
+                    notional,
+                    fees,
+                });
+            }
+        }
+    }
+
+    simulation.complete = remaining <= 0.0;
+    simulation
+        .venues
+        .sort_by(|left, right| left.exchange.cmp(&right.exchange));
+
+    if simulation.filled_quantity > 0.0 {
+        let paid_fees = match side {
+            FillSide::Buy => simulation.fees,
+            FillSide::Sell => -simulation.fees,
+        };
+
+        simulation.average_price = simulation.notional / simulation.filled_quantity;
+        simulation.effective_average_price =
+            (simulation.notional + paid_fees) / simulation.filled_quantity;
+    }
+
+    simulation.mid_price = book.mid_price().unwrap_or_default();
+
+    if simulation.mid_price > 0.0 && simulation.filled_quantity > 0.0 {
+        let average_price = if book.net_of_fees {
+            simulation.effective_average_price
+        } else {
+            simulation.average_price
+        };
+
+        let slippage = match side {
+            FillSide::Buy => average_price - simulation.mid_price,
+            FillSide::Sell => simulation.mid_price - average_price,
+        };
+
+        simulation.slippage_bps = slippage / simulation.mid_price * 10_000.0;
+    }
+
+    simulation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::level;
+
+    fn book() -> MergedBook {
+        MergedBook {
+            bids: vec![
+                Level {
+                    effective_price: 98.0,
+                    ..level("Bitstamp", 99.0, 1.0)
+                },
+                Level {
+                    effective_price: 98.4,
+                    ..level("Binance", 98.5, 2.0)
+                },
+            ],
+            asks: vec![
+                Level {
+                    effective_price: 101.1,
+                    ..level("Binance", 101.0, 1.0)
+                },
+                Level {
+                    effective_price: 102.0,
+                    ..level("Bitstamp", 101.0, 2.0)
+                },
+                Level {
+                    effective_price: 102.1,
+                    ..level("Binance", 102.0, 1.0)
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_buying_takes_the_cheapest_asks_fees_included() {
+        let simulation = simulate_fill(&book(), FillSide::Buy, FillSize::Quantity(2.5));
+
+        // Binance's ask costs 101.1 with its fee, then Bitstamp's 102.0 is taken before
+        // Binance's 102.1
+        assert!(simulation.complete);
+        assert_eq!(simulation.filled_quantity, 2.5);
+        assert_eq!(simulation.notional, 101.0 + 1.5 * 101.0);
+        assert_eq!(simulation.worst_price, 101.0);
+        assert_eq!(simulation.mid_price, 100.0);
+        assert!((simulation.slippage_bps - 100.0).abs() < 1e-9);
+        assert!((simulation.fees - (0.1 + 1.5)).abs() < 1e-9);
+        assert!((simulation.effective_average_price - (101.1 + 1.5 * 102.0) / 2.5).abs() < 1e-9);
+
+        let venues: Vec<_> = simulation
+            .venues
+            .iter()
+            .map(|venue| (venue.exchange.as_str(), venue.quantity))
+            .collect();
+        assert_eq!(venues, [("Binance", 1.0), ("Bitstamp", 1.5)]);
+    }
+
+    #[test]
+    fn test_slippage_net_of_fees_is_against_the_effective_mid_price() {
+        let book = book();
+        let book = MergedBook::from_levels(book.bids, book.asks, true);
+
+        let simulation = simulate_fill(&book, FillSide::Buy, FillSize::Quantity(1.0));
+
+        // Best effective prices are Binance's bid at 98.4 and ask at 101.1
+        assert_eq!(simulation.mid_price, 99.75);
+        assert_eq!(simulation.average_price, 101.0);
+        let slippage_bps = (101.1 - 99.75) / 99.75 * 10_000.0;
+        assert!((simulation.slippage_bps - slippage_bps).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_selling_a_notional_and_running_out_of_bids() {
+        let simulation = simulate_fill(&book(), FillSide::Sell, FillSize::Notional(98.5));
+
+        // Binance's bid yields more than Bitstamp's once fees are paid
+        assert!(simulation.complete);
+        assert_eq!(simulation.filled_quantity, 1.0);
+        assert_eq!(simulation.venues.len(), 1);
+        assert_eq!(simulation.venues[0].exchange, "Binance");
+
+        let simulation = simulate_fill(&book(), FillSide::Sell, FillSize::Quantity(5.0));
+        assert!(!simulation.complete);
+        assert_eq!(simulation.filled_quantity, 3.0);
+        assert_eq!(simulation.worst_price, 99.0);
+    }
+}
@@ -1,62 +1,675 @@
-use std::pin::Pin;
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use futures::Stream;
-use tokio::sync::broadcast::Sender;
-use tokio_stream::wrappers::BroadcastStream;
-use tonic::{transport::Server, Request, Response, Status};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{
+        broadcast::{error::RecvError, Receiver, Sender},
+        mpsc, watch,
+    },
+    task::JoinHandle,
+};
+use tokio_stream::wrappers::{BroadcastStream, UnboundedReceiverStream};
+use tonic::{
+    server::NamedService,
+    transport::{Identity, Server, ServerTlsConfig},
+    Request, Response, Status,
+};
+use tonic_health::{server::HealthReporter, ServingStatus};
 
 use crate::{
-    order_book::{Empty, OrderbookAggregator, OrderbookAggregatorService, Summary},
+    currencies::CurrencyPair,
+    feeds::{FeedMonitor, FeedsStatus},
+    index::{self, IndexConfig},
+    merged_book::{BucketSize, Crossing, CrossingKind, MergedBook, SUMMARY_DEPTH},
+    order_book::{
+        AggregatedSummary, BucketedBookRequest, CrossingEvent, CrossingEventKind, Empty,
+        FillRequest, FillSide, FillSimulation, Index, IndexRequest, Metrics, MetricsRequest,
+        OrderbookAggregator, OrderbookAggregatorService, RequestedBucketSize, RequestedFillSize,
+        Summary, Trade, CURRENCY_PAIR_METADATA_KEY, FILE_DESCRIPTOR_SET,
+    },
+    recorder::unix_timestamp_millis,
+    routing::{self, FillSize},
     Result,
 };
 
 type TonicResult<T> = Result<T, Status>;
+pub type BookSubscriber = Sender<Result<Arc<MergedBook>, String>>;
+pub type TradeSubscriber = Sender<Result<Arc<Trade>, String>>;
+type ViewStream<T> = Pin<Box<dyn Send + Stream<Item = TonicResult<T>>>>;
+type LatestBook = watch::Receiver<Option<Arc<MergedBook>>>;
+
+/// Price levels of each side the imbalance is computed over, unless requested otherwise.
+const DEFAULT_IMBALANCE_DEPTH: usize = 5;
+/// Distances from the mid price the depth is computed within, unless requested otherwise.
+const DEFAULT_DEPTH_BASIS_POINTS: [f64; 3] = [10.0, 50.0, 100.0];
+/// Deviation from the median beyond which an exchange is left out of the index, unless
+/// requested otherwise.
+const DEFAULT_INDEX_MAX_DEVIATION_BPS: f64 = 100.0;
+
+/// Interval between updates of the health status.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Books and trades kept for slow clients before they skip some.
+pub const BROADCAST_QUEUE_CAPACITY: usize = 100;
+
+const AGGREGATOR_SERVICE_NAME: &str =
+    <OrderbookAggregatorService<OrderbookAggregatorChannel> as NamedService>::NAME;
+
+/// A server bound to its address, serving requests in the background.
+pub struct RunningServer {
+    /// Address the server is bound to, with the actual port if port 0 was requested.
+    pub local_addr: SocketAddr,
+    handle: ServerHandle,
+    task: JoinHandle<Result<()>>,
+}
+
+impl RunningServer {
+    /// A handle to change how new connections are served, see `ServerHandle::reload`.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Waits until the server stops.
+    pub async fn wait(self) -> Result<()> {
+        self.task.await.expect("server task panicked")
+    }
+}
+
+/// Where and how the server is served.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub addr: SocketAddr,
+    /// Levels sent per side in book summaries.
+    pub depth: usize,
+    /// Certificate and key to serve over TLS, plaintext if unset.
+    pub tls: Option<Identity>,
+}
+
+impl ServerOptions {
+    /// Plaintext on the loopback interface at `port`, with the default depth.
+    pub fn localhost(port: u16) -> Self {
+        Self {
+            addr: (Ipv6Addr::LOCALHOST, port).into(),
+            depth: SUMMARY_DEPTH,
+            tls: None,
+        }
+    }
+}
+
+/// Accepted connections, sent to the generation of the server that serves them.
+type Connections = mpsc::UnboundedSender<Result<TcpStream, std::io::Error>>;
+/// Starts serving a new generation of the server with some depth and TLS identity.
+type ServeGeneration = dyn Fn(usize, Option<Identity>) -> Result<Connections> + Send + Sync;
+
+/// Changes how the connections accepted by a running server are served.
+#[derive(Clone)]
+pub struct ServerHandle {
+    serve_generation: Arc<ServeGeneration>,
+    generations: Arc<watch::Sender<Connections>>,
+}
+
+impl ServerHandle {
+    /// Serves the connections accepted from now on with `depth` and `tls`.
+    ///
+    /// Connections already accepted, and their streams, keep being served
+    /// with the previous depth and certificate until clients close them.
+    pub fn reload(&self, depth: usize, tls: Option<Identity>) -> Result<()> {
+        let connections = (self.serve_generation)(depth, tls)?;
+        let _ = self.generations.send(connections);
+
+        Ok(())
+    }
+}
+
+/// Channels a served pair's books and trades are published to.
+#[derive(Debug, Clone)]
+struct PairChannels {
+    books: BookSubscriber,
+    trades: TradeSubscriber,
+    latest_book: LatestBook,
+    /// Pairs traded on the exchanges the book is made of.
+    traded_pairs: Vec<CurrencyPair>,
+}
+
+/// The pairs a server streams, requests without a pair get the first one.
+///
+/// Cloning is cheap, all clones serve the same pairs.
+#[derive(Debug, Clone, Default)]
+pub struct ServedPairs {
+    pairs: Arc<RwLock<Vec<(CurrencyPair, PairChannels)>>>,
+}
+
+impl ServedPairs {
+    /// Starts serving the `books` and `trades` of `currency_pair`, made of the
+    /// books of `traded_pairs`.
+    pub fn insert(
+        &self,
+        currency_pair: &CurrencyPair,
+        books: BookSubscriber,
+        trades: TradeSubscriber,
+        traded_pairs: Vec<CurrencyPair>,
+    ) {
+        let (latest_book_sender, latest_book) = watch::channel(None);
+        tokio::spawn(track_latest_book(books.subscribe(), latest_book_sender));
+
+        let channels = PairChannels {
+            books,
+            trades,
+            latest_book,
+            traded_pairs,
+        };
+
+        let mut pairs = self.pairs.write().unwrap();
+        pairs.retain(|(served_pair, _)| served_pair != currency_pair);
+        pairs.push((currency_pair.clone(), channels));
+    }
+
+    /// Changes the traded pairs the book of `currency_pair` is made of.
+    pub fn set_traded_pairs(&self, currency_pair: &CurrencyPair, traded_pairs: Vec<CurrencyPair>) {
+        let mut pairs = self.pairs.write().unwrap();
+        if let Some((_, channels)) = pairs
+            .iter_mut()
+            .find(|(served_pair, _)| served_pair == currency_pair)
+        {
+            channels.traded_pairs = traded_pairs;
+        }
+    }
+
+    /// Stops serving `currency_pair`, its streams end once nothing publishes to them anymore.
+    pub fn remove(&self, currency_pair: &CurrencyPair) {
+        let mut pairs = self.pairs.write().unwrap();
+        pairs.retain(|(served_pair, _)| served_pair != currency_pair);
+    }
+
+    /// Orders the served pairs like `currency_pairs`, the first one being the default.
+    pub fn sort_like(&self, currency_pairs: &[CurrencyPair]) {
+        let mut pairs = self.pairs.write().unwrap();
+        pairs.sort_by_key(|(served_pair, _)| {
+            currency_pairs
+                .iter()
+                .position(|currency_pair| currency_pair == served_pair)
+        });
+    }
 
-pub async fn run_server(subscriber: Sender<Result<Summary, String>>, port: u16) -> Result<()> {
-    let addr = format!("[::1]:{port}").parse().unwrap();
+    /// The channels of `requested_pair`, or of the default pair.
+    ///
+    /// Any spelling of a served pair is accepted, like "eth/btc" for ETHBTC, and
+    /// symbols without separator are matched against the served ones.
+    fn get(&self, requested_pair: Option<&str>) -> Option<PairChannels> {
+        let pairs = self.pairs.read().unwrap();
+
+        match requested_pair {
+            Some(requested_pair) => {
+                let parsed = requested_pair.parse::<CurrencyPair>().ok();
+                let symbol = requested_pair.to_ascii_uppercase();
+
+                pairs
+                    .iter()
+                    .find(|(served_pair, _)| {
+                        parsed.as_ref() == Some(served_pair) || served_pair.as_str() == symbol
+                    })
+                    .map(|(_, channels)| channels.clone())
+            }
+            None => pairs.first().map(|(_, channels)| channels.clone()),
+        }
+    }
+
+    fn symbols(&self) -> Vec<String> {
+        let pairs = self.pairs.read().unwrap();
+        pairs
+            .iter()
+            .map(|(served_pair, _)| format!("'{}'", served_pair.as_str()))
+            .collect()
+    }
+}
+
+/// Binds the server to `options.addr` and starts serving the books of `pairs`.
+///
+/// Pass port 0 to bind to any available port, see `RunningServer::local_addr`.
+pub async fn run_server(
+    pairs: ServedPairs,
+    feed_monitor: FeedMonitor,
+    options: ServerOptions,
+) -> Result<RunningServer> {
+    let listener = TcpListener::bind(options.addr).await?;
+    let local_addr = listener.local_addr()?;
 
     let aggregator = OrderbookAggregatorChannel {
-        channel_subscriber: subscriber,
+        pairs,
+        depth: options.depth,
+        feed_monitor: feed_monitor.clone(),
     };
 
-    Server::builder()
-        .add_service(OrderbookAggregatorService::new(aggregator))
-        .serve(addr)
-        .await
-        .unwrap();
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(report_health(health_reporter, feed_monitor));
+
+    let serve_generation = move |depth, tls: Option<Identity>| -> Result<Connections> {
+        let reflection_service = tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+            .register_encoded_file_descriptor_set(
+                tonic_health::proto::GRPC_HEALTH_V1_FILE_DESCRIPTOR_SET,
+            )
+            .build()?;
+
+        let mut server = Server::builder();
+        if let Some(identity) = tls {
+            server = server.tls_config(ServerTlsConfig::new().identity(identity))?;
+        }
+
+        let router = server
+            .add_service(health_service.clone())
+            .add_service(reflection_service)
+            .add_service(OrderbookAggregatorService::new(
+                OrderbookAggregatorChannel {
+                    depth,
+                    ..aggregator.clone()
+                },
+            ));
 
-    Ok(())
+        // Once replaced by a newer generation, no connection is sent anymore
+        // and serving stops, but the connections already accepted go on
+        let (connections, incoming) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            if let Err(err) = router
+                .serve_with_incoming(UnboundedReceiverStream::new(incoming))
+                .await
+            {
+                log::error!("Server error: {err}.");
+            }
+        });
+
+        Ok(connections)
+    };
+
+    let connections = serve_generation(options.depth, options.tls)?;
+    let (generations, current_generation) = watch::channel(connections);
+
+    let task = tokio::spawn(accept_connections(listener, current_generation));
+
+    let handle = ServerHandle {
+        serve_generation: Arc::new(serve_generation),
+        generations: Arc::new(generations),
+    };
+
+    Ok(RunningServer {
+        local_addr,
+        handle,
+        task,
+    })
 }
 
-#[derive(Debug)]
+/// Sends every connection accepted by `listener` to the current generation of the server.
+async fn accept_connections(
+    listener: TcpListener,
+    current_generation: watch::Receiver<Connections>,
+) -> Result<()> {
+    loop {
+        let (connection, _) = listener.accept().await?;
+
+        // Sending only fails if the generation stopped on an error, already logged
+        let _ = current_generation.borrow().send(Ok(connection));
+    }
+}
+
+/// Keeps the health service in sync with the state of the exchange feeds.
+///
+/// Both the aggregator service and the overall server status ("") are reported
+/// as SERVING only after every feed delivered a valid book, and go back to
+/// NOT_SERVING when every exchange feeding some pair of the book is stale.
+async fn report_health(mut reporter: HealthReporter, feed_monitor: FeedMonitor) {
+    let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let status = match feed_monitor.status() {
+            FeedsStatus::Live => ServingStatus::Serving,
+            FeedsStatus::Starting | FeedsStatus::Stale => ServingStatus::NotServing,
+        };
+
+        reporter
+            .set_service_status(AGGREGATOR_SERVICE_NAME, status)
+            .await;
+        reporter.set_service_status("", status).await;
+    }
+}
+
+/// Keeps `latest_book` set to the last valid book received.
+async fn track_latest_book(
+    mut receiver: Receiver<Result<Arc<MergedBook>, String>>,
+    latest_book: watch::Sender<Option<Arc<MergedBook>>>,
+) {
+    loop {
+        match receiver.recv().await {
+            Ok(Ok(book)) => {
+                let _ = latest_book.send(Some(book));
+            }
+            // Errors and skipped books don't make the latest one wrong
+            Ok(Err(_)) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct OrderbookAggregatorChannel {
-    channel_subscriber: Sender<Result<Summary, String>>,
+    pairs: ServedPairs,
+    /// Levels sent per side, and the default depth of bucketed books.
+    depth: usize,
+    feed_monitor: FeedMonitor,
+}
+
+impl OrderbookAggregatorChannel {
+    /// The channels of the pair the request expects, the default one if it expects none.
+    fn requested_pair<T>(&self, request: &Request<T>) -> TonicResult<PairChannels> {
+        let requested_pair = match request.metadata().get(CURRENCY_PAIR_METADATA_KEY) {
+            Some(requested_pair) => requested_pair,
+            None => {
+                return self
+                    .pairs
+                    .get(None)
+                    .ok_or_else(|| Status::unavailable("no pair is served"))
+            }
+        };
+
+        let requested_pair = requested_pair
+            .to_str()
+            .map_err(|_| Status::invalid_argument("currency pair must be ASCII"))?;
+
+        self.pairs.get(Some(requested_pair)).ok_or_else(|| {
+            Status::not_found(format!(
+                "this server streams {}, not '{requested_pair}'",
+                self.pairs.symbols().join(", ")
+            ))
+        })
+    }
 }
 
 #[tonic::async_trait]
 impl OrderbookAggregator for OrderbookAggregatorChannel {
-    type BookSummaryStream = Pin<Box<dyn Send + Stream<Item = TonicResult<Summary>>>>;
+    type BookSummaryStream = ViewStream<Summary>;
+    type AggregatedBookSummaryStream = ViewStream<AggregatedSummary>;
+    type BucketedBookSummaryStream = ViewStream<AggregatedSummary>;
+    type MarketCrossingsStream = ViewStream<CrossingEvent>;
+    type MarketMetricsStream = ViewStream<Metrics>;
+    type IndexPriceStream = ViewStream<Index>;
+    type TradesStream = ViewStream<Trade>;
 
     async fn book_summary(
         &self,
-        _request: Request<Empty>,
+        request: Request<Empty>,
     ) -> TonicResult<Response<Self::BookSummaryStream>> {
-        let receiver = self.channel_subscriber.subscribe();
+        let pair = self.requested_pair(&request)?;
+        let depth = self.depth;
+        Ok(Response::new(
+            pair.book_views(move |book| book.summary(depth)),
+        ))
+    }
 
-        let stream = BroadcastStream::new(receiver);
+    async fn aggregated_book_summary(
+        &self,
+        request: Request<Empty>,
+    ) -> TonicResult<Response<Self::AggregatedBookSummaryStream>> {
+        let pair = self.requested_pair(&request)?;
+        let depth = self.depth;
+        Ok(Response::new(
+            pair.book_views(move |book| book.aggregated(depth)),
+        ))
+    }
+
+    async fn bucketed_book_summary(
+        &self,
+        request: Request<BucketedBookRequest>,
+    ) -> TonicResult<Response<Self::BucketedBookSummaryStream>> {
+        let pair = self.requested_pair(&request)?;
+
+        let BucketedBookRequest { bucket_size, depth } = request.into_inner();
+
+        let bucket_size = match bucket_size {
+            Some(RequestedBucketSize::TickSize(size)) => BucketSize::Tick(size),
+            Some(RequestedBucketSize::BasisPoints(size)) => BucketSize::BasisPoints(size),
+            None => return Err(Status::invalid_argument("a bucket size is required")),
+        };
+
+        let (BucketSize::Tick(size) | BucketSize::BasisPoints(size)) = bucket_size;
+        if !(size.is_finite() && size > 0.0) {
+            return Err(Status::invalid_argument("bucket size must be positive"));
+        }
+
+        let depth = match depth {
+            0 => self.depth,
+            depth => depth as usize,
+        };
+
+        let view = move |book: &MergedBook| book.bucketed(bucket_size, depth);
+        Ok(Response::new(pair.book_views(view)))
+    }
+
+    async fn market_crossings(
+        &self,
+        request: Request<Empty>,
+    ) -> TonicResult<Response<Self::MarketCrossingsStream>> {
+        let pair = self.requested_pair(&request)?;
+
+        // A crossing already going on when subscribing is sent as just started
+        let mut previous: Option<Crossing> = None;
+
+        let events = move |book: &MergedBook| {
+            let current = book.crossing.clone();
+            let now = unix_timestamp_millis();
+
+            let same_crossing = match (&previous, &current) {
+                (Some(previous), Some(current)) => {
+                    current.continues(previous) && current.started_at == previous.started_at
+                }
+                (None, None) => true,
+                _ => false,
+            };
+
+            let mut events = vec![];
+            if !same_crossing {
+                if let Some(previous) = &previous {
+                    events.push(crossing_event(previous, true, now));
+                }
+                if let Some(current) = &current {
+                    events.push(crossing_event(current, false, now));
+                }
+            }
+
+            previous = current;
+            events
+        };
+
+        Ok(Response::new(pair.book_events(events)))
+    }
+
+    async fn market_metrics(
+        &self,
+        request: Request<MetricsRequest>,
+    ) -> TonicResult<Response<Self::MarketMetricsStream>> {
+        let pair = self.requested_pair(&request)?;
+
+        let MetricsRequest {
+            imbalance_depth,
+            mut depth_basis_points,
+        } = request.into_inner();
+
+        let imbalance_depth = match imbalance_depth {
+            0 => DEFAULT_IMBALANCE_DEPTH,
+            depth => depth as usize,
+        };
+
+        if depth_basis_points.is_empty() {
+            depth_basis_points = DEFAULT_DEPTH_BASIS_POINTS.to_vec();
+        }
+
+        if !depth_basis_points
+            .iter()
+            .all(|basis_points| basis_points.is_finite() && *basis_points > 0.0)
+        {
+            return Err(Status::invalid_argument("depth distances must be positive"));
+        }
+
+        let view = move |book: &MergedBook| book.metrics(imbalance_depth, &depth_basis_points);
+        Ok(Response::new(pair.book_views(view)))
+    }
+
+    async fn index_price(
+        &self,
+        request: Request<IndexRequest>,
+    ) -> TonicResult<Response<Self::IndexPriceStream>> {
+        let pair = self.requested_pair(&request)?;
+
+        let IndexRequest {
+            max_deviation_bps,
+            max_age_ms,
+        } = request.into_inner();
+
+        let max_deviation_bps = if max_deviation_bps == 0.0 {
+            DEFAULT_INDEX_MAX_DEVIATION_BPS
+        } else if max_deviation_bps.is_finite() && max_deviation_bps > 0.0 {
+            max_deviation_bps
+        } else {
+            return Err(Status::invalid_argument(
+                "maximum deviation must be positive",
+            ));
+        };
+
+        let max_age = match max_age_ms {
+            0 => self.feed_monitor.staleness_timeout(),
+            max_age_ms => Duration::from_millis(max_age_ms),
+        };
+
+        let config = IndexConfig {
+            max_deviation_bps,
+            max_age,
+        };
+
+        let feed_monitor = self.feed_monitor.clone();
+        let traded_pairs = pair.traded_pairs.clone();
+        let view = move |book: &MergedBook| {
+            index::index_price(book, &feed_monitor.update_ages_of(&traded_pairs), &config)
+        };
+
+        Ok(Response::new(pair.book_views(view)))
+    }
+
+    async fn trades(&self, request: Request<Empty>) -> TonicResult<Response<Self::TradesStream>> {
+        let pair = self.requested_pair(&request)?;
+
+        let stream = BroadcastStream::new(pair.trades.subscribe());
 
         let stream = async_stream::stream! {
-            for await summary in stream {
-                // Ignore obsolete summaries (Err(_))
-                if let Ok(summary) = summary {
-                    // Map error to the gRPC error type
-                    let summary = summary.map_err(Status::internal);
-                    // Stream it
-                    yield summary;
+            for await trade in stream {
+                // Trades missed by a slow client are skipped (Err(_)), and so are
+                // trades that couldn't be parsed, the next ones are still valid
+                match trade {
+                    Ok(Ok(trade)) => yield Ok(Trade::clone(&trade)),
+                    Ok(Err(err)) => log::warn!("Skipped an invalid trade: {err}."),
+                    Err(_) => {}
                 }
             }
         };
 
         Ok(Response::new(Box::pin(stream)))
     }
+
+    async fn simulate_fill(
+        &self,
+        request: Request<FillRequest>,
+    ) -> TonicResult<Response<FillSimulation>> {
+        let pair = self.requested_pair(&request)?;
+
+        let FillRequest { side, size } = request.into_inner();
+
+        let side = FillSide::from_i32(side)
+            .ok_or_else(|| Status::invalid_argument(format!("unknown side {side}")))?;
+
+        let size = match size {
+            Some(RequestedFillSize::Quantity(quantity)) => FillSize::Quantity(quantity),
+            Some(RequestedFillSize::Notional(notional)) => FillSize::Notional(notional),
+            None => {
+                return Err(Status::invalid_argument(
+                    "a quantity or notional is required",
+                ))
+            }
+        };
+
+        let (FillSize::Quantity(amount) | FillSize::Notional(amount)) = size;
+        if !(amount.is_finite() && amount > 0.0) {
+            return Err(Status::invalid_argument("order size must be positive"));
+        }
+
+        if self.feed_monitor.status_of(&pair.traded_pairs) == FeedsStatus::Stale {
+            return Err(Status::unavailable("every exchange feed is stale"));
+        }
+
+        let book = pair.latest_book.borrow().clone();
+        let book = book.ok_or_else(|| Status::unavailable("no book received yet"))?;
+
+        Ok(Response::new(routing::simulate_fill(&book, side, size)))
+    }
+}
+
+fn crossing_event(crossing: &Crossing, ended: bool, now: u64) -> CrossingEvent {
+    let kind = match crossing.kind {
+        CrossingKind::Crossed => CrossingEventKind::Crossed,
+        CrossingKind::Locked => CrossingEventKind::Locked,
+    };
+
+    CrossingEvent {
+        kind: kind as i32,
+        ended,
+        bid_exchange: crossing.bid_exchange.clone(),
+        ask_exchange: crossing.ask_exchange.clone(),
+        bid_price: crossing.bid_price,
+        ask_price: crossing.ask_price,
+        amount: crossing.amount,
+        started_at: crossing.started_at,
+        duration_ms: now.saturating_sub(crossing.started_at),
+    }
+}
+
+impl PairChannels {
+    /// Streams `view` of every book published from now on.
+    fn book_views<T, F>(&self, view: F) -> ViewStream<T>
+    where
+        T: Send + 'static,
+        F: Fn(&MergedBook) -> T + Send + 'static,
+    {
+        self.book_events(move |book| vec![view(book)])
+    }
+
+    /// Streams the `events` of every book published from now on.
+    fn book_events<T, F>(&self, mut events: F) -> ViewStream<T>
+    where
+        T: Send + 'static,
+        F: FnMut(&MergedBook) -> Vec<T> + Send + 'static,
+    {
+        let receiver = self.books.subscribe();
+
+        let stream = BroadcastStream::new(receiver);
+
+        let stream = async_stream::stream! {
+            for await book in stream {
+                // Obsolete books missed by a slow client are skipped (Err(_)), and so
+                // are books that couldn't be built, the next ones are still valid
+                match book {
+                    Ok(Ok(book)) => for event in events(&book) {
+                        yield Ok(event);
+                    },
+                    Ok(Err(err)) => log::warn!("Skipped an invalid book: {err}."),
+                    Err(_) => {}
+                }
+            }
+        };
+
+        Box::pin(stream)
+    }
 }
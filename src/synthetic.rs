@@ -0,0 +1,249 @@
+//! Synthetic books of a pair, composed from the books of two pairs sharing a currency.
+
+use futures::{future, Stream, StreamExt};
+use merge_streams::MergeStreams;
+
+use crate::{
+    currencies::CurrencyPair,
+    fees::FeeSchedule,
+    merged_book::{MergedBook, SUMMARY_DEPTH},
+    order_book::{Leg, Level},
+    recorder, Error, Result,
+};
+
+/// A pair traded to compose a synthetic pair, with the fees of that pair.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticLeg {
+    pub currency_pair: CurrencyPair,
+    pub fees: FeeSchedule,
+}
+
+/// The two pairs trading `currency_pair` through `via`.
+///
+/// ETHGBP through BTC is traded as ETHBTC, then BTCGBP. `via` can't be the
+/// base or the quote of `currency_pair`, a leg would trade a currency for itself.
+pub fn legs_via(currency_pair: &CurrencyPair, via: &str) -> Result<[CurrencyPair; 2]> {
+    let via_upper = via.to_ascii_uppercase();
+
+    if via_upper == currency_pair.base() || via_upper == currency_pair.quote() {
+        return Err(Error::InvalidConfig(format!(
+            "{currency_pair} can't be composed through {via_upper}, one of its own currencies"
+        )));
+    }
+
+    Ok([
+        CurrencyPair::new(currency_pair.base(), via),
+        CurrencyPair::new(via, currency_pair.quote()),
+    ])
+}
+
+/// Composes a synthetic book every time the book of either leg changes.
+///
+/// Nothing is sent until both legs have a book, nor while the composed book
+/// is too shallow to fill a summary.
+pub fn synthetic_books(
+    first: (CurrencyPair, impl Stream<Item = Result<MergedBook>>),
+    second: (CurrencyPair, impl Stream<Item = Result<MergedBook>>),
+) -> impl Stream<Item = Result<MergedBook>> {
+    let (first_pair, first_books) = first;
+    let (second_pair, second_books) = second;
+
+    let first_books = Box::pin(first_books.map(|book| (0, book)));
+    let second_books = Box::pin(second_books.map(|book| (1, book)));
+
+    (first_books, second_books)
+        .merge()
+        .scan(
+            ([None, None], None),
+            move |(latest_books, last_crossing), (leg, book)| {
+                let book = match book {
+                    Ok(book) => book,
+                    Err(err) => return future::ready(Some(Some(Err(err)))),
+                };
+
+                latest_books[leg] = Some(book);
+
+                let mut composed = match latest_books {
+                    [Some(first_book), Some(second_book)] => {
+                        compose(
+                            (first_pair.as_str(), first_book),
+                            (second_pair.as_str(), second_book),
+                        )
+                    }
+                    _ => return future::ready(Some(None)),
+                };
+
+                if composed.bids.len() < SUMMARY_DEPTH || composed.asks.len() < SUMMARY_DEPTH {
+                    return future::ready(Some(None));
+                }
+
+                composed.detect_crossing(last_crossing.as_ref(), recorder::unix_timestamp_millis());
+                *last_crossing = composed.crossing.clone();
+
+                future::ready(Some(Some(Ok(composed))))
+            },
+        )
+        .filter_map(future::ready)
+}
+
+/// Composes the book of the base of the first leg against the quote of the second.
+///
+/// Selling the synthetic pair sells the first leg, then the second, so its
+/// bids walk the bids of both legs, and its asks walk the asks of both.
+pub fn compose(first: (&str, &MergedBook), second: (&str, &MergedBook)) -> MergedBook {
+    let (first_pair, first_book) = first;
+    let (second_pair, second_book) = second;
+
+    MergedBook {
+        bids: compose_side(
+            (first_pair, &first_book.bids),
+            (second_pair, &second_book.bids),
+        ),
+        asks: compose_side(
+            (first_pair, &first_book.asks),
+            (second_pair, &second_book.asks),
+        ),
+        net_of_fees: first_book.net_of_fees,
+        crossing: None,
+    }
+}
+
+/// Walks the levels of both legs, best first, into levels of the synthetic pair.
+///
+/// Each synthetic level lasts until a level of either leg is used up, its
+/// amount is in the base currency of the first leg.
+fn compose_side(first: (&str, &[Level]), second: (&str, &[Level])) -> Vec<Level> {
+    let (first_pair, first_levels) = first;
+    let (second_pair, second_levels) = second;
+
+    let mut first_levels = first_levels.iter();
+    let mut second_levels = second_levels.iter();
+    let (mut first_level, mut second_level) = (first_levels.next(), second_levels.next());
+
+    // Amount left at the current level of each leg, in the base currency of that leg
+    let mut first_left = first_level.map_or(0.0, |level| level.amount);
+    let mut second_left = second_level.map_or(0.0, |level| level.amount);
+
+    let mut levels = vec![];
+
+    while let (Some(first), Some(second)) = (first_level, second_level) {
+        // What's left of the first leg, in the currency both legs share
+        let first_worth = first_left * first.price;
+
+        let amount = if first_worth <= second_left {
+            let amount = first_left;
+            second_left -= first_worth;
+            first_level = first_levels.next();
+            first_left = first_level.map_or(0.0, |level| level.amount);
+            amount
+        } else {
+            let amount = second_left / first.price;
+            first_left -= amount;
+            second_level = second_levels.next();
+            second_left = second_level.map_or(0.0, |level| level.amount);
+            amount
+        };
+
+        if amount <= 0.0 {
+            continue;
+        }
+
+        let exchange = if first.exchange == second.exchange {
+            first.exchange.clone()
+        } else {
+            format!("{}+{}", first.exchange, second.exchange)
+        };
+
+        levels.push(Level {
+            exchange,
+            price: first.price * second.price,
+            amount,
+            order_count: 0,
+            orders: vec![],
+            effective_price: first.effective_price * second.effective_price,
+            legs: vec![
+                Leg {
+                    currency_pair: first_pair.to_owned(),
+                    exchange: first.exchange.clone(),
+                    price: first.price,
+                    amount,
+                },
+                Leg {
+                    currency_pair: second_pair.to_owned(),
+                    exchange: second.exchange.clone(),
+                    price: second.price,
+                    amount: amount * first.price,
+                },
+            ],
+        });
+    }
+
+    levels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::level;
+
+    #[test]
+    fn test_legs_through_a_shared_currency() {
+        let [first, second] = legs_via(&"ETHGBP".parse().unwrap(), "btc").unwrap();
+        assert_eq!(first.as_str(), "ETHBTC");
+        assert_eq!(second.as_str(), "BTCGBP");
+    }
+
+    #[test]
+    fn test_legs_through_an_own_currency_are_rejected() {
+        assert!(legs_via(&"ETHGBP".parse().unwrap(), "eth").is_err());
+        assert!(legs_via(&"ETHGBP".parse().unwrap(), "GBP").is_err());
+    }
+
+    #[test]
+    fn test_composing_walks_both_legs() {
+        let eth_btc = MergedBook {
+            bids: vec![level("Binance", 0.07, 10.0), level("Bitstamp", 0.06, 10.0)],
+            asks: vec![level("Binance", 0.08, 1.0)],
+            ..Default::default()
+        };
+        let btc_gbp = MergedBook {
+            bids: vec![
+                level("Bitstamp", 20_000.0, 0.5),
+                level("Binance", 19_000.0, 2.0),
+            ],
+            asks: vec![level("Bitstamp", 21_000.0, 1.0)],
+            ..Default::default()
+        };
+
+        let book = compose(("ETHBTC", &eth_btc), ("BTCGBP", &btc_gbp));
+
+        // 0.5 BTC is worth 7.14 ETH at 0.07, the other 2.86 ETH sell for BTC at 19000
+        let bids: Vec<_> = book
+            .bids
+            .iter()
+            .map(|level| (level.exchange.as_str(), level.price, level.amount))
+            .collect();
+        assert_eq!(bids.len(), 3);
+        assert_eq!(bids[0].0, "Binance+Bitstamp");
+        assert!((bids[0].1 - 1400.0).abs() < 1e-9);
+        assert!((bids[0].2 - 0.5 / 0.07).abs() < 1e-9);
+        assert_eq!(bids[1].0, "Binance");
+        assert!((bids[1].1 - 1330.0).abs() < 1e-9);
+        assert!((bids[0].2 + bids[1].2 - 10.0).abs() < 1e-9);
+
+        // The second ETHBTC level is sold whole, for 0.6 of the 1.8 BTC left at 19000
+        assert_eq!(bids[2].0, "Bitstamp+Binance");
+        assert!((bids[2].1 - 1140.0).abs() < 1e-9);
+        assert_eq!(bids[2].2, 10.0);
+
+        let legs = &book.bids[0].legs;
+        assert_eq!(legs[0].currency_pair, "ETHBTC");
+        assert_eq!(legs[1].currency_pair, "BTCGBP");
+        assert!((legs[1].amount - 0.5).abs() < 1e-9);
+
+        // 1 ETH costs 0.08 BTC, which costs 1680 GBP
+        assert_eq!(book.asks.len(), 1);
+        assert!((book.asks[0].price - 1680.0).abs() < 1e-9);
+        assert_eq!(book.asks[0].amount, 1.0);
+    }
+}
@@ -0,0 +1,456 @@
+//! Terminal UI that renders the merged order book live.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Stdout},
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures::{Stream, StreamExt};
+use tui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Cell, Paragraph, Row, Table},
+    Frame, Terminal,
+};
+
+use crate::{
+    client,
+    currencies::CurrencyPair,
+    exchanges::{BinanceExchange, BitstampExchange, ConnectToOrderBook, ExchangeEndpoints},
+    feeds::FeedMonitor,
+    fees::FeeSchedule,
+    instruments::PairCatalog,
+    merged_book::SUMMARY_DEPTH,
+    order_book::{Level, Summary},
+    recorder::Recorder,
+    Result,
+};
+
+/// Interval between redraws when no book arrives.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+/// Delay before connecting again after the book source failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+/// Width, in cells, of a full contribution bar.
+const BAR_WIDTH: usize = 30;
+
+const LADDER_WIDTHS: [Constraint; 4] = [
+    Constraint::Length(4),
+    Constraint::Length(10),
+    Constraint::Length(16),
+    Constraint::Length(16),
+];
+
+type BookStream = Pin<Box<dyn Send + Stream<Item = Result<Summary>>>>;
+
+/// Where the merged books come from.
+#[derive(Clone, Debug)]
+pub enum BookSource {
+    /// Connect to the exchanges trading the pair and merge books in this process.
+    Local(ExchangeEndpoints, PairCatalog),
+    /// Subscribe to a running server at this address.
+    Remote(String),
+}
+
+impl BookSource {
+    async fn connect(
+        &self,
+        currency_pair: &CurrencyPair,
+        feed_monitor: &FeedMonitor,
+    ) -> Result<BookStream> {
+        let stream: BookStream = match self {
+            Self::Local(endpoints, catalog) => {
+                let stream = crate::books::build_aggregated_book_order(
+                    currency_pair,
+                    catalog,
+                    endpoints,
+                    feed_monitor,
+                    &Recorder::disabled(),
+                    false,
+                    FeeSchedule::default(),
+                )
+                .await?;
+                Box::pin(stream.map(|book| Ok(book?.summary(SUMMARY_DEPTH))))
+            }
+            Self::Remote(addr) => {
+                let stream = client::subscribe(addr.clone(), Some(currency_pair)).await?;
+                Box::pin(stream.map(|summary| Ok(summary?)))
+            }
+        };
+
+        Ok(stream)
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::Local(..) => "local pipeline".into(),
+            Self::Remote(addr) => addr.clone(),
+        }
+    }
+}
+
+/// Updates produced while following a book source.
+enum BookEvent {
+    Connected,
+    Book(Summary),
+    Disconnected(String),
+}
+
+enum ConnectionStatus {
+    Connecting,
+    Connected,
+    Disconnected(String),
+}
+
+struct App {
+    source: BookSource,
+    currency_pair: CurrencyPair,
+    feed_monitor: FeedMonitor,
+    connection: ConnectionStatus,
+    summary: Option<Summary>,
+    last_update: Option<Instant>,
+}
+
+/// Runs the terminal UI until the user quits with `q`, `Esc` or `Ctrl-C`.
+pub async fn run(source: BookSource, currency_pair: CurrencyPair) -> Result<()> {
+    // Exchanges are monitored as their feeds are connected
+    let feed_monitor = FeedMonitor::default();
+
+    let app = App {
+        source,
+        currency_pair,
+        feed_monitor,
+        connection: ConnectionStatus::Connecting,
+        summary: None,
+        last_update: None,
+    };
+
+    let mut terminal = setup_terminal()?;
+    let result = run_app(&mut terminal, app).await;
+    restore_terminal(&mut terminal)?;
+
+    result
+}
+
+async fn run_app(terminal: &mut Terminal<CrosstermBackend<Stdout>>, mut app: App) -> Result<()> {
+    let books = follow_source(
+        app.source.clone(),
+        app.currency_pair.clone(),
+        app.feed_monitor.clone(),
+    );
+    let mut books = Box::pin(books);
+    let mut terminal_events = EventStream::new();
+    let mut redraw = tokio::time::interval(REDRAW_INTERVAL);
+
+    loop {
+        terminal.draw(|frame| draw(frame, &app))?;
+
+        tokio::select! {
+            Some(event) = books.next() => match event {
+                BookEvent::Connected => app.connection = ConnectionStatus::Connected,
+                BookEvent::Book(summary) => {
+                    app.summary = Some(summary);
+                    app.last_update = Some(Instant::now());
+                }
+                BookEvent::Disconnected(reason) => {
+                    app.connection = ConnectionStatus::Disconnected(reason);
+                }
+            },
+            Some(event) = terminal_events.next() => {
+                if is_quit_event(&event?) {
+                    return Ok(());
+                }
+            }
+            _ = redraw.tick() => {}
+        }
+    }
+}
+
+/// Follows the book source forever, connecting again whenever it fails.
+fn follow_source(
+    source: BookSource,
+    currency_pair: CurrencyPair,
+    feed_monitor: FeedMonitor,
+) -> impl Stream<Item = BookEvent> {
+    async_stream::stream! {
+        loop {
+            match source.connect(&currency_pair, &feed_monitor).await {
+                Ok(mut books) => {
+                    yield BookEvent::Connected;
+
+                    let mut reason = "stream ended".to_string();
+                    while let Some(summary) = books.next().await {
+                        match summary {
+                            Ok(summary) => yield BookEvent::Book(summary),
+                            Err(err) => {
+                                reason = err.to_string();
+                                break;
+                            }
+                        }
+                    }
+
+                    yield BookEvent::Disconnected(reason);
+                }
+                Err(err) => yield BookEvent::Disconnected(err.to_string()),
+            }
+
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+}
+
+fn is_quit_event(event: &Event) -> bool {
+    match event {
+        Event::Key(KeyEvent {
+            code: KeyCode::Char('c'),
+            modifiers,
+            ..
+        }) => modifiers.contains(KeyModifiers::CONTROL),
+        Event::Key(KeyEvent {
+            code: KeyCode::Char('q') | KeyCode::Esc,
+            ..
+        }) => true,
+        _ => false,
+    }
+}
+
+fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    Ok(Terminal::new(CrosstermBackend::new(stdout))?)
+}
+
+fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    Ok(())
+}
+
+fn draw<B: Backend>(frame: &mut Frame<B>, app: &App) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(5), Constraint::Min(0)])
+        .split(frame.size());
+
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Length(52), Constraint::Min(0)])
+        .split(rows[1]);
+
+    draw_status(frame, app, rows[0]);
+    draw_ladder(frame, app, columns[0]);
+    draw_contributions(frame, app, columns[1]);
+}
+
+fn draw_status<B: Backend>(frame: &mut Frame<B>, app: &App, area: Rect) {
+    let connection = match &app.connection {
+        ConnectionStatus::Connecting => {
+            Span::styled("connecting", Style::default().fg(Color::Yellow))
+        }
+        ConnectionStatus::Connected => Span::styled("connected", Style::default().fg(Color::Green)),
+        ConnectionStatus::Disconnected(reason) => {
+            Span::styled(
+                format!("disconnected: {reason}"),
+                Style::default().fg(Color::Red),
+            )
+        }
+    };
+
+    let mut feeds = vec![Span::raw("Feeds: ")];
+    match app.source {
+        BookSource::Local(..) => {
+            for (exchange, age) in app.feed_monitor.update_ages() {
+                let (text, color) = match age {
+                    None => ("waiting".to_string(), Color::Yellow),
+                    Some(age) if age >= app.feed_monitor.staleness_timeout() => {
+                        (format!("stale {:.1}s", age.as_secs_f64()), Color::Red)
+                    }
+                    Some(age) => (format!("{:.1}s ago", age.as_secs_f64()), Color::Green),
+                };
+                feeds.push(Span::styled(exchange, exchange_style(exchange)));
+                feeds.push(Span::raw(" "));
+                feeds.push(Span::styled(text, Style::default().fg(color)));
+                feeds.push(Span::raw("  "));
+            }
+        }
+        BookSource::Remote(_) => feeds.push(Span::raw("reported by the server")),
+    }
+
+    let last_update = match app.last_update {
+        Some(instant) => format!("{:.1}s ago", instant.elapsed().as_secs_f64()),
+        None => "never".into(),
+    };
+
+    let lines = vec![
+        Spans::from(vec![
+            Span::styled(
+                app.currency_pair.as_str(),
+                Style::default().add_modifier(Modifier::BOLD),
+            ),
+            Span::raw(format!(" from {} - ", app.source.describe())),
+            connection,
+        ]),
+        Spans::from(feeds),
+        Spans::from(format!("Last book: {last_update}    Quit: q")),
+    ];
+
+    let status =
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Status"));
+    frame.render_widget(status, area);
+}
+
+fn draw_ladder<B: Backend>(frame: &mut Frame<B>, app: &App, area: Rect) {
+    let summary = match &app.summary {
+        Some(summary) => summary,
+        None => {
+            let waiting = Paragraph::new("Waiting for the first book...")
+                .block(Block::default().borders(Borders::ALL).title("Book"));
+            frame.render_widget(waiting, area);
+            return;
+        }
+    };
+
+    let level_row = |side: &'static str, level: &Level, color: Color| {
+        Row::new(vec![
+            Cell::from(side),
+            Cell::from(level.exchange.clone()).style(exchange_style(&level.exchange)),
+            Cell::from(format!("{:>16}", level.price)).style(Style::default().fg(color)),
+            Cell::from(format!("{:>16}", level.amount)),
+        ])
+    };
+
+    let asks = summary
+        .asks
+        .iter()
+        .rev()
+        .map(|level| level_row("ask", level, Color::Red));
+
+    let spread = Row::new(vec![
+        Cell::from(""),
+        Cell::from("spread"),
+        Cell::from(format!("{:>16}", summary.spread)),
+        Cell::from(""),
+    ])
+    .style(Style::default().add_modifier(Modifier::BOLD));
+
+    let bids = summary
+        .bids
+        .iter()
+        .map(|level| level_row("bid", level, Color::Green));
+
+    let rows: Vec<Row> = asks.chain(Some(spread)).chain(bids).collect();
+
+    let header = Row::new(vec![
+        "SIDE",
+        "EXCHANGE",
+        "           PRICE",
+        "          AMOUNT",
+    ])
+    .style(Style::default().add_modifier(Modifier::BOLD));
+
+    let ladder = Table::new(rows)
+        .header(header)
+        .widths(&LADDER_WIDTHS)
+        .block(Block::default().borders(Borders::ALL).title("Book"));
+
+    frame.render_widget(ladder, area);
+}
+
+fn draw_contributions<B: Backend>(frame: &mut Frame<B>, app: &App, area: Rect) {
+    let mut lines = vec![];
+
+    if let Some(summary) = &app.summary {
+        for (side, levels) in [("Bids", &summary.bids), ("Asks", &summary.asks)] {
+            lines.push(Spans::from(Span::styled(
+                side,
+                Style::default().add_modifier(Modifier::BOLD),
+            )));
+
+            for (exchange, share) in venue_shares(levels) {
+                let bar = "█".repeat((share * BAR_WIDTH as f64).round() as usize);
+                lines.push(Spans::from(vec![
+                    Span::styled(format!("{exchange:<10} "), exchange_style(exchange)),
+                    Span::styled(format!("{bar:<BAR_WIDTH$}"), exchange_style(exchange)),
+                    Span::raw(format!(" {:5.1}%", share * 100.0)),
+                ]));
+            }
+
+            lines.push(Spans::default());
+        }
+    }
+
+    let contributions = Paragraph::new(lines).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title("Depth by exchange"),
+    );
+    frame.render_widget(contributions, area);
+}
+
+fn exchange_style(exchange: &str) -> Style {
+    let color = match exchange {
+        BinanceExchange::EXCHANGE_NAME => Color::Yellow,
+        BitstampExchange::EXCHANGE_NAME => Color::Cyan,
+        _ => Color::Magenta,
+    };
+
+    Style::default().fg(color)
+}
+
+/// Share of the total amount contributed by each exchange, sorted by exchange.
+fn venue_shares(levels: &[Level]) -> Vec<(&str, f64)> {
+    let mut amounts = BTreeMap::<&str, f64>::new();
+    for level in levels {
+        *amounts.entry(&level.exchange).or_default() += level.amount;
+    }
+
+    let total: f64 = amounts.values().sum();
+
+    amounts
+        .into_iter()
+        .map(|(exchange, amount)| {
+            let share = if total > 0.0 { amount / total } else { 0.0 };
+            (exchange, share)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_venue_shares_sum_amounts_per_exchange() {
+        let level = |exchange: &str, amount| {
+            Level {
+                exchange: exchange.into(),
+                price: 1.0,
+                amount,
+                order_count: 0,
+                orders: vec![],
+                effective_price: 1.0,
+                legs: vec![],
+            }
+        };
+
+        let levels = [
+            level("Bitstamp", 1.0),
+            level("Binance", 2.0),
+            level("Bitstamp", 1.0),
+        ];
+
+        let shares = venue_shares(&levels);
+
+        assert_eq!(shares, vec![("Binance", 0.5), ("Bitstamp", 0.5)]);
+    }
+}
@@ -0,0 +1,737 @@
+//! End-to-end harness: the real server, fed by mock exchanges and consumed by gRPC clients.
+
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::sync::broadcast;
+use tonic::Streaming;
+
+use crate::{
+    books::{self, Books},
+    cli::{BookSettings, ServedPair},
+    client,
+    currencies::CurrencyPair,
+    exchanges::{BinanceExchange, BitstampExchange, ConnectToOrderBook, ExchangeEndpoints},
+    feeds::FeedMonitor,
+    fees::FeeSchedule,
+    instruments::PairCatalog,
+    merged_book::BucketSize,
+    order_book::{
+        AggregatedSummary, CrossingEvent, Empty, FillRequest, FillSimulation, Index, Metrics,
+        OrderbookAggregatorClient, Summary, Trade,
+    },
+    recorder::Recorder,
+    server::{
+        self, BookSubscriber, RunningServer, ServedPairs, TradeSubscriber, BROADCAST_QUEUE_CAPACITY,
+    },
+    test_utils::mock_exchange::{MockExchange, MockProtocol, ScriptStep},
+};
+
+/// Time to wait for a summary before failing the test.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server running on an ephemeral port, connected to mock exchanges.
+pub struct Harness {
+    pub binance: MockExchange,
+    pub bitstamp: MockExchange,
+    pub currency_pair: CurrencyPair,
+    server: RunningServer,
+    /// Publishes the books along with the mock exchanges, unless connected like the real server.
+    book_publisher: Option<BookSubscriber>,
+    /// Publishes the trades, unless they come from the mock exchanges.
+    trade_publisher: Option<TradeSubscriber>,
+    /// Books of the served pairs, when connected like the real server.
+    books: Option<Books>,
+}
+
+impl Harness {
+    /// Starts mock exchanges playing the given scripts and a server merging their books.
+    pub async fn start(
+        binance_scripts: Vec<Vec<ScriptStep>>,
+        bitstamp_scripts: Vec<Vec<ScriptStep>>,
+    ) -> Self {
+        let binance = MockExchange::start(MockProtocol::Binance, binance_scripts).await;
+        let bitstamp = MockExchange::start(MockProtocol::Bitstamp, bitstamp_scripts).await;
+        let endpoints = endpoints(&binance, &bitstamp);
+
+        let currency_pair: CurrencyPair = "ETHBTC".parse().unwrap();
+        let feed_monitor = FeedMonitor::new(
+            &currency_pair,
+            &[
+                BinanceExchange::EXCHANGE_NAME,
+                BitstampExchange::EXCHANGE_NAME,
+            ],
+        );
+
+        let stream = crate::books::build_aggregated_book_order(
+            &currency_pair,
+            &PairCatalog::builtin(),
+            &endpoints,
+            &feed_monitor,
+            &Recorder::disabled(),
+            false,
+            FeeSchedule::default(),
+        )
+        .await
+        .unwrap();
+
+        let (book_publisher, _) = broadcast::channel(BROADCAST_QUEUE_CAPACITY);
+        books::spawn_publisher(stream, book_publisher.clone());
+        let (trade_publisher, _) = broadcast::channel(BROADCAST_QUEUE_CAPACITY);
+
+        let served_pairs = ServedPairs::default();
+        served_pairs.insert(
+            &currency_pair,
+            book_publisher.clone(),
+            trade_publisher.clone(),
+            vec![currency_pair.clone()],
+        );
+
+        let server = server::run_server(
+            served_pairs,
+            feed_monitor,
+            server::ServerOptions::localhost(0),
+        )
+        .await
+        .unwrap();
+
+        Self {
+            binance,
+            bitstamp,
+            currency_pair,
+            server,
+            book_publisher: Some(book_publisher),
+            trade_publisher: Some(trade_publisher),
+            books: None,
+        }
+    }
+
+    /// Starts mock exchanges playing the given scripts and a server serving
+    /// `pairs` like the real one, trades included.
+    pub async fn serve(
+        binance_scripts: Vec<Vec<ScriptStep>>,
+        bitstamp_scripts: Vec<Vec<ScriptStep>>,
+        pairs: &[ServedPair],
+    ) -> Self {
+        let binance = MockExchange::start(MockProtocol::Binance, binance_scripts).await;
+        let bitstamp = MockExchange::start(MockProtocol::Bitstamp, bitstamp_scripts).await;
+
+        let served_pairs = ServedPairs::default();
+        let feed_monitor = FeedMonitor::default();
+        let books = Books::connect(
+            pairs,
+            &book_settings(&binance, &bitstamp),
+            served_pairs.clone(),
+            feed_monitor.clone(),
+            Recorder::disabled(),
+        )
+        .await
+        .unwrap();
+
+        let server = server::run_server(
+            served_pairs,
+            feed_monitor,
+            server::ServerOptions::localhost(0),
+        )
+        .await
+        .unwrap();
+
+        Self {
+            binance,
+            bitstamp,
+            currency_pair: pairs[0].currency_pair.clone(),
+            server,
+            book_publisher: None,
+            trade_publisher: None,
+            books: Some(books),
+        }
+    }
+
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server.local_addr
+    }
+
+    /// Connects a new gRPC client and subscribes to the merged books.
+    pub async fn subscribe(&self) -> Streaming<Summary> {
+        self.subscribe_pair(&self.currency_pair).await.unwrap()
+    }
+
+    /// Connects a new gRPC client and subscribes to the merged books of `currency_pair`.
+    pub async fn subscribe_pair(
+        &self,
+        currency_pair: &CurrencyPair,
+    ) -> crate::Result<Streaming<Summary>> {
+        client::subscribe(self.server_url(), Some(currency_pair)).await
+    }
+
+    /// Connects a new gRPC client and subscribes to the aggregated books.
+    pub async fn subscribe_aggregated(&self) -> Streaming<AggregatedSummary> {
+        client::subscribe_aggregated(self.server_url(), Some(&self.currency_pair))
+            .await
+            .unwrap()
+    }
+
+    /// Connects a new gRPC client and subscribes to the books grouped in buckets.
+    pub async fn subscribe_bucketed(
+        &self,
+        bucket_size: BucketSize,
+    ) -> Streaming<AggregatedSummary> {
+        client::subscribe_bucketed(self.server_url(), Some(&self.currency_pair), bucket_size)
+            .await
+            .unwrap()
+    }
+
+    /// Connects a new gRPC client and subscribes to the metrics, with default parameters.
+    pub async fn subscribe_metrics(&self) -> Streaming<Metrics> {
+        client::subscribe_metrics(self.server_url(), Some(&self.currency_pair))
+            .await
+            .unwrap()
+    }
+
+    /// Connects a new gRPC client and subscribes to the index prices, with default parameters.
+    pub async fn subscribe_index(&self) -> Streaming<Index> {
+        client::subscribe_index(self.server_url(), Some(&self.currency_pair))
+            .await
+            .unwrap()
+    }
+
+    /// Connects a new gRPC client and subscribes to the trades.
+    pub async fn subscribe_trades(&self) -> Streaming<Trade> {
+        client::subscribe_trades(self.server_url(), Some(&self.currency_pair))
+            .await
+            .unwrap()
+    }
+
+    /// Publishes `trade` to the clients subscribed to the trades.
+    pub fn publish_trade(&self, trade: Trade) {
+        let trade_publisher = self
+            .trade_publisher
+            .as_ref()
+            .expect("trades come from the mock exchanges");
+        trade_publisher.send(Ok(trade.into())).unwrap();
+    }
+
+    /// Publishes a book that couldn't be built, failing with `err`.
+    pub fn publish_invalid_book(&self, err: &str) {
+        let book_publisher = self
+            .book_publisher
+            .as_ref()
+            .expect("books come from the mock exchanges alone");
+        book_publisher.send(Err(err.into())).unwrap();
+    }
+
+    /// Publishes a trade that couldn't be parsed, failing with `err`.
+    pub fn publish_invalid_trade(&self, err: &str) {
+        let trade_publisher = self
+            .trade_publisher
+            .as_ref()
+            .expect("trades come from the mock exchanges");
+        trade_publisher.send(Err(err.into())).unwrap();
+    }
+
+    /// Connects a new gRPC client and subscribes to the crossing events.
+    pub async fn subscribe_crossings(&self) -> Streaming<CrossingEvent> {
+        let mut client = OrderbookAggregatorClient::connect(self.server_url())
+            .await
+            .unwrap();
+
+        client
+            .market_crossings(Empty {})
+            .await
+            .unwrap()
+            .into_inner()
+    }
+
+    /// Connects a new gRPC client and simulates filling `request` on the latest book.
+    pub async fn simulate_fill(&self, request: FillRequest) -> FillSimulation {
+        let mut client = OrderbookAggregatorClient::connect(self.server_url())
+            .await
+            .unwrap();
+
+        client.simulate_fill(request).await.unwrap().into_inner()
+    }
+
+    /// Serves the connections accepted from now on with `depth` levels per side.
+    pub fn reload_depth(&self, depth: usize) {
+        self.server.handle().reload(depth, None).unwrap();
+    }
+
+    /// Serves `pairs` from now on, like a reloaded config would.
+    pub async fn reload_pairs(&mut self, pairs: &[ServedPair]) {
+        let settings = book_settings(&self.binance, &self.bitstamp);
+        let books = self
+            .books
+            .as_mut()
+            .expect("only servers started with `serve` reload pairs");
+
+        books.reload(pairs, &settings).await;
+    }
+
+    fn server_url(&self) -> String {
+        format!("http://{}", self.server_addr())
+    }
+}
+
+fn endpoints(binance: &MockExchange, bitstamp: &MockExchange) -> ExchangeEndpoints {
+    ExchangeEndpoints {
+        binance: binance.url(),
+        bitstamp: bitstamp.url(),
+        ..Default::default()
+    }
+}
+
+fn book_settings(binance: &MockExchange, bitstamp: &MockExchange) -> BookSettings {
+    BookSettings {
+        endpoints: endpoints(binance, bitstamp),
+        level3: false,
+        catalog: PairCatalog::builtin(),
+    }
+}
+
+/// Receives the next message, failing if it takes too long.
+pub async fn next_summary<T>(stream: &mut Streaming<T>) -> T {
+    tokio::time::timeout(TIMEOUT, stream.message())
+        .await
+        .expect("timed out waiting for the next summary")
+        .unwrap()
+        .expect("summary stream ended")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cli::Composition,
+        order_book::{
+            ConstituentStatus, CrossingEventKind, FillSide, Level, RequestedFillSize, TradeSide,
+        },
+        test_utils::mock_exchange::{binance_book_update, bitstamp_book_update},
+    };
+
+    fn assert_well_formed(summary: &Summary) {
+        assert_eq!(summary.bids.len(), 10);
+        assert_eq!(summary.asks.len(), 10);
+        assert!(summary
+            .bids
+            .windows(2)
+            .all(|pair| pair[0].price >= pair[1].price));
+        assert!(summary
+            .asks
+            .windows(2)
+            .all(|pair| pair[0].price <= pair[1].price));
+        assert_eq!(
+            summary.spread,
+            summary.asks[0].price - summary.bids[0].price
+        );
+    }
+
+    /// Book updates sent every 25 ms for a few seconds.
+    fn repeated(update: fn() -> ScriptStep) -> Vec<ScriptStep> {
+        (0..200)
+            .flat_map(|_| [ScriptStep::Sleep(Duration::from_millis(25)), update()])
+            .collect()
+    }
+
+    fn direct(currency_pair: &str, fees: FeeSchedule) -> ServedPair {
+        ServedPair {
+            currency_pair: currency_pair.parse().unwrap(),
+            composition: Composition::Direct(fees),
+        }
+    }
+
+    fn exchanges(levels: &[Level]) -> Vec<&str> {
+        let mut exchanges: Vec<_> = levels.iter().map(|level| level.exchange.as_str()).collect();
+        exchanges.sort_unstable();
+        exchanges.dedup();
+        exchanges
+    }
+
+    #[tokio::test]
+    async fn test_merged_books_reach_grpc_clients() {
+        let harness = Harness::start(
+            vec![vec![ScriptStep::WaitForRelease, binance_book_update()]],
+            vec![vec![ScriptStep::WaitForRelease, bitstamp_book_update()]],
+        )
+        .await;
+
+        let mut first_client = harness.subscribe().await;
+        let mut second_client = harness.subscribe().await;
+
+        harness.binance.release();
+        let summary = next_summary(&mut first_client).await;
+        assert_well_formed(&summary);
+        assert_eq!(exchanges(&summary.bids), ["Binance"]);
+
+        harness.bitstamp.release();
+        let summary = next_summary(&mut first_client).await;
+        assert_well_formed(&summary);
+
+        // Bitstamp's bids are all above Binance's, and Binance's asks are all below Bitstamp's
+        assert_eq!(exchanges(&summary.bids), ["Bitstamp"]);
+        assert_eq!(exchanges(&summary.asks), ["Binance"]);
+        assert_eq!(summary.bids[0].price, 1377.2);
+        assert_eq!(summary.asks[0].price, 1336.39);
+
+        // Every client receives the same books
+        assert_ne!(next_summary(&mut second_client).await, summary);
+        assert_eq!(next_summary(&mut second_client).await, summary);
+    }
+
+    #[tokio::test]
+    async fn test_books_keep_flowing_after_an_exchange_disconnects() {
+        let harness = Harness::start(
+            vec![
+                vec![
+                    ScriptStep::WaitForRelease,
+                    binance_book_update(),
+                    ScriptStep::Disconnect,
+                ],
+                vec![ScriptStep::WaitForRelease, binance_book_update()],
+            ],
+            vec![vec![
+                ScriptStep::WaitForRelease,
+                bitstamp_book_update(),
+                ScriptStep::WaitForRelease,
+                bitstamp_book_update(),
+            ]],
+        )
+        .await;
+
+        let mut client = harness.subscribe().await;
+
+        harness.binance.release();
+        next_summary(&mut client).await;
+        harness.bitstamp.release();
+        let before_disconnect = next_summary(&mut client).await;
+
+        // Binance already disconnected, its last book is still merged
+        harness.bitstamp.release();
+        let after_disconnect = next_summary(&mut client).await;
+
+        assert_well_formed(&after_disconnect);
+        assert_eq!(after_disconnect, before_disconnect);
+
+        // Books of the new connection are merged in turn
+        harness.binance.release();
+        assert_well_formed(&next_summary(&mut client).await);
+        assert_eq!(harness.binance.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_frames_keep_streams_open() {
+        let harness = Harness::start(
+            vec![vec![
+                ScriptStep::WaitForRelease,
+                binance_book_update(),
+                ScriptStep::Text("{not json".into()),
+            ]],
+            vec![vec![ScriptStep::WaitForRelease, bitstamp_book_update()]],
+        )
+        .await;
+
+        let mut client = harness.subscribe().await;
+
+        harness.binance.release();
+        assert_eq!(
+            exchanges(&next_summary(&mut client).await.bids),
+            ["Binance"]
+        );
+
+        // Binance's malformed frame is skipped, its last book stays merged, and
+        // books that can't be built aren't sent either
+        harness.publish_invalid_book("Binance stream error: expected value");
+        harness.bitstamp.release();
+        let summary = next_summary(&mut client).await;
+        assert_well_formed(&summary);
+        assert_eq!(exchanges(&summary.asks), ["Binance"]);
+    }
+
+    #[tokio::test]
+    async fn test_reloads_keep_existing_streams() {
+        let harness = Harness::start(
+            vec![vec![ScriptStep::WaitForRelease, binance_book_update()]],
+            vec![vec![ScriptStep::WaitForRelease, bitstamp_book_update()]],
+        )
+        .await;
+
+        let mut before_reload = harness.subscribe().await;
+        harness.reload_depth(5);
+        let mut after_reload = harness.subscribe().await;
+
+        // Streams opened before the reload go on, with the previous depth
+        harness.binance.release();
+        assert_well_formed(&next_summary(&mut before_reload).await);
+        let summary = next_summary(&mut after_reload).await;
+        assert_eq!(summary.bids.len(), 5);
+        assert_eq!(summary.asks.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_reloads_connect_only_the_changed_pairs() {
+        let mut harness = Harness::serve(
+            vec![repeated(binance_book_update)],
+            vec![repeated(bitstamp_book_update)],
+            &[direct("ETHBTC", FeeSchedule::default())],
+        )
+        .await;
+
+        let ethbtc: CurrencyPair = "ETHBTC".parse().unwrap();
+        let ltcbtc: CurrencyPair = "LTCBTC".parse().unwrap();
+
+        let mut ethbtc_client = harness.subscribe_pair(&ethbtc).await.unwrap();
+        assert_well_formed(&next_summary(&mut ethbtc_client).await);
+
+        // LTCBTC is added, and Binance's fee on ETHBTC changes
+        let fees = FeeSchedule::new(&["Binance=10".parse().unwrap()], &ethbtc, false);
+        harness
+            .reload_pairs(&[
+                direct("ETHBTC", fees),
+                direct("LTCBTC", FeeSchedule::default()),
+            ])
+            .await;
+
+        let mut ltcbtc_client = harness.subscribe_pair(&ltcbtc).await.unwrap();
+        assert_well_formed(&next_summary(&mut ltcbtc_client).await);
+
+        // ETHBTC's stream goes on, its books soon merged with the new fee on Binance's asks
+        let mut fee_applied = false;
+        for _ in 0..100 {
+            let summary = next_summary(&mut ethbtc_client).await;
+            if summary
+                .asks
+                .iter()
+                .any(|level| level.exchange == "Binance" && level.effective_price > level.price)
+            {
+                fee_applied = true;
+                break;
+            }
+        }
+        assert!(fee_applied);
+
+        // Only the feeds of LTCBTC were connected, ETHBTC's stayed connected
+        let subscriptions = |exchange: &MockExchange, channel: &str| {
+            exchange
+                .subscriptions()
+                .iter()
+                .filter(|subscription| subscription.to_string().contains(channel))
+                .count()
+        };
+        assert_eq!(subscriptions(&harness.binance, "ethbtc@depth"), 1);
+        assert_eq!(subscriptions(&harness.binance, "ltcbtc@depth"), 1);
+        assert_eq!(
+            subscriptions(&harness.bitstamp, "detail_order_book_ethbtc"),
+            1
+        );
+        assert_eq!(
+            subscriptions(&harness.bitstamp, "detail_order_book_ltcbtc"),
+            1
+        );
+
+        // Streams of a removed pair end, and it isn't served anymore
+        harness
+            .reload_pairs(&[direct("LTCBTC", FeeSchedule::default())])
+            .await;
+
+        let ended = tokio::time::timeout(TIMEOUT, async {
+            while ethbtc_client.message().await.unwrap().is_some() {}
+        })
+        .await;
+        assert!(ended.is_ok(), "the stream of a removed pair didn't end");
+        assert!(harness.subscribe_pair(&ethbtc).await.is_err());
+
+        assert_well_formed(&next_summary(&mut ltcbtc_client).await);
+    }
+
+    #[tokio::test]
+    async fn test_trades_reach_grpc_clients() {
+        let harness = Harness::start(
+            vec![vec![ScriptStep::WaitForRelease, binance_book_update()]],
+            vec![vec![ScriptStep::WaitForRelease, bitstamp_book_update()]],
+        )
+        .await;
+
+        let mut client = harness.subscribe_trades().await;
+
+        let trade = Trade {
+            exchange: "Binance".into(),
+            id: "389764512".into(),
+            price: 0.06812,
+            amount: 0.25,
+            aggressor_side: TradeSide::Sell as i32,
+            traded_at: 1665754020011,
+            received_at: 1665754020050,
+        };
+        // Invalid trades are skipped, the stream goes on
+        harness.publish_invalid_trade("Binance stream error: missing field `p`");
+        harness.publish_trade(trade.clone());
+
+        assert_eq!(next_summary(&mut client).await, trade);
+    }
+
+    #[tokio::test]
+    async fn test_aggregated_books_merge_equal_prices() {
+        let harness = Harness::start(
+            vec![vec![ScriptStep::WaitForRelease, binance_book_update()]],
+            vec![vec![ScriptStep::WaitForRelease, bitstamp_book_update()]],
+        )
+        .await;
+
+        let mut client = harness.subscribe_aggregated().await;
+
+        harness.bitstamp.release();
+        let summary = next_summary(&mut client).await;
+
+        // Bitstamp has three orders at each of its best prices
+        let best_bid = &summary.bids[0];
+        assert_eq!(best_bid.price, 1377.2);
+        assert_eq!(best_bid.venues.len(), 1);
+        assert_eq!(best_bid.venues[0].order_count, 3);
+        assert_eq!(best_bid.amount, best_bid.venues[0].amount);
+        assert_eq!(summary.asks[0].price, 1377.8);
+        assert_eq!(summary.spread, summary.asks[0].price - best_bid.price);
+
+        assert!(summary.bids.len() <= 10);
+        assert!(summary
+            .bids
+            .windows(2)
+            .all(|pair| pair[0].price > pair[1].price));
+    }
+
+    #[tokio::test]
+    async fn test_bucketed_books_group_prices() {
+        let harness = Harness::start(
+            vec![vec![ScriptStep::WaitForRelease, binance_book_update()]],
+            vec![vec![ScriptStep::WaitForRelease, bitstamp_book_update()]],
+        )
+        .await;
+
+        let mut client = harness.subscribe_bucketed(BucketSize::Tick(1.0)).await;
+
+        harness.binance.release();
+        let summary = next_summary(&mut client).await;
+
+        // Binance's bids range from 1336.28 to 1335.59, and asks from 1336.39 to 1336.92
+        let bids: Vec<_> = summary.bids.iter().map(|level| level.price).collect();
+        let asks: Vec<_> = summary.asks.iter().map(|level| level.price).collect();
+        assert_eq!(bids, [1336.0, 1335.0]);
+        assert_eq!(asks, [1337.0]);
+
+        let total_bids: f64 = summary.bids.iter().map(|level| level.amount).sum();
+        assert!((total_bids - 4.0777).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_crossed_books_are_flagged_and_reported() {
+        let harness = Harness::start(
+            vec![vec![ScriptStep::WaitForRelease, binance_book_update()]],
+            vec![vec![ScriptStep::WaitForRelease, bitstamp_book_update()]],
+        )
+        .await;
+
+        let mut summaries = harness.subscribe().await;
+        let mut crossings = harness.subscribe_crossings().await;
+
+        harness.binance.release();
+        assert!(!next_summary(&mut summaries).await.crossed);
+
+        // Bitstamp's bids are all above Binance's asks
+        harness.bitstamp.release();
+        let summary = next_summary(&mut summaries).await;
+        assert!(summary.crossed);
+        assert!(!summary.locked);
+        assert!(summary.spread < 0.0);
+
+        let event = next_summary(&mut crossings).await;
+        assert_eq!(event.kind(), CrossingEventKind::Crossed);
+        assert!(!event.ended);
+        assert_eq!(event.bid_exchange, "Bitstamp");
+        assert_eq!(event.ask_exchange, "Binance");
+        assert_eq!(event.bid_price, 1377.2);
+        assert_eq!(event.ask_price, 1336.39);
+        assert!(event.amount > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_fills_are_routed_on_the_latest_book() {
+        let harness = Harness::start(
+            vec![vec![ScriptStep::WaitForRelease, binance_book_update()]],
+            vec![vec![ScriptStep::WaitForRelease, bitstamp_book_update()]],
+        )
+        .await;
+
+        let mut client = harness.subscribe().await;
+        harness.binance.release();
+        next_summary(&mut client).await;
+        harness.bitstamp.release();
+        let summary = next_summary(&mut client).await;
+
+        // Bitstamp's bids are all above Binance's
+        let simulation = harness
+            .simulate_fill(FillRequest {
+                side: FillSide::Sell as i32,
+                size: Some(RequestedFillSize::Quantity(summary.bids[0].amount)),
+            })
+            .await;
+
+        assert!(simulation.complete);
+        assert_eq!(simulation.average_price, 1377.2);
+        assert_eq!(simulation.worst_price, 1377.2);
+        assert_eq!(simulation.venues.len(), 1);
+        assert_eq!(simulation.venues[0].exchange, "Bitstamp");
+        assert_eq!(simulation.fees, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_are_streamed_for_each_book() {
+        let harness = Harness::start(
+            vec![vec![ScriptStep::WaitForRelease, binance_book_update()]],
+            vec![vec![ScriptStep::WaitForRelease, bitstamp_book_update()]],
+        )
+        .await;
+
+        let mut client = harness.subscribe_metrics().await;
+
+        harness.binance.release();
+        let metrics = next_summary(&mut client).await;
+
+        // Binance's best bid is 1336.28 and its best ask is 1336.39
+        assert!((metrics.mid_price - 1336.335).abs() < 1e-9);
+        assert!(metrics.microprice >= 1336.28 && metrics.microprice <= 1336.39);
+        assert!((-1.0..=1.0).contains(&metrics.imbalance));
+        assert_eq!(metrics.depth.len(), 3);
+        assert!(metrics
+            .depth
+            .windows(2)
+            .all(|pair| pair[0].bid_amount <= pair[1].bid_amount));
+
+        assert_eq!(metrics.exchanges.len(), 1);
+        assert_eq!(metrics.exchanges[0].exchange, "Binance");
+        assert_eq!(metrics.exchanges[0].bid_price, 1336.28);
+        assert_eq!(metrics.exchanges[0].ask_price, 1336.39);
+    }
+
+    #[tokio::test]
+    async fn test_index_keeps_two_diverging_exchanges() {
+        let harness = Harness::start(
+            vec![vec![ScriptStep::WaitForRelease, binance_book_update()]],
+            vec![vec![ScriptStep::WaitForRelease, bitstamp_book_update()]],
+        )
+        .await;
+
+        let mut client = harness.subscribe_index().await;
+
+        harness.binance.release();
+        let index = next_summary(&mut client).await;
+        assert!((index.price - 1336.335).abs() < 1e-9);
+        assert_eq!(index.constituents.len(), 1);
+        assert_eq!(index.constituents[0].weight, 1.0);
+
+        // Bitstamp's mid price is about 3% above Binance's, but neither can be told off
+        harness.bitstamp.release();
+        let index = next_summary(&mut client).await;
+        assert_eq!(index.constituents.len(), 2);
+        assert!(index
+            .constituents
+            .iter()
+            .all(|constituent| constituent.status() == ConstituentStatus::Included));
+    }
+}
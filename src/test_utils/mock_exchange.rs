@@ -0,0 +1,632 @@
+//! Local websocket servers speaking the exchange protocols.
+//!
+//! A mock exchange accepts subscriptions, answers them like the real
+//! exchange would, and plays a script of frames to the client.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+use tungstenite::Message;
+
+/// Protocol spoken by a mock exchange.
+#[derive(Debug, Clone, Copy)]
+pub enum MockProtocol {
+    Binance,
+    Bitstamp,
+}
+
+impl MockProtocol {
+    /// Reply sent by the exchange after a successful subscription.
+    fn acknowledgement(self, subscription: &Value) -> Value {
+        match self {
+            Self::Binance => json!({ "result": null, "id": subscription["id"] }),
+            Self::Bitstamp => {
+                json!({
+                    "event": "bts:subscription_succeeded",
+                    "channel": subscription["data"]["channel"],
+                    "data": {},
+                })
+            }
+        }
+    }
+
+    /// Reply sent by the exchange after a failed subscription.
+    fn rejection(self, subscription: &Value, reason: &str) -> Value {
+        match self {
+            Self::Binance => {
+                json!({ "error": { "code": 2, "msg": reason }, "id": subscription["id"] })
+            }
+            Self::Bitstamp => {
+                json!({
+                    "event": "bts:error",
+                    "channel": "",
+                    "data": { "code": null, "message": reason },
+                })
+            }
+        }
+    }
+}
+
+/// A step of the script played after a subscription is received.
+///
+/// Scripts without an `Acknowledge` or `Reject` step have the subscription
+/// acknowledged before their first step.
+#[derive(Debug, Clone)]
+pub enum ScriptStep {
+    /// Acknowledges the subscription.
+    Acknowledge,
+    /// Rejects the subscription with the given reason.
+    Reject(String),
+    /// Sends a text frame, either a book update or a malformed frame.
+    Text(String),
+    /// Sends a ping frame, the client is expected to answer with a pong.
+    Ping,
+    /// Waits before playing the next step.
+    Sleep(Duration),
+    /// Waits until the test calls `MockExchange::release`.
+    WaitForRelease,
+    /// Closes the connection.
+    Disconnect,
+}
+
+/// What the mock exchange saw from its clients.
+#[derive(Debug)]
+struct MockState {
+    subscriptions: Mutex<Vec<Value>>,
+    pongs: AtomicUsize,
+    connections: AtomicUsize,
+    releases: Semaphore,
+}
+
+/// A websocket server listening on an ephemeral local port.
+///
+/// The n-th connection plays the n-th script, connections past the last
+/// script play the last one again. The server stops when dropped.
+pub struct MockExchange {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl MockExchange {
+    pub async fn start(protocol: MockProtocol, scripts: Vec<Vec<ScriptStep>>) -> Self {
+        assert!(
+            !scripts.is_empty(),
+            "a mock exchange needs at least one script"
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(MockState {
+            subscriptions: Mutex::default(),
+            pongs: AtomicUsize::new(0),
+            connections: AtomicUsize::new(0),
+            releases: Semaphore::new(0),
+        });
+
+        let server = tokio::spawn({
+            let state = Arc::clone(&state);
+
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let index = state.connections.fetch_add(1, Ordering::SeqCst);
+                    let script = scripts[index.min(scripts.len() - 1)].clone();
+                    let state = Arc::clone(&state);
+
+                    tokio::spawn(handle_connection(stream, protocol, script, state));
+                }
+            }
+        });
+
+        Self {
+            addr,
+            state,
+            server,
+        }
+    }
+
+    /// Websocket base URL to connect to this exchange.
+    pub fn url(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+
+    /// Subscription messages received so far, in order.
+    pub fn subscriptions(&self) -> Vec<Value> {
+        self.state.subscriptions.lock().unwrap().clone()
+    }
+
+    pub fn pongs(&self) -> usize {
+        self.state.pongs.load(Ordering::SeqCst)
+    }
+
+    pub fn connections(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
+
+    /// Lets one `ScriptStep::WaitForRelease` step proceed, now or whenever it's reached.
+    pub fn release(&self) {
+        self.state.releases.add_permits(1);
+    }
+}
+
+impl Drop for MockExchange {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    protocol: MockProtocol,
+    script: Vec<ScriptStep>,
+    state: Arc<MockState>,
+) {
+    let mut websocket = match tokio_tungstenite::accept_async(stream).await {
+        Ok(websocket) => websocket,
+        Err(_) => return,
+    };
+
+    // Wait for the subscription, closing the connection if the client leaves first
+    let subscription = loop {
+        match websocket.next().await {
+            Some(Ok(Message::Text(text))) => break text,
+            Some(Ok(_)) => continue,
+            Some(Err(_)) | None => return,
+        }
+    };
+
+    let subscription: Value = serde_json::from_str(&subscription).unwrap();
+    state
+        .subscriptions
+        .lock()
+        .unwrap()
+        .push(subscription.clone());
+
+    let (mut sink, mut stream) = websocket.split();
+
+    let answers_subscription = script
+        .iter()
+        .any(|step| matches!(step, ScriptStep::Acknowledge | ScriptStep::Reject(_)));
+    let script = if answers_subscription {
+        script
+    } else {
+        std::iter::once(ScriptStep::Acknowledge)
+            .chain(script)
+            .collect()
+    };
+
+    let reader = tokio::spawn({
+        let state = Arc::clone(&state);
+
+        async move {
+            while let Some(Ok(message)) = stream.next().await {
+                if let Message::Pong(_) = message {
+                    state.pongs.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+    });
+
+    for step in script {
+        let sent = match step {
+            ScriptStep::Acknowledge => {
+                let acknowledgement = protocol.acknowledgement(&subscription);
+                sink.send(Message::Text(acknowledgement.to_string())).await
+            }
+            ScriptStep::Reject(reason) => {
+                let rejection = protocol.rejection(&subscription, &reason);
+                sink.send(Message::Text(rejection.to_string())).await
+            }
+            ScriptStep::Text(text) => sink.send(Message::Text(text)).await,
+            ScriptStep::Ping => sink.send(Message::Ping(b"mock".to_vec())).await,
+            ScriptStep::Sleep(duration) => {
+                tokio::time::sleep(duration).await;
+                Ok(())
+            }
+            ScriptStep::WaitForRelease => {
+                state.releases.acquire().await.unwrap().forget();
+                Ok(())
+            }
+            ScriptStep::Disconnect => {
+                let _ = sink.close().await;
+                reader.abort();
+                return;
+            }
+        };
+
+        if sent.is_err() {
+            reader.abort();
+            return;
+        }
+    }
+
+    // Keep the connection open until the client leaves
+    let _ = reader.await;
+}
+
+/// A Binance book update, from the test data.
+pub fn binance_book_update() -> ScriptStep {
+    ScriptStep::Text(include_str!("../../test_data/binance_order_book_update_message.json").into())
+}
+
+/// A Bitstamp book update, from the test data.
+pub fn bitstamp_book_update() -> ScriptStep {
+    ScriptStep::Text(include_str!("../../test_data/bitstamp_order_book_update_message.json").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Stream;
+
+    use super::*;
+    use crate::{
+        currencies::CurrencyPair,
+        exchanges::{
+            BinanceExchange, BitstampExchange, ConnectToOrderBook, DEFAULT_RECONNECT_DELAY,
+        },
+        order_book::Summary,
+        Error, Result,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn next_summary(
+        stream: &mut (impl Stream<Item = Result<Summary>> + Unpin),
+    ) -> Option<Result<Summary>> {
+        tokio::time::timeout(TIMEOUT, stream.next())
+            .await
+            .expect("timed out waiting for the next summary")
+    }
+
+    #[tokio::test]
+    async fn test_binance_connection_end_to_end() {
+        let mock = MockExchange::start(
+            MockProtocol::Binance,
+            vec![vec![ScriptStep::Ping, binance_book_update()]],
+        )
+        .await;
+
+        let currency_pair: CurrencyPair = "ETHBTC".parse().unwrap();
+        let connection = BinanceExchange::connect_to_order_book(&mock.url(), &currency_pair)
+            .await
+            .unwrap();
+        let stream = connection
+            .into_messages()
+            .map(|message| BinanceExchange::try_parse_summary(message?));
+        let mut stream = Box::pin(stream);
+
+        let summary = next_summary(&mut stream).await.unwrap().unwrap();
+        assert_eq!(summary.bids[0].price, 1336.28);
+        assert_eq!(summary.asks[0].price, 1336.39);
+
+        let expected_subscription =
+            serde_json::to_value(BinanceExchange::subscribe_message(&currency_pair)).unwrap();
+        assert_eq!(mock.subscriptions(), vec![expected_subscription]);
+
+        // The pong may still be on its way
+        tokio::time::timeout(TIMEOUT, async {
+            while mock.pongs() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for the pong");
+    }
+
+    #[tokio::test]
+    async fn test_binance_trades_reconnect_after_the_server_closes() {
+        let trade =
+            ScriptStep::Text(include_str!("../../test_data/binance_trade_message.json").into());
+        let mock = MockExchange::start(
+            MockProtocol::Binance,
+            vec![vec![trade.clone(), ScriptStep::Disconnect], vec![trade]],
+        )
+        .await;
+
+        let currency_pair: CurrencyPair = "ETHBTC".parse().unwrap();
+        let trades = BinanceExchange::trades(&mock.url(), &currency_pair, DEFAULT_RECONNECT_DELAY)
+            .await
+            .unwrap();
+        let mut trades = Box::pin(trades);
+
+        for _ in 0..2 {
+            let trade = tokio::time::timeout(TIMEOUT, trades.next())
+                .await
+                .expect("timed out waiting for the next trade")
+                .unwrap()
+                .unwrap();
+            assert_eq!(trade.id, "389764512");
+        }
+        assert_eq!(mock.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_bitstamp_malformed_frame_and_disconnect() {
+        let mock = MockExchange::start(
+            MockProtocol::Bitstamp,
+            vec![vec![
+                ScriptStep::Text("{not json".into()),
+                bitstamp_book_update(),
+                ScriptStep::Sleep(Duration::from_millis(50)),
+                ScriptStep::Disconnect,
+            ]],
+        )
+        .await;
+
+        let currency_pair: CurrencyPair = "ETHBTC".parse().unwrap();
+        let connection = BitstampExchange::connect_to_order_book(&mock.url(), &currency_pair)
+            .await
+            .unwrap();
+        let stream = connection
+            .into_messages()
+            .map(|message| BitstampExchange::try_parse_summary(message?));
+        let mut stream = Box::pin(stream);
+
+        assert!(next_summary(&mut stream).await.unwrap().is_err());
+
+        let summary = next_summary(&mut stream).await.unwrap().unwrap();
+        assert_eq!(summary.bids[0].price, 1377.2);
+
+        assert!(next_summary(&mut stream).await.is_none());
+        assert_eq!(mock.connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_books_sent_before_the_acknowledgement_are_kept() {
+        let mock = MockExchange::start(
+            MockProtocol::Bitstamp,
+            vec![vec![
+                bitstamp_book_update(),
+                ScriptStep::Ping,
+                ScriptStep::Acknowledge,
+            ]],
+        )
+        .await;
+
+        let currency_pair: CurrencyPair = "ETHBTC".parse().unwrap();
+        let connection = BitstampExchange::connect_to_order_book(&mock.url(), &currency_pair)
+            .await
+            .unwrap();
+        assert_eq!(connection.early_messages.len(), 1);
+
+        let stream = connection
+            .into_messages()
+            .map(|message| BitstampExchange::try_parse_summary(message?));
+        let mut stream = Box::pin(stream);
+
+        let summary = next_summary(&mut stream).await.unwrap().unwrap();
+        assert_eq!(summary.bids[0].price, 1377.2);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_subscriptions_fail_to_connect() {
+        let binance = MockExchange::start(
+            MockProtocol::Binance,
+            vec![vec![ScriptStep::Reject("Invalid request".into())]],
+        )
+        .await;
+        let bitstamp = MockExchange::start(
+            MockProtocol::Bitstamp,
+            vec![vec![
+                bitstamp_book_update(),
+                ScriptStep::Reject("Bad subscription string.".into()),
+            ]],
+        )
+        .await;
+
+        let currency_pair: CurrencyPair = "ETHBTC".parse().unwrap();
+
+        let result = BinanceExchange::connect_to_order_book(&binance.url(), &currency_pair).await;
+        assert!(matches!(
+            result,
+            Err(Error::SubscriptionRejected(exchange, reason))
+                if exchange == "Binance" && reason == "Invalid request (code 2)"
+        ));
+
+        let result = BitstampExchange::connect_to_order_book(&bitstamp.url(), &currency_pair).await;
+        assert!(matches!(
+            result,
+            Err(Error::SubscriptionRejected(exchange, reason))
+                if exchange == "Bitstamp" && reason == "Bad subscription string."
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_bitstamp_reconnects_before_dropping_the_old_connection() {
+        let request_reconnect = r#"{"event":"bts:request_reconnect","channel":"","data":""}"#;
+        let mock = MockExchange::start(
+            MockProtocol::Bitstamp,
+            vec![
+                vec![
+                    bitstamp_book_update(),
+                    ScriptStep::Text(request_reconnect.into()),
+                    // Still delivering books while the replacement subscribes
+                    bitstamp_book_update(),
+                    ScriptStep::WaitForRelease,
+                ],
+                vec![bitstamp_book_update()],
+            ],
+        )
+        .await;
+
+        let currency_pair: CurrencyPair = "ETHBTC".parse().unwrap();
+        let stream = BitstampExchange::connect_with_reconnects(
+            &mock.url(),
+            &currency_pair,
+            DEFAULT_RECONNECT_DELAY,
+        )
+        .await
+        .unwrap()
+        .map(|message| BitstampExchange::try_parse_summary(message?));
+        let mut stream = Box::pin(stream);
+
+        // Two books from the first connection and one from the replacement, no errors
+        for _ in 0..3 {
+            next_summary(&mut stream).await.unwrap().unwrap();
+        }
+
+        assert_eq!(mock.connections(), 2);
+        assert_eq!(mock.subscriptions().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_bitstamp_keeps_its_connection_when_the_replacement_fails() {
+        let request_reconnect = r#"{"event":"bts:request_reconnect","channel":"","data":""}"#;
+        let mock = MockExchange::start(
+            MockProtocol::Bitstamp,
+            vec![
+                vec![
+                    bitstamp_book_update(),
+                    ScriptStep::Text(request_reconnect.into()),
+                    ScriptStep::WaitForRelease,
+                    bitstamp_book_update(),
+                ],
+                vec![ScriptStep::Reject("Maintenance.".into())],
+            ],
+        )
+        .await;
+
+        let currency_pair: CurrencyPair = "ETHBTC".parse().unwrap();
+        let stream = BitstampExchange::connect_with_reconnects(
+            &mock.url(),
+            &currency_pair,
+            DEFAULT_RECONNECT_DELAY,
+        )
+        .await
+        .unwrap()
+        .map(|message| BitstampExchange::try_parse_summary(message?));
+        let mut stream = Box::pin(stream);
+
+        next_summary(&mut stream).await.unwrap().unwrap();
+
+        // Let the replacement be rejected before the next book is sent
+        let release_after_rejection = async {
+            while mock.subscriptions().len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            mock.release();
+        };
+        let (summary, ()) = tokio::join!(next_summary(&mut stream), release_after_rejection);
+
+        // The failure is only logged, books keep coming from the current connection
+        summary.unwrap().unwrap();
+        assert_eq!(mock.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_bitstamp_reconnects_after_the_server_closes() {
+        let mock = MockExchange::start(
+            MockProtocol::Bitstamp,
+            vec![
+                vec![bitstamp_book_update(), ScriptStep::Disconnect],
+                vec![bitstamp_book_update()],
+            ],
+        )
+        .await;
+
+        let currency_pair: CurrencyPair = "ETHBTC".parse().unwrap();
+        let stream = BitstampExchange::connect_with_reconnects(
+            &mock.url(),
+            &currency_pair,
+            DEFAULT_RECONNECT_DELAY,
+        )
+        .await
+        .unwrap()
+        .map(|message| BitstampExchange::try_parse_summary(message?));
+        let mut stream = Box::pin(stream);
+
+        next_summary(&mut stream).await.unwrap().unwrap();
+        next_summary(&mut stream).await.unwrap().unwrap();
+
+        assert_eq!(mock.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_bitstamp_level3_book_from_snapshot_and_order_events() {
+        // After the snapshot, at 1663532910363798
+        let order_created = |id: u64, price: &str| {
+            ScriptStep::Text(
+                json!({
+                    "event": "order_created",
+                    "channel": "live_orders_ethbtc",
+                    "data": {
+                        "id": id,
+                        "order_type": 0,
+                        "price_str": price,
+                        "amount_str": "0.5",
+                        "microtimestamp": "1663532910400000",
+                    },
+                })
+                .to_string(),
+            )
+        };
+
+        // The order events connection is opened first, then the snapshot one
+        let mock = MockExchange::start(
+            MockProtocol::Bitstamp,
+            vec![
+                vec![
+                    ScriptStep::WaitForRelease,
+                    order_created(1, "1377.5"),
+                    order_created(2, "1377.2"),
+                ],
+                vec![bitstamp_book_update()],
+            ],
+        )
+        .await;
+
+        let currency_pair: CurrencyPair = "ETHBTC".parse().unwrap();
+        let stream = BitstampExchange::level3_summaries(
+            &mock.url(),
+            &currency_pair,
+            DEFAULT_RECONNECT_DELAY,
+        )
+        .await
+        .unwrap();
+        let mut stream = Box::pin(stream);
+
+        let channels: Vec<_> = mock
+            .subscriptions()
+            .iter()
+            .map(|subscription| subscription["data"]["channel"].clone())
+            .collect();
+        assert_eq!(
+            channels,
+            [
+                json!("live_orders_ethbtc"),
+                json!("detail_order_book_ethbtc")
+            ]
+        );
+
+        let snapshot = next_summary(&mut stream).await.unwrap().unwrap();
+        assert_eq!(snapshot.bids[0].price, 1377.2);
+        assert_eq!(snapshot.bids[0].order_count, 3);
+
+        mock.release();
+
+        let summary = next_summary(&mut stream).await.unwrap().unwrap();
+        assert_eq!(summary.bids[0].price, 1377.5);
+        assert_eq!(summary.bids[0].order_count, 1);
+
+        // Joins the back of the queue
+        let summary = next_summary(&mut stream).await.unwrap().unwrap();
+        let level = &summary.bids[1];
+        assert_eq!(level.price, 1377.2);
+        assert_eq!(level.order_count, 4);
+        assert_eq!(level.orders[3].id, "2");
+        assert_eq!(level.orders[3].queue_position, 3);
+    }
+}
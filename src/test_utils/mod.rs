@@ -0,0 +1,19 @@
+//! Support code shared by tests.
+
+pub mod harness;
+pub mod mock_exchange;
+
+use crate::order_book::Level;
+
+/// A level of `exchange` without orders, legs nor fees.
+pub fn level(exchange: &str, price: f64, amount: f64) -> Level {
+    Level {
+        exchange: exchange.into(),
+        price,
+        amount,
+        order_count: 0,
+        orders: vec![],
+        effective_price: price,
+        legs: vec![],
+    }
+}